@@ -0,0 +1,44 @@
+use crate::document::Document;
+use crate::editor::Position;
+
+/// An open document together with the view state it had when it was last shown.
+#[derive(Default)]
+pub struct Buffer {
+    pub document: Document,
+    pub cursor_position: Position,
+    pub offset: Position,
+    pub selection_start: Option<Position>,
+}
+
+impl Buffer {
+    pub fn new(document: Document) -> Self {
+        Self {
+            document,
+            ..Self::default()
+        }
+    }
+
+    /// Opens `filename`, or starts an empty buffer that will create it on save.
+    /// Files of `large_file_size` bytes or more open in large-file mode.
+    pub fn open(filename: &str, large_file_size: u64) -> Result<Self, std::io::Error> {
+        match Document::open(filename) {
+            Ok(mut document) => {
+                document.set_large(document.size_bytes() as u64 >= large_file_size);
+                Ok(Self::new(document))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let mut document = Document::default();
+                document.set_file_name(filename);
+                Ok(Self::new(document))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn display_name(&self) -> String {
+        self.document
+            .file_name
+            .clone()
+            .unwrap_or("[No Name]".to_string())
+    }
+}
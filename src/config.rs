@@ -3,7 +3,7 @@ use std::env;
 use std::fs;
 use std::path::{Path, PathBuf};
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, Default)]
 #[allow(dead_code)]
 pub struct Config {
     pub editor: EditorConfig,
@@ -21,6 +21,16 @@ pub struct EditorConfig {
     pub mouse_support: bool,
     #[serde(default = "default_theme")]
     pub theme: String,
+    /// Files of at least this many megabytes open in large-file mode.
+    #[serde(default = "default_large_file_mb")]
+    pub large_file_mb: u64,
+}
+
+impl EditorConfig {
+    /// `large_file_mb` in bytes.
+    pub fn large_file_size(&self) -> u64 {
+        self.large_file_mb.saturating_mul(1024 * 1024)
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -40,24 +50,96 @@ pub struct ThemeConfig {
     pub status_fg: String,
     // Syntax highlighting
     #[serde(default = "default_keyword")]
-    pub keyword: String,
+    pub keyword: HighlightStyle,
     #[serde(default = "default_string")]
-    pub string: String,
+    pub string: HighlightStyle,
     #[serde(default = "default_comment")]
-    pub comment: String,
+    pub comment: HighlightStyle,
     #[serde(default = "default_number")]
-    pub number: String,
+    pub number: HighlightStyle,
     #[serde(default = "default_type_color")]
-    pub type_color: String,
+    pub type_color: HighlightStyle,
     #[serde(default = "default_control_flow")]
-    pub control_flow: String,
+    pub control_flow: HighlightStyle,
+    // Finer classes; a theme without them falls back to one of the above
+    // (see `theme::Palette`)
+    #[serde(default)]
+    pub function: Option<HighlightStyle>,
+    #[serde(default)]
+    pub lifetime: Option<HighlightStyle>,
+    #[serde(default)]
+    pub macro_color: Option<HighlightStyle>,
+    #[serde(default)]
+    pub attribute: Option<HighlightStyle>,
+    #[serde(default)]
+    pub operator: Option<HighlightStyle>,
+    #[serde(default)]
+    pub punctuation: Option<HighlightStyle>,
+    #[serde(default)]
+    pub constant: Option<HighlightStyle>,
+    #[serde(default)]
+    pub escape: Option<HighlightStyle>,
+    #[serde(default)]
+    pub doc_comment: Option<HighlightStyle>,
 }
 
-impl Default for Config {
-    fn default() -> Self {
+/// How one kind of text is drawn. In a theme file it is either a color,
+/// `keyword = "#c678dd"`, or a table:
+/// `keyword = { fg = "#c678dd", bg = "#282c34", bold = true, italic = true }`.
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+#[serde(from = "StyleSpec")]
+pub struct HighlightStyle {
+    pub fg: Option<String>,
+    pub bg: Option<String>,
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+}
+
+impl HighlightStyle {
+    fn fg(color: &str) -> Self {
         Self {
-            editor: EditorConfig::default(),
-            theme: ThemeConfig::default(),
+            fg: Some(color.to_string()),
+            ..Self::default()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StyleSpec {
+    Color(String),
+    Table {
+        #[serde(default)]
+        fg: Option<String>,
+        #[serde(default)]
+        bg: Option<String>,
+        #[serde(default)]
+        bold: bool,
+        #[serde(default)]
+        italic: bool,
+        #[serde(default)]
+        underline: bool,
+    },
+}
+
+impl From<StyleSpec> for HighlightStyle {
+    fn from(spec: StyleSpec) -> Self {
+        match spec {
+            StyleSpec::Color(color) => HighlightStyle::fg(&color),
+            StyleSpec::Table {
+                fg,
+                bg,
+                bold,
+                italic,
+                underline,
+            } => HighlightStyle {
+                fg,
+                bg,
+                bold,
+                italic,
+                underline,
+            },
         }
     }
 }
@@ -69,6 +151,7 @@ impl Default for EditorConfig {
             line_numbers: true,
             mouse_support: true,
             theme: "default".into(),
+            large_file_mb: 50,
         }
     }
 }
@@ -82,12 +165,24 @@ impl Default for ThemeConfig {
             selection_bg: "#3e4451".into(),
             status_bg: "#3e4451".into(),
             status_fg: "#ffffff".into(),
-            keyword: "#c678dd".into(),
-            string: "#98c379".into(),
-            comment: "#5c6370".into(),
-            number: "#d19a66".into(),
-            type_color: "#e5c07b".into(),
-            control_flow: "#c678dd".into(),
+            keyword: default_keyword(),
+            string: default_string(),
+            comment: default_comment(),
+            number: default_number(),
+            type_color: default_type_color(),
+            control_flow: default_control_flow(),
+            function: Some(HighlightStyle::fg("#61afef")),
+            lifetime: Some(HighlightStyle::fg("#e06c75")),
+            macro_color: Some(HighlightStyle::fg("#56b6c2")),
+            attribute: Some(HighlightStyle::fg("#e5c07b")),
+            operator: Some(HighlightStyle::fg("#56b6c2")),
+            punctuation: None,
+            constant: Some(HighlightStyle::fg("#d19a66")),
+            escape: Some(HighlightStyle::fg("#56b6c2")),
+            doc_comment: Some(HighlightStyle {
+                italic: true,
+                ..HighlightStyle::fg("#7f848e")
+            }),
         }
     }
 }
@@ -104,6 +199,9 @@ fn default_mouse_support() -> bool {
 fn default_theme() -> String {
     "default".to_string()
 }
+fn default_large_file_mb() -> u64 {
+    50
+}
 fn default_background() -> String {
     "#1e1e1e".to_string()
 }
@@ -122,23 +220,23 @@ fn default_status_bg() -> String {
 fn default_status_fg() -> String {
     "#ffffff".to_string()
 }
-fn default_keyword() -> String {
-    "#c678dd".to_string()
+fn default_keyword() -> HighlightStyle {
+    HighlightStyle::fg("#c678dd")
 }
-fn default_string() -> String {
-    "#98c379".to_string()
+fn default_string() -> HighlightStyle {
+    HighlightStyle::fg("#98c379")
 }
-fn default_comment() -> String {
-    "#5c6370".to_string()
+fn default_comment() -> HighlightStyle {
+    HighlightStyle::fg("#5c6370")
 }
-fn default_number() -> String {
-    "#d19a66".to_string()
+fn default_number() -> HighlightStyle {
+    HighlightStyle::fg("#d19a66")
 }
-fn default_type_color() -> String {
-    "#e5c07b".to_string()
+fn default_type_color() -> HighlightStyle {
+    HighlightStyle::fg("#e5c07b")
 }
-fn default_control_flow() -> String {
-    "#c678dd".to_string()
+fn default_control_flow() -> HighlightStyle {
+    HighlightStyle::fg("#c678dd")
 }
 
 impl Config {
@@ -171,3 +269,27 @@ impl Config {
         config
     }
 }
+
+/// `$XDG_STATE_HOME/meow/` (by default `~/.local/state/meow/`), where state
+/// kept between sessions lives.
+pub fn state_dir() -> Option<PathBuf> {
+    let state_home = match env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".local/state"),
+    };
+    Some(state_home.join("meow"))
+}
+
+/// Where per-file state lives: `kind` is a directory under `state_dir()`,
+/// and the file inside it is named after a hash of `file`'s absolute path.
+pub fn state_file(kind: &str, file: &Path) -> Option<PathBuf> {
+    let state_dir = state_dir()?;
+    let file = fs::canonicalize(file)
+        .or_else(|_| std::path::absolute(file))
+        .ok()?;
+    let hash = crate::history::fnv1a(
+        crate::history::FNV_OFFSET_BASIS,
+        file.to_string_lossy().as_bytes(),
+    );
+    Some(state_dir.join(kind).join(format!("{:016x}", hash)))
+}
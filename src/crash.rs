@@ -0,0 +1,48 @@
+use crate::config;
+use crate::terminal;
+use std::backtrace::Backtrace;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::panic::{self, PanicHookInfo};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Makes a panic restore the terminal before its message is printed, and
+/// append a crash report with a backtrace to `state_dir()/crash.log`.
+pub fn install_panic_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        terminal::restore();
+        default_hook(info);
+        match write_report(info) {
+            Ok(path) => eprintln!(
+                "meow crashed. A crash report was written to {}",
+                path.display()
+            ),
+            Err(e) => eprintln!(
+                "meow crashed, and the crash report could not be written: {}",
+                e
+            ),
+        }
+    }));
+}
+
+fn write_report(info: &PanicHookInfo) -> io::Result<PathBuf> {
+    let dir =
+        config::state_dir().ok_or_else(|| io::Error::other("No place to keep crash reports"))?;
+    fs::create_dir_all(&dir)?;
+    let path = dir.join("crash.log");
+    let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    writeln!(
+        file,
+        "meow {} crashed at {} (Unix time)",
+        env!("CARGO_PKG_VERSION"),
+        time
+    )?;
+    writeln!(file, "{}", info)?;
+    writeln!(file, "\nBacktrace:\n{}\n", Backtrace::force_capture())?;
+    Ok(path)
+}
@@ -1,8 +1,21 @@
 use crate::editor::Position;
-use crate::row::Row;
+use crate::encoding::{Encoding, LineEnding};
+use crate::highlighter::{self, Highlighter};
+use crate::history::{self, Edit, History};
+use crate::loader;
+use crate::row::{LineState, Row};
+use crate::swap::{self, SwapFile};
+use crate::syntax::Syntax;
+use regex::Regex;
 use ropey::Rope;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fs;
-use std::io::{BufWriter, Error};
+use std::io::{BufWriter, Error, ErrorKind, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
 
 #[derive(PartialEq, Copy, Clone)]
 pub enum SearchDirection {
@@ -10,11 +23,127 @@ pub enum SearchDirection {
     Backward,
 }
 
+/// What `Document::check_disk` found out about the file.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum DiskChange {
+    Unchanged,
+    /// Another program wrote different contents to it.
+    Modified,
+    Deleted,
+}
+
+/// The size, modification time and hash of the file as it was last read or
+/// written, to tell whether another program changed it since.
+#[derive(PartialEq, Copy, Clone, Debug)]
+struct DiskState {
+    len: u64,
+    modified: Option<SystemTime>,
+    hash: u64,
+}
+
+impl DiskState {
+    fn new(metadata: &fs::Metadata, hash: u64) -> Self {
+        Self {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            hash,
+        }
+    }
+
+    fn read(path: &str) -> Result<Self, Error> {
+        let metadata = fs::metadata(path)?;
+        Ok(Self::new(&metadata, loader::hash_file(path)?))
+    }
+}
+
+/// How many rows `Document::row` keeps around.
+const ROW_CACHE_SIZE: usize = 1024;
+
+/// Rows built by `Document::row`, each with the state its line ends in, and
+/// the state each line starts in for the keyword highlighter.
+///
+/// An edit drops the rows of the lines it touched and moves the others along.
+/// Lines further down are only highlighted again if the state they start in
+/// changed, e.g. after typing `/*`. The syntax tree highlighter can change
+/// any line after an edit, so with it all rows are dropped instead.
+#[derive(Default)]
+struct RowCache {
+    rows: HashMap<usize, (Row, LineState)>,
+    // `states[y]` is the state line `y` starts in. Those before `valid` are
+    // up to date; the others are from before the last edits
+    states: Vec<LineState>,
+    valid: usize,
+    // The last line edits may have changed since `valid` was last caught up
+    edited: usize,
+}
+
+impl RowCache {
+    /// Keeps up with an edit on line `y` that took out `removed` line breaks
+    /// and put in `inserted`.
+    fn edit(&mut self, y: usize, removed: usize, inserted: usize) {
+        let moved = |line: usize| {
+            if line > y + removed {
+                Some(line - removed + inserted)
+            } else if line < y {
+                Some(line)
+            } else {
+                None
+            }
+        };
+        self.rows = self
+            .rows
+            .drain()
+            .filter_map(|(line, row)| Some((moved(line)?, row)))
+            .collect();
+        if self.states.len() > y + 1 + removed {
+            self.states.splice(
+                y + 1..y + 1 + removed,
+                std::iter::repeat_n(LineState::Normal, inserted),
+            );
+        } else {
+            self.states.truncate(y + 1);
+        }
+        self.valid = self.valid.min(y + 1);
+        self.edited = moved(self.edited).unwrap_or(y).max(y + inserted);
+    }
+
+    fn insert(&mut self, y: usize, row: Row, end: LineState) {
+        if self.rows.len() >= ROW_CACHE_SIZE {
+            self.rows.clear();
+        }
+        self.rows.insert(y, (row, end));
+    }
+}
+
 pub struct Document {
     pub content: Rope,
     pub file_name: Option<String>,
     pub dirty: bool,
-    pub syntax: &'static crate::syntax::Syntax,
+    pub syntax: Arc<Syntax>,
+    // The rope always holds '\n' line breaks; these describe the file on disk.
+    pub line_ending: LineEnding,
+    pub encoding: Encoding,
+    pub bom: bool,
+    /// Set by `open` when the saved undo history couldn't be used.
+    pub undo_error: Option<String>,
+    /// `:w` refuses to overwrite the file (e.g. another session is editing it).
+    pub read_only: bool,
+    /// A swap file left by an earlier session that died, set by `open` until
+    /// the user decides whether to recover it.
+    pub recovery: Option<SwapFile>,
+    // Counts changes to the text; `swapped_revision` is the one last written
+    // to the swap file
+    revision: u64,
+    swapped_revision: u64,
+    disk: Option<DiskState>,
+    // Hash of a changed file the user chose to keep the buffer over, so
+    // `check_disk` doesn't report it again
+    ignored_disk_hash: Option<u64>,
+    // Set for files above the size limit in the config: no highlighting
+    large: bool,
+    rows: RefCell<RowCache>,
+    history: History,
+    highlighter: Option<Highlighter>,
 }
 
 impl Default for Document {
@@ -23,31 +152,362 @@ impl Default for Document {
             content: Rope::new(),
             file_name: None,
             dirty: false,
-            syntax: crate::syntax::Syntax::default_ref(),
+            syntax: Syntax::plain(),
+            line_ending: LineEnding::default(),
+            encoding: Encoding::default(),
+            bom: false,
+            undo_error: None,
+            read_only: false,
+            recovery: None,
+            revision: 0,
+            swapped_revision: 0,
+            disk: None,
+            ignored_disk_hash: None,
+            large: false,
+            rows: RefCell::default(),
+            history: History::default(),
+            highlighter: None,
         }
     }
 }
 
 impl Document {
     pub fn open(filename: &str) -> Result<Self, std::io::Error> {
-        let contents = fs::read_to_string(filename)?;
-        let content = Rope::from_str(&contents);
-        let syntax = crate::syntax::Syntax::select(filename);
+        let metadata = fs::metadata(filename)?;
+        let loaded = loader::load(filename)?;
+        let disk = Some(DiskState::new(&metadata, loaded.hash));
+        let syntax = Syntax::select(filename, &edge_lines(&loaded.content));
+        let highlighter = Highlighter::for_file_type(&syntax.file_type);
 
-        Ok(Self {
-            content,
+        let mut document = Self {
+            content: loaded.content,
             file_name: Some(filename.to_string()),
             dirty: false,
             syntax,
-        })
+            line_ending: loaded.line_ending,
+            encoding: loaded.encoding,
+            bom: loaded.bom,
+            undo_error: None,
+            read_only: false,
+            recovery: None,
+            revision: 0,
+            swapped_revision: 0,
+            disk,
+            ignored_disk_hash: None,
+            large: false,
+            rows: RefCell::default(),
+            history: History::default(),
+            highlighter,
+        };
+        document.load_undo_history();
+        document.find_swap_file();
+        Ok(document)
+    }
+
+    /// Looks for a swap file left by another session. One that holds exactly
+    /// the text on disk has nothing to recover and is deleted.
+    fn find_swap_file(&mut self) {
+        let Some(file_name) = &self.file_name else {
+            return;
+        };
+        match SwapFile::read(file_name) {
+            Ok(swap) if swap.text == self.content => swap::remove(file_name),
+            Ok(swap) if !swap.is_own() => self.recovery = Some(swap),
+            _ => {}
+        }
     }
 
-    pub fn save(&self) -> Result<(), Error> {
-        if let Some(file_name) = &self.file_name {
-            let file = fs::File::create(file_name)?;
-            let mut writer = BufWriter::new(file);
-            self.content.write_to(&mut writer)?;
+    /// Writes the text to the swap file if it changed since the last call.
+    /// Read-only buffers and ones with a swap file still to be recovered
+    /// leave the swap file alone.
+    pub fn write_swap(&mut self) -> Result<(), Error> {
+        let Some(file_name) = &self.file_name else {
+            return Ok(());
+        };
+        if self.read_only || self.recovery.is_some() || self.revision == self.swapped_revision {
+            return Ok(());
         }
+        SwapFile::new(file_name, self.content.to_string()).write()?;
+        self.swapped_revision = self.revision;
+        Ok(())
+    }
+
+    /// Deletes the swap file, unless it belongs to another session.
+    pub fn remove_swap(&mut self) {
+        if let Some(file_name) = &self.file_name
+            && !self.read_only
+            && self.recovery.is_none()
+        {
+            swap::remove(file_name);
+        }
+    }
+
+    /// Replaces the text with the swap file's, as one step that can be undone
+    /// to get back to the file on disk.
+    pub fn recover(&mut self) {
+        let Some(swap) = self.recovery.take() else {
+            return;
+        };
+        self.replace_all(&swap.text);
+    }
+
+    fn replace_all(&mut self, text: &str) {
+        let start = Position::default();
+        self.begin_undo_group(start);
+        self.remove_text(0, self.content.len_chars(), start);
+        self.insert_text(0, text, start);
+        self.end_undo_group(start);
+    }
+
+    /// Compares the file on disk with what was last read or written. The
+    /// file is only read again when its size or modification time moved, and
+    /// one that was merely touched counts as unchanged.
+    pub fn check_disk(&mut self) -> DiskChange {
+        match self.changed_disk_state() {
+            Ok(Some(state)) if self.ignored_disk_hash != Some(state.hash) => DiskChange::Modified,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                // Reported once; a new file written there later is not ours
+                self.disk = None;
+                DiskChange::Deleted
+            }
+            _ => DiskChange::Unchanged,
+        }
+    }
+
+    /// The file's state on disk if its contents differ from `self.disk`.
+    fn changed_disk_state(&mut self) -> Result<Option<DiskState>, Error> {
+        let (Some(file_name), Some(disk)) = (&self.file_name, self.disk) else {
+            return Ok(None);
+        };
+        let metadata = fs::metadata(file_name)?;
+        if metadata.len() == disk.len && metadata.modified().ok() == disk.modified {
+            return Ok(None);
+        }
+        let state = DiskState::new(&metadata, loader::hash_file(file_name)?);
+        if state.hash == disk.hash {
+            self.disk = Some(state);
+            return Ok(None);
+        }
+        Ok(Some(state))
+    }
+
+    /// Stops `check_disk` from reporting the file's current contents, after
+    /// the user chose to keep the buffer. `save` still refuses to overwrite
+    /// them until `allow_overwrite`.
+    pub fn ignore_disk_change(&mut self) {
+        if let Ok(Some(state)) = self.changed_disk_state() {
+            self.ignored_disk_hash = Some(state.hash);
+        }
+    }
+
+    /// `:w!`: lets the next `save` overwrite the file even if another program
+    /// changed it.
+    pub fn allow_overwrite(&mut self) {
+        self.disk = None;
+    }
+
+    /// The file's contents as they are on disk now.
+    pub fn read_disk_text(&self) -> Result<String, Error> {
+        let Some(file_name) = &self.file_name else {
+            return Err(Error::other("No file name"));
+        };
+        Ok(loader::load(file_name)?.content.to_string())
+    }
+
+    /// Takes over the file's new contents after another program changed it.
+    /// Unlike `reload` this keeps the undo history: the reload is one more
+    /// step, which `u` undoes. Large files start a new history instead, as
+    /// two copies of the text in it would cost too much memory.
+    pub fn reload_changed(&mut self) -> Result<(), Error> {
+        let Some(file_name) = &self.file_name else {
+            return Err(Error::other("No file name"));
+        };
+        let metadata = fs::metadata(file_name)?;
+        let loaded = loader::load(file_name)?;
+        let disk = DiskState::new(&metadata, loaded.hash);
+        if self.large {
+            self.content = loaded.content;
+            self.history = History::default();
+            self.revision += 1;
+            *self.rows.get_mut() = RowCache::default();
+        } else if loaded.content != self.content {
+            self.replace_all(&loaded.content.to_string());
+        }
+        self.encoding = loaded.encoding;
+        self.bom = loaded.bom;
+        self.line_ending = loaded.line_ending;
+        self.dirty = false;
+        self.history.mark_saved();
+        self.disk = Some(disk);
+        self.ignored_disk_hash = None;
+        Ok(())
+    }
+
+    /// Deletes the swap file found by `open` without using it.
+    pub fn discard_recovery(&mut self) {
+        if self.recovery.take().is_some()
+            && let Some(file_name) = &self.file_name
+        {
+            swap::remove(file_name);
+        }
+    }
+
+    /// Picks up the undo history saved with the file, if it still matches.
+    /// A missing history is normal; a corrupt or outdated one is skipped and
+    /// reported through `undo_error`.
+    fn load_undo_history(&mut self) {
+        let Some(path) = self
+            .file_name
+            .as_deref()
+            .and_then(|f| history::undo_file_path(Path::new(f)))
+        else {
+            return;
+        };
+        match History::load(&path, self.content_hash()) {
+            Ok(history) => self.history = history,
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => self.undo_error = Some(format!("Undo history ignored: {}", e)),
+        }
+    }
+
+    /// Saves the undo history beside the file (see `history::undo_file_path`).
+    fn save_undo_history(&self) -> Result<(), Error> {
+        let path = self
+            .file_name
+            .as_deref()
+            .and_then(|f| history::undo_file_path(Path::new(f)))
+            .ok_or_else(|| Error::other("No place to keep undo history"))?;
+        self.history.save(&path, self.content_hash())
+    }
+
+    /// FNV-1a hash of the text, which ties a saved undo history to it.
+    fn content_hash(&self) -> u64 {
+        self.content
+            .chunks()
+            .fold(history::FNV_OFFSET_BASIS, |hash, chunk| {
+                history::fnv1a(hash, chunk.as_bytes())
+            })
+    }
+
+    pub fn save(&mut self) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "buffer is read-only (add ! to override)",
+            ));
+        }
+        if matches!(self.changed_disk_state(), Ok(Some(_))) {
+            return Err(Error::other(
+                "file changed on disk since it was read (add ! to override)",
+            ));
+        }
+        let Some(file_name) = &self.file_name else {
+            return Err(Error::other("No file name"));
+        };
+        write_atomic(Path::new(file_name), self)?;
+        self.finish_save();
+        Ok(())
+    }
+
+    /// Writes the buffer to `path` and, once that worked, takes `path` as
+    /// its file name. The buffer is no longer read-only.
+    pub fn save_as(&mut self, path: &str) -> Result<(), Error> {
+        write_atomic(Path::new(path), self)?;
+        self.remove_swap();
+        self.recovery = None;
+        self.read_only = false;
+        self.set_file_name(path);
+        self.finish_save();
+        Ok(())
+    }
+
+    /// Bookkeeping after the text was written to the buffer's own file.
+    fn finish_save(&mut self) {
+        self.disk = self
+            .file_name
+            .as_deref()
+            .and_then(|f| DiskState::read(f).ok());
+        self.ignored_disk_hash = None;
+        self.dirty = false;
+        self.history.mark_saved();
+        self.remove_swap();
+        self.swapped_revision = self.revision;
+        // The file itself is safe; losing its undo history isn't worth an error
+        let _ = self.save_undo_history();
+    }
+
+    /// Writes a copy of the buffer to `path` without adopting it as the file name.
+    pub fn write_copy(&self, path: &str) -> Result<(), Error> {
+        write_atomic(Path::new(path), self)
+    }
+
+    /// Appends the buffer to the end of `path`, creating it if needed.
+    pub fn append_to(&self, path: &str) -> Result<(), Error> {
+        let file = fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let mut writer = BufWriter::new(file);
+        self.write_contents(&mut writer, false)?;
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()
+    }
+
+    /// Writes the text in the file's own encoding and line ending style.
+    fn write_contents<W: Write>(&self, writer: &mut W, with_bom: bool) -> Result<(), Error> {
+        if with_bom && self.bom {
+            writer.write_all(self.encoding.bom())?;
+        }
+        let mut buf = Vec::new();
+        for chunk in self.content.chunks() {
+            buf.clear();
+            match self.line_ending {
+                LineEnding::Lf => self.encoding.encode(chunk, &mut buf)?,
+                LineEnding::Crlf => self
+                    .encoding
+                    .encode(&chunk.replace('\n', "\r\n"), &mut buf)?,
+            }
+            writer.write_all(&buf)?;
+        }
+        Ok(())
+    }
+
+    /// Switches the line ending style used when writing the file.
+    pub fn set_line_ending(&mut self, line_ending: LineEnding) {
+        if self.line_ending != line_ending {
+            self.line_ending = line_ending;
+            self.dirty = true;
+            self.history.mark_unsaved();
+        }
+    }
+
+    /// Renames the buffer and picks the syntax for the new name.
+    pub fn set_file_name(&mut self, file_name: &str) {
+        self.file_name = Some(file_name.to_string());
+        self.disk = None;
+        self.ignored_disk_hash = None;
+        let syntax = Syntax::select(file_name, &edge_lines(&self.content));
+        if syntax.file_type != self.syntax.file_type {
+            self.syntax = syntax;
+            self.reset_highlighting();
+        }
+    }
+
+    /// Highlights the buffer as `syntax`, whatever its file name says.
+    pub fn set_syntax(&mut self, syntax: Arc<Syntax>) {
+        self.syntax = syntax;
+        self.reset_highlighting();
+    }
+
+    /// Re-reads the file from disk, discarding unsaved changes. The undo
+    /// history is the one saved with the file.
+    pub fn reload(&mut self) -> Result<(), Error> {
+        let Some(file_name) = self.file_name.clone() else {
+            return Err(Error::other("No file name"));
+        };
+        self.remove_swap();
+        let large = self.large;
+        *self = Document::open(&file_name)?;
+        self.set_large(large);
         Ok(())
     }
 
@@ -56,25 +516,98 @@ impl Document {
             return None;
         }
 
-        let line = self.content.line(index);
-        // Ropey lines include the newline character, but Row expects without.
-        // We need to strip it.
-        let line_cow = line.to_string(); // Convert to String (owned)
-        // Check if ends with newline and remove it
-        let content = if line_cow.ends_with('\n') {
-            let mut s = line_cow;
-            s.pop();
-            if s.ends_with('\r') {
-                s.pop();
+        let mut cache = self.rows.borrow_mut();
+        let state = match &self.highlighter {
+            None if !self.large => self.line_state(&mut cache, index),
+            _ => LineState::Normal,
+        };
+        if let Some((row, _)) = cache.rows.get(&index) {
+            return Some(row.clone());
+        }
+        let (row, end) = self.build_row(index, state);
+        // A row built before the syntax tree caught up with an edit has no
+        // highlighting yet, so it isn't kept
+        if self.highlighter.as_ref().is_none_or(Highlighter::is_ready) {
+            cache.insert(index, row.clone(), end);
+        }
+        Some(row)
+    }
+
+    /// Line `y` highlighted, starting in `state`, and the state it ends in.
+    fn build_row(&self, y: usize, state: LineState) -> (Row, LineState) {
+        let content = self.line_text(y);
+        let mut row = Row::from(&*content);
+        let end = match &self.highlighter {
+            Some(highlighter) => {
+                highlighter.highlight_line(&self.content, y, &mut row.highlighting);
+                LineState::Normal
             }
-            s
+            None if self.large => LineState::Normal,
+            None => row.update_highlighting(&self.syntax, state),
+        };
+        (row, end)
+    }
+
+    /// The state line `y` starts in for the keyword highlighter. Lines from
+    /// the first one an edit may have changed are highlighted again until
+    /// one past the edits starts in the same state as before.
+    fn line_state(&self, cache: &mut RowCache, y: usize) -> LineState {
+        if cache.states.is_empty() {
+            cache.states.push(LineState::Normal);
+        }
+        cache.valid = cache.valid.max(1);
+        while cache.valid <= y {
+            let line = cache.valid - 1;
+            let end = match cache.rows.get(&line) {
+                Some((_, end)) => *end,
+                None => {
+                    Row::from(&*self.line_text(line)).end_state(&self.syntax, cache.states[line])
+                }
+            };
+            let next = line + 1;
+            cache.valid = next + 1;
+            if next == cache.states.len() {
+                cache.states.push(end);
+            } else if cache.states[next] != end {
+                cache.states[next] = end;
+                cache.rows.remove(&next);
+            } else if next > cache.edited {
+                // The rest of the text is as it was, and so are its states
+                cache.valid = cache.states.len();
+                cache.edited = 0;
+            }
+        }
+        cache.states[y]
+    }
+
+    /// Whether the file is over the size limit for highlighting.
+    pub fn is_large(&self) -> bool {
+        self.large
+    }
+
+    /// Turns large-file mode on or off. Large files aren't highlighted, which
+    /// would mean parsing all of them.
+    pub fn set_large(&mut self, large: bool) {
+        if large != self.large {
+            self.large = large;
+            self.reset_highlighting();
+        }
+    }
+
+    fn reset_highlighting(&mut self) {
+        self.highlighter = if self.large {
+            None
         } else {
-            line_cow
+            Highlighter::for_file_type(&self.syntax.file_type)
         };
+        *self.rows.get_mut() = RowCache::default();
+    }
 
-        let mut row = Row::from(content.as_str());
-        row.update_highlighting(self.syntax);
-        Some(row)
+    /// Brings the syntax tree up to date with the edits made since the last call.
+    pub fn update_syntax_tree(&mut self) {
+        if let Some(highlighter) = &mut self.highlighter {
+            highlighter.parse(&self.content);
+        }
     }
 
     #[allow(dead_code)]
@@ -86,6 +619,14 @@ impl Document {
         self.content.len_lines()
     }
 
+    /// Length of line `y` in chars, not counting the line break.
+    pub fn line_len(&self, y: usize) -> usize {
+        if y >= self.len() {
+            return 0;
+        }
+        self.line_text(y).chars().count()
+    }
+
     pub fn size_bytes(&self) -> usize {
         self.content.len_bytes()
     }
@@ -93,8 +634,14 @@ impl Document {
     pub fn insert(&mut self, at: &crate::editor::Position, c: char) {
         let char_idx = self.position_to_char_idx(at);
         if char_idx <= self.content.len_chars() {
-            self.content.insert_char(char_idx, c);
-            self.dirty = true;
+            self.insert_text(char_idx, &c.to_string(), *at);
+        }
+    }
+
+    pub fn insert_str(&mut self, at: &crate::editor::Position, text: &str) {
+        let char_idx = self.position_to_char_idx(at);
+        if !text.is_empty() {
+            self.insert_text(char_idx, text, *at);
         }
     }
 
@@ -105,42 +652,144 @@ impl Document {
     pub fn delete(&mut self, at: &crate::editor::Position) {
         let char_idx = self.position_to_char_idx(at);
         if char_idx < self.content.len_chars() {
-            self.content.remove(char_idx..char_idx + 1);
-            self.dirty = true;
+            self.remove_text(char_idx, char_idx + 1, *at);
         }
     }
 
-    // Helper to convert Position (x, y) to absolute char index for Rope
-    fn position_to_char_idx(&self, pos: &Position) -> usize {
-        if pos.y >= self.len() {
-            return self.content.len_chars();
+    /// Opens an undo group: every edit until `end_undo_group` is undone as one step.
+    pub fn begin_undo_group(&mut self, cursor: Position) {
+        self.history.begin(cursor);
+    }
+
+    pub fn end_undo_group(&mut self, cursor: Position) {
+        self.history.commit(cursor);
+    }
+
+    /// Reverts the last transaction and returns where the cursor was before it.
+    pub fn undo(&mut self) -> Option<Position> {
+        let transaction = self.history.undo()?;
+        for edit in transaction.edits.iter().rev() {
+            match edit {
+                Edit::Insert { at, text } => self.apply_remove(*at, *at + text.chars().count()),
+                Edit::Delete { at, text } => self.apply_insert(*at, text),
+            }
         }
-        let line_char_idx = self.content.line_to_char(pos.y);
+        self.dirty = !self.history.is_saved();
+        Some(transaction.cursor_before)
+    }
 
-        // Ensure we don't go beyond the line (including newline)
-        // Note: x is visual index, but we assume 1 char = 1 index for now (UTF-8).
-        // Rope handles UTF-8 correctly.
+    /// Re-applies the last undone transaction and returns the cursor after it.
+    pub fn redo(&mut self) -> Option<Position> {
+        let transaction = self.history.redo()?;
+        for edit in &transaction.edits {
+            match edit {
+                Edit::Insert { at, text } => self.apply_insert(*at, text),
+                Edit::Delete { at, text } => self.apply_remove(*at, *at + text.chars().count()),
+            }
+        }
+        self.dirty = !self.history.is_saved();
+        Some(transaction.cursor_after)
+    }
 
-        // If x is past the end of the line (excluding newline), we clamp it?
-        // But insert needs to be able to append.
+    // All recorded mutations of the rope go through these two helpers.
+    fn insert_text(&mut self, char_idx: usize, text: &str, cursor: Position) {
+        self.apply_insert(char_idx, text);
+        self.history.record(
+            Edit::Insert {
+                at: char_idx,
+                text: text.to_string(),
+            },
+            cursor,
+        );
+        self.dirty = true;
+    }
 
-        // If line ends with \n, valid x indices are 0..len-1 (for insertion before \n) ?
-        // Usually editors allow cursor to be at the newline char (which effectively appends to line).
+    fn remove_text(&mut self, start: usize, end: usize, cursor: Position) {
+        let text = self.content.slice(start..end).to_string();
+        self.apply_remove(start, end);
+        self.history
+            .record(Edit::Delete { at: start, text }, cursor);
+        self.dirty = true;
+    }
+
+    // Raw rope mutations; these keep the syntax tree in step with the text.
+    fn apply_insert(&mut self, char_idx: usize, text: &str) {
+        if let Some(highlighter) = &mut self.highlighter {
+            let start_byte = self.content.char_to_byte(char_idx);
+            highlighter.edit(&highlighter::input_edit(&self.content, start_byte, 0, text));
+        }
+        let y = self.content.char_to_line(char_idx);
+        let lines = self.content.len_lines();
+        self.content.insert(char_idx, text);
+        self.revision += 1;
+        self.edit_rows(y, lines);
+    }
 
-        // actually, Ropey::line includes \n.
-        // line_char_idx + pos.x.
+    fn apply_remove(&mut self, start: usize, end: usize) {
+        if let Some(highlighter) = &mut self.highlighter {
+            let start_byte = self.content.char_to_byte(start);
+            let old_len = self.content.char_to_byte(end) - start_byte;
+            highlighter.edit(&highlighter::input_edit(
+                &self.content,
+                start_byte,
+                old_len,
+                "",
+            ));
+        }
+        let y = self.content.char_to_line(start);
+        let lines = self.content.len_lines();
+        self.content.remove(start..end);
+        self.revision += 1;
+        self.edit_rows(y, lines);
+    }
 
-        // We verify that pos.x is valid for this line.
-        // Row len doesn't include \n.
-        // So pos.x <= row_len.
+    /// Updates the row cache after an edit on line `y`, when the text had
+    /// `lines` lines.
+    fn edit_rows(&mut self, y: usize, lines: usize) {
+        let cache = self.rows.get_mut();
+        if self.highlighter.is_some() {
+            cache.rows.clear();
+            return;
+        }
+        let now = self.content.len_lines();
+        cache.edit(y, lines.saturating_sub(now), now.saturating_sub(lines));
+    }
 
-        if pos.x + line_char_idx > self.content.len_chars() {
+    /// Converts a `Position` (a char column within a line) to an absolute char
+    /// index into the rope. A column past the end of the line is clamped to the
+    /// end, so it never spills into the next line.
+    pub fn position_to_char_idx(&self, pos: &Position) -> usize {
+        if pos.y >= self.len() {
             return self.content.len_chars();
         }
+        let line_char_idx = self.content.line_to_char(pos.y);
+        line_char_idx + pos.x.min(self.line_len(pos.y))
+    }
+
+    pub fn char_idx_to_position(&self, idx: usize) -> Position {
+        let idx = idx.min(self.content.len_chars());
+        let y = self.content.char_to_line(idx);
+        Position {
+            x: idx - self.content.line_to_char(y),
+            y,
+        }
+    }
 
-        line_char_idx + pos.x
+    /// Text between two char indices, end exclusive.
+    pub fn slice(&self, start: usize, end: usize) -> String {
+        let len = self.content.len_chars();
+        self.content.slice(start.min(len)..end.min(len)).to_string()
+    }
+
+    /// Removes the chars between two char indices, end exclusive.
+    pub fn delete_chars(&mut self, start: usize, end: usize, cursor: Position) {
+        let end = end.min(self.content.len_chars());
+        if start < end {
+            self.remove_text(start, end, cursor);
+        }
     }
 
+    #[allow(dead_code)]
     pub fn get_substring(&self, start: &Position, end: &Position) -> String {
         let start_idx = self.position_to_char_idx(start);
         let end_idx = self.position_to_char_idx(end);
@@ -182,65 +831,683 @@ impl Document {
         let end_idx = (end_idx + 1).min(len);
 
         if start_idx < len {
-            self.content.remove(start_idx..end_idx);
-            self.dirty = true;
+            let cursor = if start.y < end.y || (start.y == end.y && start.x <= end.x) {
+                *start
+            } else {
+                *end
+            };
+            self.remove_text(start_idx, end_idx, cursor);
         }
     }
 
-    pub fn find(&self, query: &str, at: &Position, direction: SearchDirection) -> Option<Position> {
-        if query.is_empty() {
+    /// Finds the next match of `regex` from `at` in `direction`, wrapping around
+    /// the end of the buffer. A forward search accepts a match starting at `at`;
+    /// a backward search only one starting before it.
+    ///
+    /// Lines are matched one at a time and borrowed straight from the rope when
+    /// they sit in a single chunk, so searching doesn't copy the buffer.
+    pub fn find(
+        &self,
+        regex: &Regex,
+        at: &Position,
+        direction: SearchDirection,
+    ) -> Option<Position> {
+        let lines = self.len();
+        if at.y >= lines {
             return None;
         }
-
-        let start_char_idx = self.position_to_char_idx(at);
-        let content_str = self.content.to_string(); // Converting whole rope to string is expensive but simplest for search now.
-        // Ropey doesn't have built-in search yet? It does have iterators.
-        // For efficiency we should iterate chunks, but for now `to_string` is acceptable for MVP migration.
+        let start_byte = |text: &str| {
+            text.char_indices()
+                .nth(at.x)
+                .map_or(text.len(), |(byte, _)| byte)
+        };
+        let position = |y: usize, text: &str, byte: usize| Position {
+            x: text[..byte].chars().count(),
+            y,
+        };
 
         match direction {
             SearchDirection::Forward => {
-                // Search from start_char_idx
-                if let Some(idx) = content_str[start_char_idx..].find(query) {
-                    let found_idx = start_char_idx + idx;
-                    // Convert back to Position
-                    let line_idx = self.content.char_to_line(found_idx);
-                    let line_start = self.content.line_to_char(line_idx);
-                    let x = found_idx - line_start;
-                    return Some(Position { x, y: line_idx });
-                } else {
-                    // Wrap around? Original implementation wrapped.
-                    if let Some(idx) = content_str.find(query) {
-                        let found_idx = idx;
-                        let line_idx = self.content.char_to_line(found_idx);
-                        let line_start = self.content.line_to_char(line_idx);
-                        let x = found_idx - line_start;
-                        return Some(Position { x, y: line_idx });
+                let text = self.line_text(at.y);
+                if at.x <= text.chars().count()
+                    && let Some(m) = regex.find_at(&text, start_byte(&text))
+                {
+                    return Some(position(at.y, &text, m.start()));
+                }
+                // The rest of the buffer, then from the top back round to `at`
+                for y in (at.y + 1..lines).chain(0..=at.y) {
+                    let text = self.line_text(y);
+                    if let Some(m) = regex.find(&text) {
+                        return Some(position(y, &text, m.start()));
                     }
                 }
             }
             SearchDirection::Backward => {
-                // Search before start_char_idx
-                // find (forward) then filter? or rfind?
-                // `rfind` searches from right.
-                if let Some(idx) = content_str[..start_char_idx].rfind(query) {
-                    let found_idx = idx;
-                    let line_idx = self.content.char_to_line(found_idx);
-                    let line_start = self.content.line_to_char(line_idx);
-                    let x = found_idx - line_start;
-                    return Some(Position { x, y: line_idx });
-                } else {
-                    // Wrap around to end
-                    if let Some(idx) = content_str.rfind(query) {
-                        let found_idx = idx;
-                        let line_idx = self.content.char_to_line(found_idx);
-                        let line_start = self.content.line_to_char(line_idx);
-                        let x = found_idx - line_start;
-                        return Some(Position { x, y: line_idx });
+                let text = self.line_text(at.y);
+                let limit = start_byte(&text);
+                if let Some(m) = regex
+                    .find_iter(&text)
+                    .take_while(|m| m.start() < limit)
+                    .last()
+                {
+                    return Some(position(at.y, &text, m.start()));
+                }
+                for y in (0..at.y).rev().chain((at.y..lines).rev()) {
+                    let text = self.line_text(y);
+                    if let Some(m) = regex.find_iter(&text).last() {
+                        return Some(position(y, &text, m.start()));
                     }
                 }
             }
         }
-
         None
     }
+
+    /// Replaces the first match of `regex` on line `y` (every match if `global`)
+    /// with `replacement`, which may refer to capture groups as `${1}`.
+    /// Returns the number of replacements made.
+    pub fn substitute_line(
+        &mut self,
+        y: usize,
+        regex: &Regex,
+        replacement: &str,
+        global: bool,
+    ) -> usize {
+        if y >= self.len() {
+            return 0;
+        }
+        let line_start = self.content.line_to_char(y);
+        let mut replacements = Vec::new();
+        {
+            let text = self.line_text(y);
+            let mut char_idx = line_start;
+            let mut byte_idx = 0;
+            for caps in regex.captures_iter(&text) {
+                let m = caps.get(0).unwrap();
+                char_idx += text[byte_idx..m.start()].chars().count();
+                let start = char_idx;
+                char_idx += m.as_str().chars().count();
+                byte_idx = m.end();
+
+                let mut expanded = String::new();
+                caps.expand(replacement, &mut expanded);
+                replacements.push((start, char_idx, expanded));
+                if !global {
+                    break;
+                }
+            }
+        }
+
+        // Back to front, so earlier char indices stay valid
+        let cursor = Position { x: 0, y };
+        for (start, end, text) in replacements.iter().rev() {
+            if start < end {
+                self.remove_text(*start, *end, cursor);
+            }
+            if !text.is_empty() {
+                self.insert_text(*start, text, cursor);
+            }
+        }
+        replacements.len()
+    }
+
+    /// The text of line `y` without its line break, borrowed from the rope
+    /// unless the line straddles a chunk boundary.
+    fn line_text(&self, y: usize) -> Cow<'_, str> {
+        let mut text: Cow<str> = self.content.line(y).into();
+        let len = text.strip_suffix('\n').map_or(text.len(), |rest| {
+            rest.strip_suffix('\r').unwrap_or(rest).len()
+        });
+        match &mut text {
+            Cow::Borrowed(s) => *s = &s[..len],
+            Cow::Owned(s) => s.truncate(len),
+        }
+        text
+    }
+}
+
+/// How many lines at each end of the text are searched for a modeline.
+const MODELINE_LINES: usize = 5;
+
+/// The start of the first and last few lines of the text, where a `#!` line
+/// or a modeline can be. The first line comes first.
+fn edge_lines(content: &Rope) -> Vec<String> {
+    let len = content.len_lines();
+    let tail = len.saturating_sub(MODELINE_LINES).max(MODELINE_LINES);
+    (0..len.min(MODELINE_LINES))
+        .chain(tail..len)
+        .map(|i| content.line(i).chars().take(256).collect())
+        .collect()
+}
+
+/// Writes `document` to a temporary file next to `path`, syncs it and renames it
+/// over `path`, so a failed write never leaves a truncated file behind. The
+/// original file's permissions are carried over.
+fn write_atomic(path: &Path, document: &Document) -> Result<(), Error> {
+    // Write through symlinks rather than replacing them with a regular file
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let Some(name) = path.file_name() else {
+        return Err(Error::new(ErrorKind::InvalidInput, "Not a file path"));
+    };
+    let tmp_path = dir.join(format!(
+        ".{}.meow-{}.tmp",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    let permissions = fs::metadata(&path).ok().map(|m| m.permissions());
+
+    let result = (|| {
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp_path)?;
+        let mut writer = BufWriter::new(file);
+        document.write_contents(&mut writer, true)?;
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        if let Some(permissions) = permissions {
+            file.set_permissions(permissions)?;
+        }
+        file.sync_all()?;
+        fs::rename(&tmp_path, &path)
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    } else if let Ok(dir) = fs::File::open(dir) {
+        // Persist the rename itself; not every platform supports this
+        let _ = dir.sync_all();
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search;
+    use std::path::PathBuf;
+
+    fn doc(text: &str) -> Document {
+        Document {
+            content: Rope::from_str(text),
+            ..Document::default()
+        }
+    }
+
+    /// An empty directory under the temp dir, for tests that write files.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("meow-document-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn pos(x: usize, y: usize) -> Position {
+        Position { x, y }
+    }
+
+    fn find(
+        document: &Document,
+        pattern: &str,
+        at: Position,
+        dir: SearchDirection,
+    ) -> Option<Position> {
+        document.find(&search::compile(pattern).unwrap(), &at, dir)
+    }
+
+    #[test]
+    fn find_forward_after_emoji() {
+        let d = doc("😀 foo 😀 foo");
+        assert_eq!(
+            find(&d, "foo", pos(0, 0), SearchDirection::Forward),
+            Some(pos(2, 0))
+        );
+        assert_eq!(
+            find(&d, "foo", pos(3, 0), SearchDirection::Forward),
+            Some(pos(8, 0))
+        );
+        // Wraps back round to the first match
+        assert_eq!(
+            find(&d, "foo", pos(9, 0), SearchDirection::Forward),
+            Some(pos(2, 0))
+        );
+    }
+
+    #[test]
+    fn find_backward_in_cjk() {
+        let d = doc("日本語テキスト日本");
+        assert_eq!(
+            find(&d, "日本", pos(9, 0), SearchDirection::Backward),
+            Some(pos(7, 0))
+        );
+        assert_eq!(
+            find(&d, "日本", pos(7, 0), SearchDirection::Backward),
+            Some(pos(0, 0))
+        );
+        assert_eq!(
+            find(&d, "日本", pos(0, 0), SearchDirection::Backward),
+            Some(pos(7, 0))
+        );
+    }
+
+    #[test]
+    fn find_across_lines_with_multibyte_text() {
+        let d = doc("ünïcödé\n日本語\nhello 🌍 world\n");
+        assert_eq!(
+            find(&d, "world", pos(0, 0), SearchDirection::Forward),
+            Some(pos(8, 2))
+        );
+        assert_eq!(
+            find(&d, "語", pos(3, 2), SearchDirection::Backward),
+            Some(pos(2, 1))
+        );
+        assert_eq!(
+            find(&d, "ö", pos(0, 1), SearchDirection::Forward),
+            Some(pos(4, 0))
+        );
+    }
+
+    #[test]
+    fn find_counts_combining_marks_as_chars() {
+        // "e" followed by U+0301 COMBINING ACUTE ACCENT is two chars
+        let d = doc("e\u{301}cole e\u{301}cole");
+        assert_eq!(
+            find(&d, "cole", pos(0, 0), SearchDirection::Forward),
+            Some(pos(2, 0))
+        );
+        assert_eq!(
+            find(&d, "cole", pos(3, 0), SearchDirection::Forward),
+            Some(pos(9, 0))
+        );
+    }
+
+    #[test]
+    fn find_is_smart_case_for_non_ascii() {
+        let d = doc("ÉCOLE école");
+        assert_eq!(
+            find(&d, "école", pos(0, 0), SearchDirection::Forward),
+            Some(pos(0, 0))
+        );
+        assert_eq!(
+            find(&d, "ÉCOLE", pos(1, 0), SearchDirection::Forward),
+            Some(pos(0, 0))
+        );
+        assert_eq!(
+            find(&d, "é", pos(1, 0), SearchDirection::Forward),
+            Some(pos(6, 0))
+        );
+    }
+
+    #[test]
+    fn find_past_end_of_line_does_not_panic() {
+        let d = doc("日本\n語");
+        assert_eq!(
+            find(&d, "日", pos(3, 0), SearchDirection::Forward),
+            Some(pos(0, 0))
+        );
+        assert_eq!(
+            find(&d, "日", pos(30, 0), SearchDirection::Backward),
+            Some(pos(0, 0))
+        );
+        assert_eq!(find(&d, "x", pos(0, 0), SearchDirection::Forward), None);
+    }
+
+    #[test]
+    fn insert_between_multibyte_chars() {
+        let mut d = doc("日本");
+        d.insert(&pos(2, 0), '😀');
+        d.insert(&pos(1, 0), 'a');
+        assert_eq!(d.content.to_string(), "日a本😀");
+        assert!(d.dirty);
+    }
+
+    #[test]
+    fn insert_past_end_of_line_stays_on_that_line() {
+        let mut d = doc("日本\nabc");
+        d.insert(&pos(100, 0), 'x');
+        assert_eq!(d.content.to_string(), "日本x\nabc");
+    }
+
+    #[test]
+    fn delete_emoji_and_combining_mark() {
+        let mut d = doc("a😀b e\u{301}x");
+        d.delete(&pos(1, 0));
+        assert_eq!(d.content.to_string(), "ab e\u{301}x");
+        d.delete(&pos(4, 0));
+        assert_eq!(d.content.to_string(), "ab ex");
+    }
+
+    #[test]
+    fn delete_range_across_lines() {
+        let mut d = doc("日本語\n😀😀😀\nend");
+        assert_eq!(d.get_substring(&pos(1, 0), &pos(1, 1)), "本語\n😀😀");
+        d.delete_range(&pos(1, 1), &pos(1, 0));
+        assert_eq!(d.content.to_string(), "日😀\nend");
+    }
+
+    #[test]
+    fn undo_restores_multibyte_text() {
+        let mut d = doc("日本😀");
+        d.delete(&pos(1, 0));
+        d.insert(&pos(0, 0), 'é');
+        assert_eq!(d.content.to_string(), "é日😀");
+        d.undo();
+        d.undo();
+        assert_eq!(d.content.to_string(), "日本😀");
+        d.redo();
+        assert_eq!(d.content.to_string(), "日😀");
+    }
+
+    #[test]
+    fn undo_back_to_saved_text_is_not_dirty() {
+        let mut d = doc("abc");
+        d.insert(&pos(3, 0), 'd');
+        assert!(d.dirty);
+        d.undo();
+        assert!(!d.dirty);
+        d.redo();
+        assert!(d.dirty);
+        d.undo();
+        d.insert(&pos(0, 0), 'x');
+        d.undo();
+        assert!(!d.dirty);
+
+        // Once the saved step is in the redo stack, a new edit drops it
+        d.insert(&pos(0, 0), 'y');
+        d.history.mark_saved();
+        d.undo();
+        d.insert(&pos(0, 0), 'z');
+        d.undo();
+        assert!(d.dirty);
+    }
+
+    #[test]
+    fn substitute_with_capture_groups_after_emoji() {
+        let mut d = doc("😀 a=1, b=2\n");
+        let regex = search::compile(r"(\w)=(\d)").unwrap();
+        assert_eq!(d.substitute_line(0, &regex, "${2}=${1}", true), 2);
+        assert_eq!(d.content.to_string(), "😀 1=a, 2=b\n");
+    }
+
+    #[test]
+    fn substitute_first_match_only_in_cjk() {
+        let mut d = doc("日本 日本");
+        let regex = search::compile("日本").unwrap();
+        assert_eq!(d.substitute_line(0, &regex, "にほん", false), 1);
+        assert_eq!(d.content.to_string(), "にほん 日本");
+    }
+
+    #[test]
+    fn row_length_is_in_chars() {
+        let d = doc("日本😀e\u{301}\nnext");
+        let row = d.row(0).unwrap();
+        assert_eq!(row.content, "日本😀e\u{301}");
+        assert_eq!(row.len(), 5);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn save_replaces_the_file_and_keeps_its_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_dir("save");
+        let path = dir.join("file.txt");
+        fs::write(&path, "old\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+
+        let mut d = Document::open(path.to_str().unwrap()).unwrap();
+        d.insert_str(&pos(0, 0), "new ");
+        d.save().unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        let contents = fs::read_to_string(&path).unwrap();
+        let entries = fs::read_dir(&dir).unwrap().count();
+        fs::remove_dir_all(&dir).unwrap();
+        if let Some(undo_file) = history::undo_file_path(&path) {
+            let _ = fs::remove_file(undo_file);
+        }
+
+        assert_eq!(mode & 0o777, 0o640);
+        assert_eq!(contents, "new old\n");
+        assert!(!d.dirty);
+        // No temporary file is left behind
+        assert_eq!(entries, 1);
+    }
+
+    #[test]
+    fn failed_save_leaves_the_file_alone() {
+        let dir = temp_dir("failed-save");
+        let path = dir.join("file.txt");
+        fs::write(&path, b"caf\xe9\n").unwrap();
+
+        let mut d = Document::open(path.to_str().unwrap()).unwrap();
+        assert_eq!(d.encoding, Encoding::Latin1);
+        // Not something Latin-1 can hold
+        d.insert_str(&pos(0, 0), "日本");
+        let err = d.save().unwrap_err();
+        let contents = fs::read(&path).unwrap();
+        let entries = fs::read_dir(&dir).unwrap().count();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(contents, b"caf\xe9\n");
+        assert_eq!(entries, 1);
+        assert!(d.dirty);
+    }
+
+    /// A document in `language`, highlighted line by line with the row cache.
+    fn code(language: &str, text: &str) -> Document {
+        Document {
+            syntax: Syntax::find(language).unwrap(),
+            ..doc(text)
+        }
+    }
+
+    /// Builds every row, as drawing the whole document would.
+    fn show(d: &Document) {
+        (0..d.len()).for_each(|y| drop(d.row(y)));
+    }
+
+    fn states(d: &Document) -> Vec<LineState> {
+        let mut cache = d.rows.borrow_mut();
+        (0..d.len()).map(|y| d.line_state(&mut cache, y)).collect()
+    }
+
+    /// Checks the states and rows `d` has after its edits against those of
+    /// its text highlighted from scratch.
+    fn assert_fresh(d: &Document) {
+        let fresh = Document {
+            syntax: Arc::clone(&d.syntax),
+            ..doc(&d.content.to_string())
+        };
+        assert_eq!(states(d), states(&fresh));
+        for y in 0..d.len() {
+            assert_eq!(
+                d.row(y).unwrap().highlighting,
+                fresh.row(y).unwrap().highlighting,
+                "line {}",
+                y
+            );
+        }
+    }
+
+    /// Removes the `len` chars at `x, y`.
+    fn remove(d: &mut Document, x: usize, y: usize, len: usize) {
+        let start = d.position_to_char_idx(&pos(x, y));
+        d.delete_chars(start, start + len, pos(x, y));
+    }
+
+    #[test]
+    fn block_comment_opened_and_closed_above_lines() {
+        use LineState::{Comment, Normal};
+        let mut d = code("C", "int a;\nint b;\nint c;\n");
+        show(&d);
+        d.insert_str(&pos(0, 0), "/* ");
+        assert_eq!(states(&d), [Normal, Comment(0), Comment(0), Comment(0)]);
+        assert_fresh(&d);
+        d.insert_str(&pos(6, 1), " */");
+        assert_eq!(states(&d), [Normal, Comment(0), Normal, Normal]);
+        assert_fresh(&d);
+        // Without the `*/` the comment goes on to the end again
+        remove(&mut d, 7, 1, 2);
+        assert_eq!(states(&d), [Normal, Comment(0), Comment(0), Comment(0)]);
+        assert_fresh(&d);
+        remove(&mut d, 0, 0, 3);
+        assert_eq!(states(&d), [Normal; 4]);
+        assert_fresh(&d);
+    }
+
+    #[test]
+    fn newline_inside_block_comment() {
+        use LineState::{Comment, Normal};
+        let mut d = code("C", "/* one two */\nint x;");
+        show(&d);
+        d.insert_newline(&pos(7, 0));
+        assert_eq!(states(&d), [Normal, Comment(0), Normal]);
+        assert_fresh(&d);
+        remove(&mut d, 7, 0, 1);
+        assert_eq!(states(&d), [Normal, Normal]);
+        assert_fresh(&d);
+    }
+
+    #[test]
+    fn python_triple_quotes() {
+        let mut d = code("Python", "x = 1\ny = 2\nz = 3\nw = 4");
+        show(&d);
+        d.insert_str(&pos(5, 0), " \"\"\"");
+        let states_now = states(&d);
+        assert!(matches!(states_now[1], LineState::String(_)));
+        assert_eq!(states_now[1..], [states_now[1]; 3]);
+        assert_fresh(&d);
+        d.insert_str(&pos(5, 1), "\"\"\"");
+        let states_now = states(&d);
+        assert!(matches!(states_now[1], LineState::String(_)));
+        assert_eq!(states_now[2..], [LineState::Normal; 2]);
+        assert_fresh(&d);
+    }
+
+    #[test]
+    fn rust_raw_strings() {
+        use LineState::{Comment, Normal};
+        let mut d = code(
+            "Rust",
+            "let s = 0;\nsay \"hi\" /* no comment\n\"#;\nlet b = 2;",
+        );
+        show(&d);
+        assert_eq!(states(&d), [Normal, Normal, Comment(0), Comment(0)]);
+        // Quotes and `/*` inside a raw string neither end it nor open a comment
+        d.insert_str(&pos(8, 0), "r#\"");
+        let states_now = states(&d);
+        assert!(matches!(states_now[1], LineState::String(_)));
+        assert_eq!(states_now, [Normal, states_now[1], states_now[1], Normal]);
+        assert_fresh(&d);
+    }
+
+    #[test]
+    fn escapes_and_char_literals() {
+        use LineState::{Comment, Normal};
+        let mut d = code("C", "s = \"a\\\" /*\";\nc = '\"'; /*\nx */\nint y;");
+        show(&d);
+        assert_eq!(states(&d), [Normal, Normal, Comment(0), Normal]);
+        // Without the backslash the string ends before the `/*`
+        remove(&mut d, 6, 0, 1);
+        assert_eq!(states(&d), [Normal, Comment(0), Comment(0), Normal]);
+        assert_fresh(&d);
+
+        // A backslash at the end of a line carries a string over to the next
+        let mut d = code("C", "s = \"abc\nd\";\nint y;");
+        show(&d);
+        d.insert(&pos(8, 0), '\\');
+        let states_now = states(&d);
+        assert!(matches!(states_now[1], LineState::String(_)));
+        assert_eq!(states_now[2], Normal);
+        assert_fresh(&d);
+    }
+
+    #[test]
+    fn edits_rehighlight_lines_until_their_state_is_unchanged() {
+        let text: String = (0..100).map(|i| format!("int v{};\n", i)).collect();
+        let mut d = code("C", &text);
+        show(&d);
+        d.insert(&pos(0, 5), 'x');
+        {
+            let cache = d.rows.borrow();
+            assert!(!cache.rows.contains_key(&5));
+            assert_eq!(cache.rows.len(), d.len() - 1);
+        }
+        // Line 6 starts in the same state as before, so the ones after it
+        // are taken as they are
+        drop(d.row(6));
+        {
+            let cache = d.rows.borrow();
+            assert_eq!(cache.valid, cache.states.len());
+        }
+
+        // A comment opened on line 50 reaches every line below it, whose
+        // rows are dropped; those above are kept
+        d.insert_str(&pos(0, 50), "/*");
+        states(&d);
+        {
+            let cache = d.rows.borrow();
+            assert!((51..d.len()).all(|y| !cache.rows.contains_key(&y)));
+            assert!(
+                (0..50)
+                    .filter(|&y| y != 5)
+                    .all(|y| cache.rows.contains_key(&y))
+            );
+        }
+        assert_fresh(&d);
+
+        // Both edits are caught up with, though the state after the first
+        // is unchanged
+        d.insert_str(&pos(0, 80), "*/");
+        d.insert(&pos(0, 10), 'x');
+        assert_eq!(states(&d)[81], LineState::Normal);
+        assert_fresh(&d);
+    }
+
+    #[test]
+    fn row_cache_follows_inserted_and_removed_lines() {
+        let mut cache = RowCache {
+            states: vec![LineState::Normal; 10],
+            valid: 10,
+            ..RowCache::default()
+        };
+        for y in 0..10 {
+            cache.insert(y, Row::from(y.to_string().as_str()), LineState::Normal);
+        }
+        // The line each cached row is at, and its text
+        let lines = |cache: &RowCache| {
+            let mut lines: Vec<(usize, String)> = cache
+                .rows
+                .iter()
+                .map(|(y, (row, _))| (*y, row.content.clone()))
+                .collect();
+            lines.sort();
+            lines
+                .into_iter()
+                .map(|(y, text)| format!("{}:{}", y, text))
+                .collect::<Vec<_>>()
+        };
+
+        // Two line breaks typed on line 3
+        cache.edit(3, 0, 2);
+        assert_eq!(cache.states.len(), 12);
+        assert_eq!((cache.valid, cache.edited), (4, 5));
+        assert_eq!(
+            lines(&cache),
+            [
+                "0:0", "1:1", "2:2", "6:4", "7:5", "8:6", "9:7", "10:8", "11:9"
+            ]
+        );
+
+        // Lines 7 and 8 joined onto line 6
+        cache.edit(6, 2, 0);
+        assert_eq!(cache.states.len(), 10);
+        assert_eq!((cache.valid, cache.edited), (4, 6));
+        assert_eq!(lines(&cache), ["0:0", "1:1", "2:2", "7:7", "8:8", "9:9"]);
+    }
 }
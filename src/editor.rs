@@ -1,29 +1,37 @@
+use crate::buffer::Buffer;
 use crate::config::Config;
-use crate::document::{Document, SearchDirection};
+use crate::document::{DiskChange, Document, SearchDirection};
+use crate::encoding::LineEnding;
+use crate::motion::{self, Motion};
+use crate::normal::{self, Action, Command, Operator, Parsed};
+use crate::register::{Register, RegisterKind, Registers};
+use crate::row;
+use crate::search::{self, Substitution};
+use crate::selection::{self, Selection};
+use crate::syntax::{self, Syntax};
 use crate::terminal::Terminal;
-use crossterm::event::{KeyCode, KeyEvent, MouseButton, MouseEvent, MouseEventKind};
+use crate::textobject::TextObject;
+use crate::theme::Palette;
+use crate::window::{SplitDirection, Window, WindowLayout};
+use anyhow::Context;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers, MouseButton, MouseEvent, MouseEventKind};
 use ratatui::{
     layout::{Constraint, Direction, Layout, Rect},
     style::{Color, Style},
     text::{Line, Span},
-    widgets::{Block, Borders, List, ListItem, Paragraph},
+    widgets::{Block, Borders, Clear, List, ListItem, Paragraph},
 };
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::io;
-use std::time::{Duration, Instant};
-
-fn parse_hex_color(hex: &str) -> Color {
-    if hex.len() != 7 || !hex.starts_with('#') {
-        return Color::Reset;
-    }
-
-    let r = u8::from_str_radix(&hex[1..3], 16).unwrap_or(0);
-    let g = u8::from_str_radix(&hex[3..5], 16).unwrap_or(0);
-    let b = u8::from_str_radix(&hex[5..7], 16).unwrap_or(0);
-
-    Color::Rgb(r, g, b)
-}
-
-#[derive(Default, Clone, Copy, PartialEq)]
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use unicode_width::UnicodeWidthStr;
+
+#[derive(Default, Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
 pub struct Position {
     pub x: usize,
     pub y: usize,
@@ -34,8 +42,76 @@ pub enum Mode {
     Normal,
     Insert,
     Command,
+    /// `v`
     Visual,
+    /// `V`
+    VisualLine,
+    /// `Ctrl-v`
+    VisualBlock,
     Search,
+    /// Waiting for the answer to a `Prompt`.
+    Prompt,
+}
+
+/// A question that must be answered before editing can go on.
+#[derive(Clone, Copy, PartialEq)]
+enum Prompt {
+    /// Buffer `buffer` has a swap file left by a session that died.
+    Recover { buffer: usize },
+    /// Another program changed the file of buffer `buffer`, which has
+    /// unsaved changes.
+    Changed { buffer: usize },
+}
+
+impl Prompt {
+    fn question(&self) -> &'static str {
+        match self {
+            Prompt::Recover { .. } => "[R]ecover, [D]elete swap file, [O]pen read-only? ",
+            Prompt::Changed { .. } => "[D]iff, [R]eload, [K]eep buffer? ",
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self {
+            Prompt::Recover { .. } => " Swap file found ",
+            Prompt::Changed { .. } => " File changed on disk ",
+        }
+    }
+}
+
+/// How often swap files of buffers with unsaved changes are refreshed.
+const SWAP_INTERVAL: Duration = Duration::from_secs(2);
+/// How often open files are checked for changes by other programs, besides
+/// whenever the terminal regains focus.
+const DISK_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A change that `.` can repeat: the command that started it plus the keys
+/// typed in Insert mode before returning to Normal mode.
+#[derive(Clone)]
+struct Change {
+    command: Command,
+    insert_keys: Vec<KeyEvent>,
+}
+
+/// `I`, `A` or `c` in Visual block mode: the text typed on the first line of
+/// the block is copied to the others when Insert mode ends.
+struct BlockInsert {
+    start: Position,
+    /// The remaining lines of the block.
+    lines: RangeInclusive<usize>,
+    /// Display column the text goes in at.
+    column: usize,
+    /// `A` pads lines that end before `column`; otherwise they are skipped.
+    pad: bool,
+}
+
+/// Screen areas of one window, as computed by `Editor::panes`.
+struct Pane {
+    window: usize,
+    area: Rect,
+    text: Rect,
+    bar: Option<Rect>,
+    separator: Option<Rect>,
 }
 
 pub struct Editor {
@@ -43,240 +119,408 @@ pub struct Editor {
     terminal: Terminal,
     cursor_position: Position,
     offset: Position,
-    document: Document,
+    buffers: Vec<Buffer>,
+    current_buffer: usize,
+    windows: Vec<Window>,
+    current_window: usize,
+    window_layout: WindowLayout,
+    // Set after Ctrl-w, waiting for the window command key
+    pending_window_key: bool,
+    // Normal-mode keys of a command still being typed, e.g. `3d`
+    pending_keys: String,
+    // The change being recorded for `.`, and the last finished one
+    recording: Option<Change>,
+    last_change: Option<Change>,
+    block_insert: Option<BlockInsert>,
     status_message: String,
     status_time: Instant,
     mode: Mode,
     selection_start: Option<Position>,
     mouse_drag_start: Option<Position>,
     last_search_query: Option<String>,
-    clipboard: Option<arboard::Clipboard>,
+    // `/` searches forward and `?` backward; `n` repeats in the same direction
+    search_direction: SearchDirection,
+    // Multi-line output of commands like `:ls`, shown until the next key press
+    info_lines: Vec<String>,
+    prompt: Option<Prompt>,
+    registers: Registers,
+    // Register named with `"x` for the command being run
+    register: Option<char>,
     #[allow(dead_code)]
     config: Config,
+    // `config.theme`, ready to draw with
+    palette: Palette,
     command_buffer: String,
     // Auto-completion
     completion_active: bool,
     completion_index: usize,
     completion_list: Vec<String>,
+    last_swap: Instant,
+    last_disk_check: Instant,
+    // The last swap file error shown, so a persistent one isn't repeated
+    swap_error: Option<String>,
+    // Set by SIGTERM or SIGHUP
+    terminate: Arc<AtomicBool>,
 }
 
 impl Editor {
-    pub fn new(config: Config) -> Self {
+    pub fn new(config: Config) -> anyhow::Result<Self> {
         let args: Vec<String> = std::env::args().collect();
-        let mut document = Document::default();
-        if let Some(filename) = args.get(1) {
-            if let Ok(doc) = Document::open(filename) {
-                document = doc;
-            } else {
-                // If file doesn't exist, we'll create it on save
-                document.file_name = Some(filename.clone());
+        let mut buffers = Vec::new();
+        let mut status_message = match syntax::load_languages().as_slice() {
+            [] => String::new(),
+            [error] => format!("Error in language file {}", error),
+            [error, rest @ ..] => {
+                format!("Error in language file {} (and {} more)", error, rest.len())
             }
+        };
+        for filename in args.iter().skip(1) {
+            match Buffer::open(filename, config.editor.large_file_size()) {
+                Ok(mut buffer) => {
+                    if let Some(e) = buffer.document.undo_error.take() {
+                        status_message = e;
+                    }
+                    buffers.push(buffer);
+                }
+                Err(e) => status_message = format!("Error opening {}: {}", filename, e),
+            }
+        }
+        if buffers.is_empty() {
+            buffers.push(Buffer::default());
         }
 
-        let clipboard = arboard::Clipboard::new().ok();
+        // Leave the loop on SIGTERM/SIGHUP so unsaved changes get into the
+        // swap files and the terminal is restored.
+        let terminate = Arc::new(AtomicBool::new(false));
+        let _ = signal_hook::flag::register(signal_hook::consts::SIGTERM, Arc::clone(&terminate));
+        #[cfg(unix)]
+        let _ = signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&terminate));
+
+        let terminal = Terminal::new().context("Failed to initialize terminal")?;
+        Ok(Self {
+            status_message,
+            terminate,
+            ..Self::with_buffers(config, terminal, Registers::new(), buffers)
+        })
+    }
 
+    /// An editor showing `buffers` on `terminal`, before any status message.
+    fn with_buffers(
+        config: Config,
+        terminal: Terminal,
+        registers: Registers,
+        buffers: Vec<Buffer>,
+    ) -> Self {
         Self {
             should_quit: false,
-            terminal: Terminal::new().expect("Failed to initialize terminal"),
+            terminal,
             cursor_position: Position::default(),
             offset: Position::default(),
-            document,
+            buffers,
+            current_buffer: 0,
+            windows: vec![Window::default()],
+            current_window: 0,
+            window_layout: WindowLayout::Leaf(0),
+            pending_window_key: false,
+            pending_keys: String::new(),
+            recording: None,
+            last_change: None,
+            block_insert: None,
             status_message: String::new(),
             status_time: Instant::now(),
             mode: Mode::Normal,
             selection_start: None,
             mouse_drag_start: None,
             last_search_query: None,
-            clipboard,
+            search_direction: SearchDirection::Forward,
+            info_lines: Vec::new(),
+            prompt: None,
+            registers,
+            register: None,
+            palette: Palette::new(&config.theme),
             config,
             command_buffer: String::new(),
             completion_active: false,
             completion_index: 0,
             completion_list: Vec::new(),
+            last_swap: Instant::now(),
+            last_disk_check: Instant::now(),
+            swap_error: None,
+            terminate: Arc::default(),
+        }
+    }
+
+    /// Runs until the user quits. An error, SIGTERM or SIGHUP writes the swap
+    /// files and leaves them in place.
+    pub fn run(&mut self) -> anyhow::Result<()> {
+        self.prompt_recovery();
+        let result = self.event_loop();
+        if result.is_err() || !self.should_quit {
+            // Keep the swap files: they are all that's left of the changes.
+            // The terminal may be gone already (after SIGHUP, drawing fails
+            // for that reason), so errors are ignored.
+            self.write_swap_files(true);
+            let _ = self.terminal.stop();
+            return result;
         }
+        for buffer in &mut self.buffers {
+            buffer.document.remove_swap();
+        }
+        self.terminal.stop()?;
+        Ok(())
     }
 
-    pub fn run(&mut self) {
+    /// Draws the screen and handles keys until the user quits or SIGTERM or
+    /// SIGHUP arrives.
+    fn event_loop(&mut self) -> anyhow::Result<()> {
         loop {
-            if let Err(e) = self.refresh_screen() {
-                die(e);
+            if self.terminate.load(Ordering::Relaxed) {
+                return Ok(());
             }
+            self.refresh_screen()?;
             if self.should_quit {
-                break;
+                return Ok(());
             }
-            if let Err(e) = self.process_keypress() {
-                die(e);
+            self.process_keypress()?;
+            self.write_swap_files(false);
+        }
+    }
+
+    /// Writes swap files for buffers with unsaved changes, at most every
+    /// `SWAP_INTERVAL` unless `now` is set.
+    fn write_swap_files(&mut self, now: bool) {
+        if !now && self.last_swap.elapsed() < SWAP_INTERVAL {
+            return;
+        }
+        self.last_swap = Instant::now();
+        let mut error = None;
+        for buffer in &mut self.buffers {
+            if buffer.document.dirty
+                && let Err(e) = buffer.document.write_swap()
+            {
+                error = Some(format!("Error writing swap file: {}", e));
             }
         }
-        self.terminal.stop().unwrap();
+        if error.is_some() && error != self.swap_error {
+            self.set_status_message(error.clone().unwrap_or_default());
+        }
+        self.swap_error = error;
     }
 
-    fn refresh_screen(&mut self) -> Result<(), io::Error> {
-        let mode = self.mode;
-        let command_buf = self.command_buffer.clone();
-        let status_msg = self.status_message.clone();
-        let doc_len = self.document.len();
-        let filename = self
-            .document
-            .file_name
-            .clone()
-            .unwrap_or("[No Name]".to_string());
-
-        // Calculate viewport
-        let terminal_size = self.terminal.backend.size()?;
-        let height = terminal_size.height as usize;
-        let width = terminal_size.width as usize;
-
-        let offset_x = self.offset.x;
-        let offset_y = self.offset.y;
-
-        let cursor_x = self.cursor_position.x.saturating_sub(offset_x);
-        let cursor_y = self.cursor_position.y.saturating_sub(offset_y);
-
-        // Calculate gutter width for line numbers
-        let gutter_width = if self.config.editor.line_numbers {
-            // Width of line number + 1 space padding
-            let digits = doc_len.to_string().len();
-            digits + 2 // " 1 " style padding
-        } else {
-            0
+    /// Asks what to do about the first buffer with a swap file left by a
+    /// session that died, if there is one.
+    fn prompt_recovery(&mut self) {
+        let Some(index) = self
+            .buffers
+            .iter()
+            .position(|b| b.document.recovery.is_some())
+        else {
+            return;
         };
+        self.switch_buffer(index);
+        let buffer = &self.buffers[index];
+        let Some(swap) = &buffer.document.recovery else {
+            return;
+        };
+        self.info_lines = vec![
+            format!("Found a swap file for \"{}\"", buffer.display_name()),
+            format!(
+                "written by process {} {}.",
+                swap.pid,
+                describe_age(swap.modified)
+            ),
+            String::new(),
+            "It holds changes that were never saved, most likely because that".to_string(),
+            "editor was killed or its terminal closed. If it is still running".to_string(),
+            "and editing the file, open it read-only.".to_string(),
+        ];
+        self.prompt = Some(Prompt::Recover { buffer: index });
+        self.mode = Mode::Prompt;
+    }
 
-        let text_width = width.saturating_sub(gutter_width);
-
-        // Prepare text to render
-        let mut lines = Vec::new();
-        for y in 0..height.saturating_sub(2) {
-            // Reserve space for status bar
-            let file_row = y + offset_y;
-            if file_row < doc_len {
-                if let Some(row) = self.document.row(file_row) {
-                    let mut spans = Vec::new();
-
-                    if self.config.editor.line_numbers {
-                        let line_num = file_row + 1;
-                        let digits = doc_len.to_string().len();
-                        // Right align line number
-                        let default_style = Style::default()
-                            .fg(Color::DarkGray)
-                            .bg(parse_hex_color(&self.config.theme.background));
-                        // Use background color for gutter to match rendering or distinct?
-                        // Usually gutter has same bg or slightly different. Let's use theme background for now.
-                        let gutter_str = format!("{:>width$} ", line_num, width = digits + 1);
-                        spans.push(Span::styled(gutter_str, default_style));
-                    }
-
-                    let mut current_x = 0;
-                    let mut current_style = Style::default();
-                    let mut current_span_content = String::new();
-
-                    for (i, c) in row.content.chars().enumerate() {
-                        if current_x < offset_x {
-                            current_x += 1;
-                            continue;
-                        }
-                        if current_x >= offset_x + text_width {
-                            break;
-                        }
+    fn process_prompt(&mut self, key: KeyEvent) {
+        let msg = match self.prompt {
+            Some(Prompt::Recover { buffer }) => self.answer_recover(buffer, key),
+            Some(Prompt::Changed { buffer }) => self.answer_changed(buffer, key),
+            None => Some(String::new()),
+        };
+        let Some(msg) = msg else {
+            return;
+        };
+        self.prompt = None;
+        self.info_lines.clear();
+        self.mode = Mode::Normal;
+        let pos = self.cursor_position;
+        self.move_cursor_absolute(pos.x, pos.y);
+        self.set_status_message(msg);
+        self.prompt_recovery();
+    }
 
-                        let highlight = row
-                            .highlighting
-                            .get(i)
-                            .unwrap_or(&crate::row::HighlightType::None);
-                        let mut style =
-                            match highlight {
-                                crate::row::HighlightType::Number => {
-                                    Style::default().fg(parse_hex_color(&self.config.theme.number))
-                                }
-                                crate::row::HighlightType::String => {
-                                    Style::default().fg(parse_hex_color(&self.config.theme.string))
-                                }
-                                crate::row::HighlightType::Comment => {
-                                    Style::default().fg(parse_hex_color(&self.config.theme.comment))
-                                }
-                                crate::row::HighlightType::Keyword => {
-                                    Style::default().fg(parse_hex_color(&self.config.theme.keyword))
-                                }
-                                crate::row::HighlightType::Type => Style::default()
-                                    .fg(parse_hex_color(&self.config.theme.type_color)),
-                                crate::row::HighlightType::ControlFlow => Style::default()
-                                    .fg(parse_hex_color(&self.config.theme.control_flow)),
-                                _ => Style::default()
-                                    .fg(parse_hex_color(&self.config.theme.foreground)),
-                            };
-
-                        if self.mode == Mode::Visual {
-                            if let Some(start_pos) = self.selection_start {
-                                let (start, end) = if start_pos.y < self.cursor_position.y
-                                    || (start_pos.y == self.cursor_position.y
-                                        && start_pos.x <= self.cursor_position.x)
-                                {
-                                    (start_pos, self.cursor_position)
-                                } else {
-                                    (self.cursor_position, start_pos)
-                                };
-
-                                let is_selected = if file_row > start.y && file_row < end.y {
-                                    true
-                                } else if file_row == start.y && file_row == end.y {
-                                    current_x >= start.x && current_x <= end.x
-                                } else if file_row == start.y {
-                                    current_x >= start.x
-                                } else if file_row == end.y {
-                                    current_x <= end.x
-                                } else {
-                                    false
-                                };
-
-                                if is_selected {
-                                    style =
-                                        style.bg(parse_hex_color(&self.config.theme.selection_bg));
-                                }
-                            }
-                        }
+    /// Handles a key of the recovery prompt; `None` keeps asking.
+    fn answer_recover(&mut self, buffer: usize, key: KeyEvent) -> Option<String> {
+        let document = &mut self.buffers[buffer].document;
+        let msg = match key.code {
+            KeyCode::Char('r' | 'R') => {
+                document.recover();
+                "Recovered unsaved changes; u restores the file on disk"
+            }
+            KeyCode::Char('d' | 'D') => {
+                document.discard_recovery();
+                "Swap file deleted"
+            }
+            KeyCode::Char('o' | 'O') | KeyCode::Esc => {
+                document.read_only = true;
+                document.recovery = None;
+                "Opened read-only"
+            }
+            _ => return None,
+        };
+        Some(msg.to_string())
+    }
 
-                        if i == 0 || style != current_style {
-                            if !current_span_content.is_empty() {
-                                spans.push(Span::styled(
-                                    current_span_content.clone(),
-                                    current_style,
-                                ));
-                            }
-                            current_style = style;
-                            current_span_content = c.to_string();
-                        } else {
-                            current_span_content.push(c);
-                        }
+    /// Handles a key of the changed-file prompt; `None` keeps asking.
+    fn answer_changed(&mut self, buffer: usize, key: KeyEvent) -> Option<String> {
+        let name = self.buffers[buffer].display_name();
+        let document = &mut self.buffers[buffer].document;
+        match key.code {
+            KeyCode::Char('d' | 'D') => {
+                self.info_lines = self.disk_diff(buffer);
+                None
+            }
+            KeyCode::Char('r' | 'R') => Some(match document.reload_changed() {
+                Ok(()) => format!("Reloaded {}; u brings back your changes", name),
+                Err(e) => format!("Error reloading {}: {}", name, e),
+            }),
+            KeyCode::Char('k' | 'K') | KeyCode::Esc => {
+                document.ignore_disk_change();
+                Some("Kept the buffer; :w! overwrites the file".to_string())
+            }
+            _ => None,
+        }
+    }
 
-                        current_x += 1;
-                    }
+    /// Unified diff from the file on disk to the buffer, cut to fit the
+    /// screen.
+    fn disk_diff(&self, buffer: usize) -> Vec<String> {
+        let document = &self.buffers[buffer].document;
+        let disk = match document.read_disk_text() {
+            Ok(text) => text,
+            Err(e) => return vec![format!("Error reading the file: {}", e)],
+        };
+        let text = document.content.to_string();
+        let diff = similar::TextDiff::from_lines(&disk, &text);
+        let mut lines: Vec<String> = diff
+            .unified_diff()
+            .context_radius(2)
+            .header("on disk", "buffer")
+            .to_string()
+            .lines()
+            .map(|line| line.replace('\t', "    "))
+            .collect();
+        let max = self
+            .text_region()
+            .map_or(20, |region| region.height as usize)
+            .saturating_sub(2)
+            .max(2);
+        if lines.len() > max {
+            let more = lines.len() - (max - 1);
+            lines.truncate(max - 1);
+            lines.push(format!("... {} more lines", more));
+        }
+        lines
+    }
 
-                    if !current_span_content.is_empty() {
-                        spans.push(Span::styled(current_span_content, current_style));
+    /// Looks for files changed by other programs, at most every
+    /// `DISK_CHECK_INTERVAL` unless `now` is set. Buffers without unsaved
+    /// changes are reloaded; for others the user is asked what to do.
+    fn check_disk_changes(&mut self, now: bool) {
+        if self.mode != Mode::Normal
+            || !self.pending_keys.is_empty()
+            || (!now && self.last_disk_check.elapsed() < DISK_CHECK_INTERVAL)
+        {
+            return;
+        }
+        self.last_disk_check = Instant::now();
+        for index in 0..self.buffers.len() {
+            let name = self.buffers[index].display_name();
+            let document = &mut self.buffers[index].document;
+            match document.check_disk() {
+                DiskChange::Unchanged => {}
+                DiskChange::Deleted => {
+                    self.set_status_message(format!("{} was deleted on disk", name));
+                }
+                DiskChange::Modified if !document.dirty => {
+                    let msg = match document.reload_changed() {
+                        Ok(()) => format!("{} changed on disk; reloaded", name),
+                        Err(e) => format!("Error reloading {}: {}", name, e),
+                    };
+                    if index == self.current_buffer {
+                        let pos = self.cursor_position;
+                        self.move_cursor_absolute(pos.x, pos.y);
                     }
-
-                    lines.push(Line::from(spans));
+                    self.set_status_message(msg);
                 }
-            } else if file_row == doc_len && doc_len == 0 {
-                // Empty buffer
-                let mut empty_line_spans = Vec::new();
-                if self.config.editor.line_numbers {
-                    let digits = 1; // 0 len -> 1 digit
-                    let gutter_str = format!("{:>width$} ", 1, width = digits + 1);
-                    empty_line_spans.push(Span::styled(
-                        gutter_str,
-                        Style::default().fg(Color::DarkGray),
-                    ));
+                DiskChange::Modified => {
+                    self.switch_buffer(index);
+                    self.info_lines = vec![
+                        format!("{} was changed by another program, and the", name),
+                        "buffer has unsaved changes.".to_string(),
+                        String::new(),
+                        "Reload to take the new contents (u brings back your changes),".to_string(),
+                        "or keep the buffer and overwrite the file with :w!.".to_string(),
+                    ];
+                    self.prompt = Some(Prompt::Changed { buffer: index });
+                    self.mode = Mode::Prompt;
+                    return;
                 }
-                empty_line_spans.push(Span::styled(
-                    "~ empty buffer ~",
-                    Style::default().fg(Color::DarkGray),
-                ));
-                lines.push(Line::from(empty_line_spans));
-            } else {
-                lines.push(Line::styled("~", Style::default().fg(Color::DarkGray)));
             }
         }
+    }
+
+    fn refresh_screen(&mut self) -> Result<(), io::Error> {
+        self.sync_window();
+        for window in &self.windows {
+            self.buffers[window.buffer].document.update_syntax_tree();
+        }
+        let mode = self.mode;
+        let command_buf = self.command_buffer.clone();
+        let status_msg = self.status_message.clone();
+        let doc_len = self.document().len();
+        let filename = self.buffers[self.current_buffer].display_name();
+        let file_type = if self.document().is_large() {
+            format!("{} (large file)", self.document().syntax.file_type)
+        } else {
+            self.document().syntax.file_type.to_string()
+        };
+        let size_bytes = self.document().size_bytes();
+        let buffer_info = format!("[{}/{}]", self.current_buffer + 1, self.buffers.len());
+        let modified = match (self.document().dirty, self.document().read_only) {
+            (true, true) => " [+] [RO]",
+            (true, false) => " [+]",
+            (false, true) => " [RO]",
+            (false, false) => "",
+        };
+        let file_format = format!(
+            "{}{} | {}",
+            self.document().encoding,
+            if self.document().bom { " BOM" } else { "" },
+            self.document().line_ending
+        );
+
+        let panes = self.panes(self.text_region()?);
+        let rendered: Vec<Vec<Line>> = panes
+            .iter()
+            .map(|pane| self.render_window(pane.window, pane.text))
+            .collect();
+
+        let focused = panes
+            .iter()
+            .find(|pane| pane.window == self.current_window)
+            .map(|pane| pane.text)
+            .unwrap_or_default();
+        let gutter_width = self.gutter_width(self.document());
+        let cursor_x = self.cursor_column().saturating_sub(self.offset.x);
+        let cursor_y = self.cursor_position.y.saturating_sub(self.offset.y);
 
         self.terminal.backend.draw(|f| {
             let chunks = Layout::default()
@@ -291,9 +535,35 @@ impl Editor {
                 )
                 .split(f.area());
 
-            let text_area = Paragraph::new(lines)
-                .style(Style::default().bg(parse_hex_color(&self.config.theme.background)));
-            f.render_widget(text_area, chunks[0]);
+            for (pane, lines) in panes.iter().zip(rendered) {
+                let text_area =
+                    Paragraph::new(lines).style(Style::default().bg(self.palette.background));
+                f.render_widget(text_area, pane.text);
+
+                if let Some(separator) = pane.separator {
+                    let bar = vec![Line::from("│"); separator.height as usize];
+                    f.render_widget(
+                        Paragraph::new(bar).style(Style::default().fg(Color::DarkGray)),
+                        separator,
+                    );
+                }
+                if let Some(bar) = pane.bar {
+                    let window = &self.windows[pane.window];
+                    let style = if pane.window == self.current_window {
+                        Style::default()
+                            .bg(self.palette.status_bg)
+                            .fg(self.palette.status_fg)
+                    } else {
+                        Style::default()
+                            .bg(self.palette.selection_bg)
+                            .fg(self.palette.foreground)
+                    };
+                    let buffer = &self.buffers[window.buffer];
+                    let modified = if buffer.document.dirty { " [+]" } else { "" };
+                    let name = format!(" {}{}", buffer.display_name(), modified);
+                    f.render_widget(Paragraph::new(name).style(style), bar);
+                }
+            }
 
             // Status Bar
             let mode_str = match mode {
@@ -301,27 +571,37 @@ impl Editor {
                 Mode::Insert => "INSERT",
                 Mode::Command => "COMMAND",
                 Mode::Visual => "VISUAL",
+                Mode::VisualLine => "VISUAL LINE",
+                Mode::VisualBlock => "VISUAL BLOCK",
                 Mode::Search => "SEARCH",
+                Mode::Prompt => "PROMPT",
             };
             let status_text = format!(
-                " {} | {} | {} | Lines: {} | Bytes: {}",
+                " {} | {} {}{} | {} | {} | Lines: {} | Bytes: {}",
                 mode_str,
+                buffer_info,
                 filename,
-                self.document.syntax.file_type,
+                modified,
+                file_type,
+                file_format,
                 doc_len,
-                self.document.size_bytes()
+                size_bytes
             );
             let status_bar = Paragraph::new(status_text).style(
                 Style::default()
-                    .bg(parse_hex_color(&self.config.theme.status_bg))
-                    .fg(parse_hex_color(&self.config.theme.status_fg)),
+                    .bg(self.palette.status_bg)
+                    .fg(self.palette.status_fg),
             );
             f.render_widget(status_bar, chunks[1]);
 
             // Command/Message Line
             let cmd_text = match mode {
                 Mode::Command => format!(":{}", command_buf),
-                Mode::Search => format!("/{}", command_buf),
+                Mode::Search => match self.search_direction {
+                    SearchDirection::Forward => format!("/{}", command_buf),
+                    SearchDirection::Backward => format!("?{}", command_buf),
+                },
+                Mode::Prompt => self.prompt.map_or("", |p| p.question()).to_string(),
                 _ => {
                     if status_msg.is_empty()
                         || Instant::now().duration_since(self.status_time) > Duration::from_secs(5)
@@ -335,9 +615,38 @@ impl Editor {
 
             f.render_widget(Paragraph::new(cmd_text), chunks[2]);
 
+            if !self.info_lines.is_empty() {
+                let height = (self.info_lines.len() as u16 + 2).min(chunks[0].height);
+                let area = Rect::new(
+                    chunks[0].x,
+                    chunks[0].y + chunks[0].height - height,
+                    chunks[0].width,
+                    height,
+                );
+                let items: Vec<ListItem> = self
+                    .info_lines
+                    .iter()
+                    .map(|line| ListItem::new(line.as_str()))
+                    .collect();
+                let list = List::new(items)
+                    .block(
+                        Block::default().borders(Borders::ALL).title(
+                            self.prompt
+                                .map_or(" Press any key to continue ", |p| p.title()),
+                        ),
+                    )
+                    .style(
+                        Style::default()
+                            .fg(self.palette.foreground)
+                            .bg(self.palette.background),
+                    );
+                f.render_widget(Clear, area);
+                f.render_widget(list, area);
+            }
+
             if self.completion_active && !self.completion_list.is_empty() {
-                let x_pos = chunks[0].x + gutter_width as u16 + cursor_x as u16;
-                let y_pos = chunks[0].y + cursor_y as u16 + 1;
+                let x_pos = focused.x + gutter_width as u16 + cursor_x as u16;
+                let y_pos = focused.y + cursor_y as u16 + 1;
 
                 let max_width = self
                     .completion_list
@@ -358,12 +667,12 @@ impl Editor {
                     .map(|(i, text)| {
                         let style = if i == self.completion_index {
                             Style::default()
-                                .bg(parse_hex_color(&self.config.theme.selection_bg))
-                                .fg(parse_hex_color(&self.config.theme.foreground))
+                                .bg(self.palette.selection_bg)
+                                .fg(self.palette.foreground)
                         } else {
                             Style::default()
-                                .fg(parse_hex_color(&self.config.theme.foreground))
-                                .bg(parse_hex_color(&self.config.theme.background))
+                                .fg(self.palette.foreground)
+                                .bg(self.palette.background)
                         };
                         ListItem::new(Span::styled(text.clone(), style))
                     })
@@ -371,15 +680,17 @@ impl Editor {
 
                 let list = List::new(items)
                     .block(Block::default().borders(Borders::ALL))
-                    .style(Style::default().bg(parse_hex_color(&self.config.theme.background)));
+                    .style(Style::default().bg(self.palette.background));
 
                 f.render_widget(list, area);
             }
 
-            if mode != Mode::Command && mode != Mode::Search {
+            if let Some(prompt) = self.prompt {
+                f.set_cursor_position((chunks[2].x + prompt.question().len() as u16, chunks[2].y));
+            } else if mode != Mode::Command && mode != Mode::Search {
                 f.set_cursor_position((
-                    chunks[0].x + gutter_width as u16 + cursor_x as u16,
-                    chunks[0].y + cursor_y as u16,
+                    focused.x + gutter_width as u16 + cursor_x as u16,
+                    focused.y + cursor_y as u16,
                 ));
             } else {
                 f.set_cursor_position((chunks[2].x + 1 + command_buf.len() as u16, chunks[2].y));
@@ -388,138 +699,387 @@ impl Editor {
         Ok(())
     }
 
+    /// Builds the visible lines (gutter included) of one window.
+    fn render_window(&self, index: usize, area: Rect) -> Vec<Line<'static>> {
+        let window = &self.windows[index];
+        let document = &self.buffers[window.buffer].document;
+        let doc_len = document.len();
+        let offset_x = window.offset.x;
+        let offset_y = window.offset.y;
+        let gutter_width = self.gutter_width(document);
+        let text_width = (area.width as usize).saturating_sub(gutter_width);
+        let selection = if index == self.current_window {
+            self.selection(document, window.selection_start, window.cursor_position)
+        } else {
+            None
+        };
+
+        let mut lines = Vec::new();
+        for y in 0..area.height as usize {
+            let file_row = y + offset_y;
+            if file_row < doc_len {
+                if let Some(row) = document.row(file_row) {
+                    let mut spans = Vec::new();
+
+                    if self.config.editor.line_numbers {
+                        let line_num = file_row + 1;
+                        let digits = doc_len.to_string().len();
+                        // Right align line number
+                        let default_style = Style::default()
+                            .fg(Color::DarkGray)
+                            .bg(self.palette.background);
+                        // Use background color for gutter to match rendering or distinct?
+                        // Usually gutter has same bg or slightly different. Let's use theme background for now.
+                        let gutter_str = format!("{:>width$} ", line_num, width = digits + 1);
+                        spans.push(Span::styled(gutter_str, default_style));
+                    }
+
+                    let tab_size = self.config.editor.tab_size;
+                    let mut column = 0;
+                    let mut current_style = Style::default();
+                    let mut current_span_content = String::new();
+
+                    for (i, grapheme) in row.graphemes() {
+                        let start = column;
+                        column += row::grapheme_width(grapheme, start, tab_size);
+                        if column <= offset_x {
+                            continue;
+                        }
+                        if start >= offset_x + text_width {
+                            break;
+                        }
+                        // Tabs become spaces, and a wide char cut off by either
+                        // edge of the window is drawn as blanks.
+                        let visible = column.min(offset_x + text_width) - start.max(offset_x);
+                        let cell: Cow<str> = if grapheme == "\t" || visible < column - start {
+                            " ".repeat(visible).into()
+                        } else if grapheme.chars().all(char::is_control) {
+                            "\u{fffd}".into()
+                        } else if grapheme.width() == 0 {
+                            // A combining mark with nothing to combine with
+                            format!(" {}", grapheme).into()
+                        } else {
+                            grapheme.into()
+                        };
+
+                        let highlight = row
+                            .highlighting
+                            .get(i)
+                            .unwrap_or(&crate::row::HighlightType::None);
+                        let mut style = self.palette.highlight(*highlight);
+
+                        if selection.is_some_and(|sel| sel.contains(file_row, i, start..column)) {
+                            style = style.bg(self.palette.selection_bg);
+                        }
+
+                        if current_span_content.is_empty() || style != current_style {
+                            if !current_span_content.is_empty() {
+                                spans.push(Span::styled(
+                                    current_span_content.clone(),
+                                    current_style,
+                                ));
+                            }
+                            current_style = style;
+                            current_span_content = cell.into_owned();
+                        } else {
+                            current_span_content.push_str(&cell);
+                        }
+                    }
+
+                    if !current_span_content.is_empty() {
+                        spans.push(Span::styled(current_span_content, current_style));
+                    }
+
+                    lines.push(Line::from(spans));
+                }
+            } else if file_row == doc_len && doc_len == 0 {
+                // Empty buffer
+                let mut empty_line_spans = Vec::new();
+                if self.config.editor.line_numbers {
+                    let digits = 1; // 0 len -> 1 digit
+                    let gutter_str = format!("{:>width$} ", 1, width = digits + 1);
+                    empty_line_spans.push(Span::styled(
+                        gutter_str,
+                        Style::default().fg(Color::DarkGray),
+                    ));
+                }
+                empty_line_spans.push(Span::styled(
+                    "~ empty buffer ~",
+                    Style::default().fg(Color::DarkGray),
+                ));
+                lines.push(Line::from(empty_line_spans));
+            } else {
+                lines.push(Line::styled("~", Style::default().fg(Color::DarkGray)));
+            }
+        }
+        lines
+    }
+
+    fn gutter_width(&self, document: &Document) -> usize {
+        if self.config.editor.line_numbers {
+            // Width of line number + 1 space padding
+            document.len().to_string().len() + 2 // " 1 " style padding
+        } else {
+            0
+        }
+    }
+
+    /// Area shared by all windows: the whole screen minus status and command lines.
+    fn text_region(&self) -> Result<Rect, io::Error> {
+        let size = self.terminal.backend.size()?;
+        Ok(Rect::new(0, 0, size.width, size.height.saturating_sub(2)))
+    }
+
+    /// Lays out the windows inside `region`. When the screen is split every
+    /// window gets a name bar on its last line and, unless it touches the right
+    /// edge, a separator column.
+    fn panes(&self, region: Rect) -> Vec<Pane> {
+        let mut areas = Vec::new();
+        self.window_layout.areas(region, &mut areas);
+        let split = areas.len() > 1;
+        areas
+            .into_iter()
+            .map(|(window, area)| {
+                let mut text = area;
+                let mut bar = None;
+                let mut separator = None;
+                if split {
+                    if text.right() < region.right() && text.width > 1 {
+                        separator = Some(Rect::new(text.right() - 1, text.y, 1, text.height));
+                        text.width -= 1;
+                    }
+                    if text.height > 1 {
+                        bar = Some(Rect::new(text.x, text.bottom() - 1, text.width, 1));
+                        text.height -= 1;
+                    }
+                }
+                Pane {
+                    window,
+                    area,
+                    text,
+                    bar,
+                    separator,
+                }
+            })
+            .collect()
+    }
+
+    fn focused_pane(&self) -> Option<Pane> {
+        let region = self.text_region().ok()?;
+        self.panes(region)
+            .into_iter()
+            .find(|pane| pane.window == self.current_window)
+    }
+
     fn process_keypress(&mut self) -> Result<(), io::Error> {
         if crossterm::event::poll(Duration::from_millis(100))? {
             let event = crossterm::event::read()?;
             match event {
-                crossterm::event::Event::Key(key) => match self.mode {
-                    Mode::Normal => self.process_normal_mode(key),
-                    Mode::Insert => self.process_insert_mode(key),
-                    Mode::Command => self.process_command_mode(key),
-                    Mode::Visual => self.process_visual_mode(key),
-                    Mode::Search => self.process_search_mode(key),
-                },
+                crossterm::event::Event::Key(key) if self.mode == Mode::Prompt => {
+                    self.process_prompt(key);
+                }
+                crossterm::event::Event::Mouse(_) if self.mode == Mode::Prompt => {}
+                crossterm::event::Event::Key(_) if !self.info_lines.is_empty() => {
+                    self.info_lines.clear();
+                }
+                crossterm::event::Event::Key(key) => self.handle_key(key),
                 crossterm::event::Event::Mouse(mouse_event) => {
                     self.process_mouse(mouse_event);
                 }
+                crossterm::event::Event::FocusGained => self.check_disk_changes(true),
                 _ => {}
             }
         }
+        self.check_disk_changes(false);
         Ok(())
     }
 
+    fn handle_key(&mut self, key: KeyEvent) {
+        if self.mode == Mode::Insert
+            && let Some(change) = &mut self.recording
+        {
+            change.insert_keys.push(key);
+        }
+        match self.mode {
+            Mode::Normal => self.process_normal_mode(key),
+            Mode::Insert => self.process_insert_mode(key),
+            Mode::Command => self.process_command_mode(key),
+            Mode::Visual | Mode::VisualLine | Mode::VisualBlock => self.process_visual_mode(key),
+            Mode::Search => self.process_search_mode(key),
+            Mode::Prompt => self.process_prompt(key),
+        }
+        // A change is complete once the editor is back out of Insert mode
+        if self.mode != Mode::Insert
+            && let Some(change) = self.recording.take()
+        {
+            self.last_change = Some(change);
+        }
+    }
+
     fn process_mouse(&mut self, event: MouseEvent) {
-        let x = event.column as usize;
-        let y = event.row as usize;
-        let terminal_height = self.terminal.backend.size().unwrap().height as usize;
+        let Ok(region) = self.text_region() else {
+            return;
+        };
+        let Some(pane) = self.panes(region).into_iter().find(|pane| {
+            pane.text
+                .contains(ratatui::layout::Position::new(event.column, event.row))
+        }) else {
+            return;
+        };
 
-        let doc_len = self.document.len();
-        let gutter_width = if self.config.editor.line_numbers {
-            doc_len.to_string().len() + 2
+        if pane.window != self.current_window {
+            // Clicking or scrolling in another window focuses it; a drag
+            // that wanders into it does not.
+            if matches!(event.kind, MouseEventKind::Drag(_)) {
+                return;
+            }
+            if self.mode == Mode::Insert {
+                self.block_insert = None;
+                self.end_undo_group();
+            }
+            self.mode = Mode::Normal;
+            self.focus_window(pane.window);
+        }
+
+        let x = (event.column - pane.text.x) as usize;
+        let y = (event.row - pane.text.y) as usize;
+        let gutter_width = self.gutter_width(self.document());
+
+        let column = if x >= gutter_width {
+            self.offset.x + (x - gutter_width)
         } else {
-            0
+            self.offset.x // Click on gutter -> start of line?
         };
-
-        // Check if click is within text area (simplified)
-        if y < terminal_height.saturating_sub(2) {
-            let doc_x = if x >= gutter_width {
-                self.offset.x + (x - gutter_width)
-            } else {
-                self.offset.x // Click on gutter -> start of line?
-            };
-            let doc_y = self.offset.y + y;
-
-            // Ignore click on gutter? or select line?
-            // For now let's just allow it to move cursor to start if clicked on gutter (x < gutter_width -> doc_x = offset_x)
-
-            match event.kind {
-                MouseEventKind::Down(MouseButton::Left) => {
-                    self.move_cursor_absolute(doc_x, doc_y);
-                    self.mode = Mode::Normal;
-                    self.selection_start = None;
-                    self.mouse_drag_start = Some(self.cursor_position);
-                }
-                MouseEventKind::Drag(MouseButton::Left) => {
-                    self.move_cursor_absolute(doc_x, doc_y);
-                    if self.mouse_drag_start.is_some() {
-                        if self.mode == Mode::Normal {
-                            self.mode = Mode::Visual;
-                            self.selection_start = self.mouse_drag_start;
-                        }
-                    }
-                }
-                MouseEventKind::Up(MouseButton::Left) => {
-                    self.mouse_drag_start = None;
+        let doc_y = self.offset.y + y;
+        let tab_size = self.config.editor.tab_size;
+        let doc_x = self
+            .document()
+            .row(doc_y)
+            .map_or(0, |row| row.index_at_column(column, tab_size));
+
+        // Ignore click on gutter? or select line?
+        // For now let's just allow it to move cursor to start if clicked on gutter (x < gutter_width -> doc_x = offset_x)
+
+        match event.kind {
+            MouseEventKind::Down(MouseButton::Left) => {
+                if self.mode == Mode::Insert {
+                    self.block_insert = None;
+                    self.end_undo_group();
                 }
-                MouseEventKind::ScrollUp => {
-                    self.move_cursor(0, -3);
-                }
-                MouseEventKind::ScrollDown => {
-                    self.move_cursor(0, 3);
+                self.move_cursor_absolute(doc_x, doc_y);
+                self.mode = Mode::Normal;
+                self.selection_start = None;
+                self.mouse_drag_start = Some(self.cursor_position);
+            }
+            MouseEventKind::Drag(MouseButton::Left) => {
+                self.move_cursor_absolute(doc_x, doc_y);
+                if self.mouse_drag_start.is_some() && self.mode == Mode::Normal {
+                    self.mode = Mode::Visual;
+                    self.selection_start = self.mouse_drag_start;
                 }
-                _ => {}
             }
+            MouseEventKind::Up(MouseButton::Left) => {
+                self.mouse_drag_start = None;
+            }
+            MouseEventKind::ScrollUp => {
+                self.move_cursor(0, -3);
+            }
+            MouseEventKind::ScrollDown => {
+                self.move_cursor(0, 3);
+            }
+            _ => {}
         }
     }
 
     fn process_visual_mode(&mut self, key: KeyEvent) {
+        let control = key.modifiers.contains(KeyModifiers::CONTROL);
+        // After a lone `"` the key names a register, even `c`, `d`, `x` or `y`
+        let naming_register = self.pending_keys.ends_with('"')
+            && normal::pending_register(&self.pending_keys).is_none();
+        // Motions (with counts) extend the selection; the operators below
+        // act on it directly.
+        if let KeyCode::Char(c) = key.code
+            && !control
+            && (naming_register || !matches!(c, 'c' | 'd' | 'x' | 'y'))
+        {
+            self.pending_keys.push(c);
+            match normal::parse_visual(&self.pending_keys) {
+                Parsed::Pending => return,
+                Parsed::Done(Command {
+                    count,
+                    action: Action::Move(motion),
+                    ..
+                }) => {
+                    self.pending_keys.clear();
+                    self.move_to(motion, count);
+                    return;
+                }
+                Parsed::Done(Command {
+                    count,
+                    action: Action::Select(object),
+                    ..
+                }) => {
+                    self.pending_keys.clear();
+                    self.select_object(object, count.unwrap_or(1));
+                    return;
+                }
+                _ => self.pending_keys.clear(),
+            }
+        } else {
+            self.register = normal::pending_register(&self.pending_keys);
+            self.pending_keys.clear();
+        }
+
+        let document = self.document();
+        let Some(selection) = self.selection(document, self.selection_start, self.cursor_position)
+        else {
+            return;
+        };
         match key.code {
-            KeyCode::Esc => {
-                self.mode = Mode::Normal;
-                self.selection_start = None;
+            KeyCode::Esc => self.exit_visual(),
+            KeyCode::Char('v') if control => self.switch_visual(Mode::VisualBlock),
+            KeyCode::Char('v') => self.switch_visual(Mode::Visual),
+            KeyCode::Char('V') => self.switch_visual(Mode::VisualLine),
+            KeyCode::Char('>') => {
+                self.exit_visual();
+                self.shift_lines(selection.line_range(), true);
             }
-            KeyCode::Char('h') => self.move_cursor(-1, 0),
-            KeyCode::Char('j') => self.move_cursor(0, 1),
-            KeyCode::Char('k') => self.move_cursor(0, -1),
-            KeyCode::Char('l') => self.move_cursor(1, 0),
-            KeyCode::Char('y') => {
-                if let Some(start) = self.selection_start {
-                    let content = self.document.get_substring(&start, &self.cursor_position);
-                    if let Some(cb) = &mut self.clipboard {
-                        let _ = cb.set_text(content);
-                    }
-                }
-                self.mode = Mode::Normal;
-                self.selection_start = None;
-                self.set_status_message("Yanked!".to_string());
-            }
-            KeyCode::Char('d') => {
-                if let Some(start) = self.selection_start {
-                    self.document.delete_range(&start, &self.cursor_position);
-                    // Move cursor to start of deletion
-                    let (new_pos, _) = if start.y < self.cursor_position.y
-                        || (start.y == self.cursor_position.y && start.x <= self.cursor_position.x)
-                    {
-                        (start, self.cursor_position)
-                    } else {
-                        (self.cursor_position, start)
-                    };
-                    self.move_cursor_absolute(new_pos.x, new_pos.y);
+            KeyCode::Char('<') => {
+                self.exit_visual();
+                self.shift_lines(selection.line_range(), false);
+            }
+            KeyCode::Char(c @ ('c' | 'd' | 'x' | 'y')) => {
+                let operator = match c {
+                    'c' => Operator::Change,
+                    'y' => Operator::Yank,
+                    _ => Operator::Delete,
+                };
+                self.exit_visual();
+                self.operate_selection(operator, selection);
+                match c {
+                    'y' => self.set_status_message("Yanked!".to_string()),
+                    'd' => self.set_status_message("Deleted".to_string()),
+                    'x' => self.set_status_message("Cut!".to_string()),
+                    _ => {}
                 }
-                self.mode = Mode::Normal;
-                self.selection_start = None;
-                self.set_status_message("Deleted".to_string());
             }
-            KeyCode::Char('x') => {
-                if let Some(start) = self.selection_start {
-                    let content = self.document.get_substring(&start, &self.cursor_position);
-                    if let Some(cb) = &mut self.clipboard {
-                        let _ = cb.set_text(content);
-                    }
-                    self.document.delete_range(&start, &self.cursor_position);
-                    // Move cursor to start of deletion
-                    let (new_pos, _) = if start.y < self.cursor_position.y
-                        || (start.y == self.cursor_position.y && start.x <= self.cursor_position.x)
-                    {
-                        (start, self.cursor_position)
+            KeyCode::Char(c @ ('I' | 'A')) => {
+                if let Selection::Block {
+                    top,
+                    bottom,
+                    left,
+                    right,
+                } = selection
+                {
+                    self.exit_visual();
+                    let (column, pad) = if c == 'I' {
+                        (left, false)
                     } else {
-                        (self.cursor_position, start)
+                        (right + 1, true)
                     };
-                    self.move_cursor_absolute(new_pos.x, new_pos.y);
+                    self.begin_undo_group();
+                    self.start_block_insert(top, bottom, column, pad);
                 }
-                self.mode = Mode::Normal;
-                self.selection_start = None;
-                self.set_status_message("Cut!".to_string());
             }
             KeyCode::Left => self.move_cursor(-1, 0),
             KeyCode::Right => self.move_cursor(1, 0),
@@ -529,71 +1089,665 @@ impl Editor {
         }
     }
 
+    /// The selection in `document` between `anchor` and `cursor` for the
+    /// current Visual mode, or `None` outside Visual mode.
+    fn selection(
+        &self,
+        document: &Document,
+        anchor: Option<Position>,
+        cursor: Position,
+    ) -> Option<Selection> {
+        let anchor = anchor?;
+        match self.mode {
+            Mode::Visual => Some(Selection::chars(anchor, cursor)),
+            Mode::VisualLine => Some(Selection::lines(anchor, cursor)),
+            Mode::VisualBlock => Some(Selection::block(
+                document,
+                anchor,
+                cursor,
+                self.config.editor.tab_size,
+            )),
+            _ => None,
+        }
+    }
+
+    /// `v`, `V` and `Ctrl-v` switch between the Visual modes, or leave
+    /// Visual mode when pressed again.
+    fn switch_visual(&mut self, mode: Mode) {
+        if self.mode == mode {
+            self.exit_visual();
+        } else {
+            self.mode = mode;
+        }
+    }
+
+    fn exit_visual(&mut self) {
+        self.mode = Mode::Normal;
+        self.selection_start = None;
+    }
+
+    /// Applies `operator` to a Visual selection.
+    fn operate_selection(&mut self, operator: Operator, selection: Selection) {
+        match selection {
+            Selection::Chars { start, end } => {
+                let document = self.document();
+                let start_idx = document.position_to_char_idx(&start);
+                let end_idx = match document.row(end.y) {
+                    Some(row) if end.x < row.len() => {
+                        document.position_to_char_idx(&end) - end.x + row.next_grapheme(end.x)
+                    }
+                    _ => document.position_to_char_idx(&end) + 1,
+                };
+                self.operate_range(operator, start_idx, end_idx);
+            }
+            Selection::Lines { first, last } => self.operate_lines(operator, first, last),
+            Selection::Block {
+                top,
+                bottom,
+                left,
+                right,
+            } => self.operate_block(operator, top, bottom, left, right),
+        }
+    }
+
+    /// Applies `operator` to display columns `left..=right` of lines
+    /// `top..=bottom`. Yanked text has one line per line of the block.
+    fn operate_block(
+        &mut self,
+        operator: Operator,
+        top: usize,
+        bottom: usize,
+        left: usize,
+        right: usize,
+    ) {
+        let tab_size = self.config.editor.tab_size;
+        let document = self.document();
+        let spans: Vec<(usize, usize)> = (top..=bottom)
+            .filter_map(|y| {
+                let row = document.row(y)?;
+                let span = selection::block_span(&row, left, right, tab_size);
+                let line_start = document.content.line_to_char(y);
+                Some((line_start + span.start, line_start + span.end))
+            })
+            .collect();
+        let text: Vec<String> = spans
+            .iter()
+            .map(|&(start, end)| document.slice(start, end))
+            .collect();
+        let start = spans
+            .first()
+            .map_or(Position { x: 0, y: top }, |&(start, _)| {
+                document.char_idx_to_position(start)
+            });
+        self.store_register(operator, text.join("\n"), RegisterKind::Block);
+
+        if operator != Operator::Yank {
+            self.begin_undo_group();
+            // Bottom-up, so the indices of the lines above stay valid
+            for &(span_start, span_end) in spans.iter().rev() {
+                self.document_mut()
+                    .delete_chars(span_start, span_end, start);
+            }
+            if operator == Operator::Change {
+                self.start_block_insert(top, bottom, left, false);
+                return;
+            }
+            self.end_undo_group();
+        }
+        self.move_cursor_absolute(start.x, start.y);
+    }
+
+    /// Enters Insert mode at display `column` of line `top` (padding the line
+    /// with spaces if `pad` is set), to repeat the typed text down to `bottom`.
+    /// The caller opens the undo group; leaving Insert mode closes it.
+    fn start_block_insert(&mut self, top: usize, bottom: usize, column: usize, pad: bool) {
+        let start = self.block_column(top, column, pad);
+        self.move_cursor_absolute(start.x, start.y);
+        self.block_insert = Some(BlockInsert {
+            start,
+            lines: top + 1..=bottom,
+            column,
+            pad,
+        });
+        self.mode = Mode::Insert;
+    }
+
+    /// Copies what was typed since `start_block_insert` to the other lines of
+    /// the block. Nothing is copied if the cursor left the line.
+    fn finish_block_insert(&mut self) {
+        let Some(block) = self.block_insert.take() else {
+            return;
+        };
+        let cursor = self.cursor_position;
+        if cursor.y != block.start.y || cursor.x <= block.start.x {
+            return;
+        }
+        let document = self.document();
+        let line_start = document.content.line_to_char(cursor.y);
+        let text = document.slice(line_start + block.start.x, line_start + cursor.x);
+        for y in block.lines {
+            let at = self.block_column(y, block.column, block.pad);
+            if self.document().row(y).is_some_and(|row| at.x < row.len()) || block.pad {
+                self.document_mut().insert_str(&at, &text);
+            }
+        }
+        self.move_cursor_absolute(block.start.x, block.start.y);
+    }
+
+    /// Char position of display `column` on line `y`. With `pad`, a line that
+    /// ends before the column is first filled with spaces up to it.
+    fn block_column(&mut self, y: usize, column: usize, pad: bool) -> Position {
+        let tab_size = self.config.editor.tab_size;
+        let Some(row) = self.document().row(y) else {
+            return Position { x: 0, y };
+        };
+        let width = row.column_of(row.len(), tab_size);
+        if pad && width < column {
+            let end = Position { x: row.len(), y };
+            self.document_mut()
+                .insert_str(&end, &" ".repeat(column - width));
+            return Position {
+                x: row.len() + column - width,
+                y,
+            };
+        }
+        Position {
+            x: row.index_at_column(column, tab_size),
+            y,
+        }
+    }
+
+    /// `>` / `<`: indents or outdents `lines` by `tab_size` spaces. Empty lines
+    /// aren't indented.
+    fn shift_lines(&mut self, lines: RangeInclusive<usize>, indent: bool) {
+        let tab_size = self.config.editor.tab_size;
+        let first = *lines.start();
+        self.begin_undo_group();
+        for y in lines {
+            let document = self.document();
+            if y >= document.len() {
+                break;
+            }
+            let at = Position { x: 0, y };
+            if indent {
+                if document.line_len(y) > 0 {
+                    self.document_mut().insert_str(&at, &" ".repeat(tab_size));
+                }
+                continue;
+            }
+            // Remove up to `tab_size` columns of leading whitespace
+            let mut column = 0;
+            let mut count = 0;
+            for c in document.content.line(y).chars() {
+                match c {
+                    ' ' if column < tab_size => column += 1,
+                    '\t' if column < tab_size => column = tab_size,
+                    _ => break,
+                }
+                count += 1;
+            }
+            let start = document.content.line_to_char(y);
+            self.document_mut().delete_chars(start, start + count, at);
+        }
+        self.end_undo_group();
+        let x = motion::first_non_blank(self.document(), first);
+        self.move_cursor_absolute(x, first);
+    }
+
     fn process_normal_mode(&mut self, key: KeyEvent) {
+        if self.pending_window_key {
+            self.pending_window_key = false;
+            self.process_window_key(key);
+            return;
+        }
+        if let KeyCode::Char(c) = key.code
+            && !key
+                .modifiers
+                .intersects(KeyModifiers::CONTROL | KeyModifiers::ALT)
+        {
+            self.pending_keys.push(c);
+            match normal::parse(&self.pending_keys) {
+                Parsed::Pending => return,
+                Parsed::Done(command) => {
+                    self.pending_keys.clear();
+                    self.run_normal_command(command);
+                    return;
+                }
+                Parsed::Invalid => {
+                    self.pending_keys.clear();
+                    return;
+                }
+                // Not part of the grammar: any count typed before it is dropped
+                Parsed::NotACommand => self.pending_keys.clear(),
+            }
+        } else {
+            self.pending_keys.clear();
+        }
         match key.code {
-            KeyCode::Char('q') => self.should_quit = true, // Quick quit for debugging
-            KeyCode::Char('i') => self.mode = Mode::Insert,
+            KeyCode::Char('w') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                self.pending_window_key = true;
+            }
+            KeyCode::Char('r') if key.modifiers.contains(KeyModifiers::CONTROL) => self.redo(),
+            KeyCode::Char('q') => self.quit(false), // Quick quit for debugging
+            KeyCode::Char('u') => self.undo(),
             KeyCode::Char(':') => {
                 self.mode = Mode::Command;
                 self.command_buffer.clear();
             }
-            KeyCode::Char('h') => self.move_cursor(-1, 0),
-            KeyCode::Char('j') => self.move_cursor(0, 1),
-            KeyCode::Char('k') => self.move_cursor(0, -1),
-            KeyCode::Char('l') => self.move_cursor(1, 0),
-            KeyCode::Char('x') => self.document.delete(&self.cursor_position),
+            KeyCode::Char('v') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                self.mode = Mode::VisualBlock;
+                self.selection_start = Some(self.cursor_position);
+            }
             KeyCode::Char('v') => {
                 self.mode = Mode::Visual;
                 self.selection_start = Some(self.cursor_position);
             }
-            KeyCode::Char('p') => {
-                if let Some(cb) = &mut self.clipboard {
-                    if let Ok(content) = cb.get_text() {
-                        for c in content.chars() {
-                            self.document.insert(&self.cursor_position, c);
-                            if c == '\n' {
-                                let pos = Position {
-                                    x: 0,
-                                    y: self.cursor_position.y + 1,
-                                };
-                                self.move_cursor_absolute(pos.x, pos.y);
-                            } else {
-                                self.move_cursor(1, 0);
-                            }
-                        }
+            KeyCode::Char('V') => {
+                self.mode = Mode::VisualLine;
+                self.selection_start = Some(self.cursor_position);
+            }
+            KeyCode::Char('/') => {
+                self.mode = Mode::Search;
+                self.search_direction = SearchDirection::Forward;
+                self.command_buffer.clear();
+            }
+            KeyCode::Char('?') => {
+                self.mode = Mode::Search;
+                self.search_direction = SearchDirection::Backward;
+                self.command_buffer.clear();
+            }
+            KeyCode::Char('n') => {
+                if let Some(query) = self.last_search_query.clone() {
+                    self.run_search(&query, self.search_direction);
+                }
+            }
+            KeyCode::Char('N') => {
+                if let Some(query) = self.last_search_query.clone() {
+                    let direction = match self.search_direction {
+                        SearchDirection::Forward => SearchDirection::Backward,
+                        SearchDirection::Backward => SearchDirection::Forward,
+                    };
+                    self.run_search(&query, direction);
+                }
+            }
+            KeyCode::Left => self.move_cursor(-1, 0),
+            KeyCode::Right => self.move_cursor(1, 0),
+            KeyCode::Up => self.move_cursor(0, -1),
+            KeyCode::Down => self.move_cursor(0, 1),
+            _ => {}
+        }
+    }
+
+    fn run_normal_command(&mut self, command: Command) {
+        if command.is_change() {
+            self.recording = Some(Change {
+                command,
+                insert_keys: Vec::new(),
+            });
+        }
+        let count = command.count.unwrap_or(1);
+        self.register = command.register;
+        match command.action {
+            Action::Move(motion) => self.move_to(motion, command.count),
+            Action::Operate(operator, motion) => self.operate(operator, motion, command.count),
+            Action::OperateObject(operator, object) => self.operate_object(operator, object, count),
+            // Only produced by `normal::parse_visual`
+            Action::Select(_) => {}
+            Action::OperateLines(operator) => {
+                let first = self.cursor_position.y;
+                let last = (first + count - 1).min(self.document().len().saturating_sub(1));
+                self.operate_lines(operator, first, last);
+            }
+            Action::DeleteChar => {
+                // Up to `count` chars, but not past the end of the line. A
+                // single `x` there joins the next line.
+                let pos = self.cursor_position;
+                let document = self.document();
+                let start = document.position_to_char_idx(&pos);
+                let end = match document.row(pos.y) {
+                    Some(row) if pos.x < row.len() => {
+                        let mut x = pos.x;
+                        for _ in 0..count {
+                            x = row.next_grapheme(x);
+                        }
+                        start - pos.x + x
+                    }
+                    _ => start + 1,
+                };
+                self.operate_range(Operator::Delete, start, end);
+            }
+            Action::Paste { before } => self.paste(before, count),
+            Action::Insert => {
+                self.begin_undo_group();
+                self.mode = Mode::Insert;
+            }
+            Action::Repeat => self.repeat_last_change(command.count),
+        }
+    }
+
+    /// `.`: runs the last change again, with `count` replacing its count.
+    fn repeat_last_change(&mut self, count: Option<usize>) {
+        let Some(mut change) = self.last_change.clone() else {
+            return;
+        };
+        if count.is_some() {
+            change.command.count = count;
+        }
+        self.run_normal_command(change.command);
+        for key in change.insert_keys {
+            self.handle_key(key);
+        }
+        if self.mode == Mode::Insert {
+            self.handle_key(KeyEvent::from(KeyCode::Esc));
+        }
+    }
+
+    /// Visual mode `iw`, `a(`...: selects the text object around the cursor.
+    fn select_object(&mut self, object: TextObject, count: usize) {
+        let document = self.document();
+        let Some(range) = object.select(document, self.cursor_position, count) else {
+            return;
+        };
+        if range.end <= range.start {
+            return;
+        }
+        let start = document.char_idx_to_position(range.start);
+        let end = document.char_idx_to_position(range.end - 1);
+        self.selection_start = Some(start);
+        self.cursor_position = end;
+        self.scroll();
+    }
+
+    fn move_to(&mut self, motion: Motion, count: Option<usize>) {
+        let tab_size = self.config.editor.tab_size;
+        let target = motion.apply(self.document(), self.cursor_position, count, tab_size);
+        self.move_cursor_absolute(target.x, target.y);
+    }
+
+    /// Applies `operator` to the text between the cursor and where `motion` lands.
+    fn operate(&mut self, operator: Operator, motion: Motion, count: Option<usize>) {
+        let tab_size = self.config.editor.tab_size;
+        let from = self.cursor_position;
+        let document = self.document();
+        let on_blank = document
+            .content
+            .get_char(document.position_to_char_idx(&from))
+            .is_none_or(char::is_whitespace);
+
+        let (to, inclusive) = match motion {
+            // `cw` on a word changes up to its end, like `ce`
+            Motion::WordForward { big } if operator == Operator::Change && !on_blank => (
+                motion::change_word_end(document, from, count.unwrap_or(1), big),
+                true,
+            ),
+            _ => (
+                motion.apply(document, from, count, tab_size),
+                motion.is_inclusive(),
+            ),
+        };
+        // `dj` on the last line (or `dk` on the first) fails, as in Vim
+        if matches!(motion, Motion::Up | Motion::Down) && to.y == from.y {
+            return;
+        }
+        if motion.is_linewise() {
+            self.operate_lines(operator, from.y.min(to.y), from.y.max(to.y));
+            return;
+        }
+
+        let mut to = to;
+        // `dw` on the last word of a line stops at the end of that line
+        if matches!(motion, Motion::WordForward { .. })
+            && to.y > from.y
+            && to.x <= motion::first_non_blank(document, to.y)
+        {
+            to = Position {
+                x: document.line_len(to.y - 1),
+                y: to.y - 1,
+            };
+        }
+
+        let a = document.position_to_char_idx(&from);
+        let b = document.position_to_char_idx(&to);
+        let (start, mut end) = (a.min(b), a.max(b));
+        if inclusive {
+            let end_pos = document.char_idx_to_position(end);
+            end = match document.row(end_pos.y) {
+                Some(row) if end_pos.x < row.len() => {
+                    end - end_pos.x + row.next_grapheme(end_pos.x)
+                }
+                // Never the line break: `d$` on an empty line does nothing
+                _ => end,
+            };
+        }
+        if start == end && operator != Operator::Change {
+            return;
+        }
+        self.operate_range(operator, start, end);
+    }
+
+    /// Applies `operator` to a text object around the cursor (`diw`, `ca"`...).
+    fn operate_object(&mut self, operator: Operator, object: TextObject, count: usize) {
+        let document = self.document();
+        let Some(mut range) = object.select(document, self.cursor_position, count) else {
+            return;
+        };
+        // `ci{` on a block keeps a line to type into before the closing bracket
+        if operator == Operator::Change
+            && !range.linewise
+            && range.end > range.start
+            && document.content.char(range.end - 1) == '\n'
+        {
+            range.end -= 1;
+        }
+        if range.linewise {
+            let first = document.content.char_to_line(range.start);
+            let last = document.content.char_to_line(range.end.saturating_sub(1));
+            self.operate_lines(operator, first, last.max(first));
+        } else {
+            self.operate_range(operator, range.start, range.end);
+        }
+    }
+
+    /// Applies `operator` to chars `start..end` and leaves the cursor at `start`.
+    fn operate_range(&mut self, operator: Operator, start: usize, end: usize) {
+        let document = self.document();
+        let text = document.slice(start, end);
+        let start_pos = document.char_idx_to_position(start);
+
+        match operator {
+            Operator::Yank => self.store_register(operator, text, RegisterKind::Chars),
+            Operator::Delete | Operator::Change => {
+                self.store_register(operator, text, RegisterKind::Chars);
+                self.begin_undo_group();
+                self.document_mut().delete_chars(start, end, start_pos);
+                if operator == Operator::Change {
+                    self.mode = Mode::Insert;
+                } else {
+                    self.end_undo_group();
+                }
+            }
+        }
+        self.move_cursor_absolute(start_pos.x, start_pos.y);
+    }
+
+    /// Applies `operator` to lines `first..=last` (`dd`, `yj`, `cG`...).
+    fn operate_lines(&mut self, operator: Operator, first: usize, last: usize) {
+        let document = self.document();
+        let start = document.content.line_to_char(first);
+        let end = if last + 1 < document.len() {
+            document.content.line_to_char(last + 1)
+        } else {
+            document.content.len_chars()
+        };
+        let at_end = end == document.content.len_chars();
+        let mut text = document.slice(start, end);
+        let ends_with_newline = text.ends_with('\n');
+        if !ends_with_newline {
+            text.push('\n');
+        }
+        self.store_register(operator, text, RegisterKind::Lines);
+
+        let cursor = self.cursor_position;
+        match operator {
+            Operator::Yank => {
+                let y = first.min(cursor.y);
+                self.move_cursor_absolute(cursor.x, y);
+            }
+            Operator::Delete => {
+                // Deleting the last lines takes the line break before them instead
+                let start = if at_end && first > 0 {
+                    start - 1
+                } else {
+                    start
+                };
+                self.begin_undo_group();
+                self.document_mut().delete_chars(start, end, cursor);
+                self.end_undo_group();
+                let y = first.min(self.document().len().saturating_sub(1));
+                let x = motion::first_non_blank(self.document(), y);
+                self.move_cursor_absolute(x, y);
+            }
+            Operator::Change => {
+                // Keep one (now empty) line to type into
+                let end = if ends_with_newline { end - 1 } else { end };
+                self.begin_undo_group();
+                self.document_mut().delete_chars(start, end, cursor);
+                self.move_cursor_absolute(0, first);
+                self.mode = Mode::Insert;
+            }
+        }
+    }
+
+    /// Saves yanked or deleted text in the register named for the command
+    /// (`"ay`), or in `"0` / `"1` and the unnamed register.
+    fn store_register(&mut self, operator: Operator, text: String, kind: RegisterKind) {
+        let register = Register::new(text, kind);
+        match operator {
+            Operator::Yank => self.registers.yank(self.register, register),
+            Operator::Delete | Operator::Change => self.registers.delete(self.register, register),
+        }
+    }
+
+    /// `p` / `P`: puts the register contents `count` times after (or before)
+    /// the cursor: whole lines below (above) the cursor line, a block
+    /// column-wise, anything else at the cursor.
+    fn paste(&mut self, before: bool, count: usize) {
+        let Some(register) = self.registers.get(self.register) else {
+            let name = self.register.unwrap_or('"');
+            self.set_status_message(format!("Nothing in register \"{}", name));
+            return;
+        };
+        let cursor = self.cursor_position;
+        self.begin_undo_group();
+        match register.kind {
+            RegisterKind::Chars => {
+                let document = self.document();
+                let x = match document.row(cursor.y) {
+                    Some(row) if !before && cursor.x < row.len() => row.next_grapheme(cursor.x),
+                    _ => cursor.x,
+                };
+                let at = Position { x, y: cursor.y };
+                let idx = document.position_to_char_idx(&at);
+                let text = register.text.repeat(count);
+                self.document_mut().insert_str(&at, &text);
+                // The cursor ends on the last char put, or at the start of
+                // text that spans lines
+                let end = if text.contains('\n') {
+                    at
+                } else {
+                    let last = idx + text.chars().count().saturating_sub(1);
+                    self.document().char_idx_to_position(last)
+                };
+                self.move_cursor_absolute(end.x, end.y);
+            }
+            RegisterKind::Lines => {
+                let document = self.document();
+                let len = document.content.len_chars();
+                let mut y = if before { cursor.y } else { cursor.y + 1 };
+                let mut text = register.text.repeat(count);
+                let at = if y < document.len() {
+                    Position { x: 0, y }
+                } else if len == 0 || document.content.char(len - 1) == '\n' {
+                    // On the empty line after the final line break
+                    y = cursor.y;
+                    Position { x: 0, y }
+                } else {
+                    // Below the last line of a file without a final line break
+                    text.pop();
+                    text.insert(0, '\n');
+                    Position {
+                        x: document.line_len(cursor.y),
+                        y: cursor.y,
                     }
-                }
+                };
+                self.document_mut().insert_str(&at, &text);
+                let x = motion::first_non_blank(self.document(), y);
+                self.move_cursor_absolute(x, y);
             }
-            KeyCode::Char('/') => {
-                self.mode = Mode::Search;
-                self.command_buffer.clear();
+            RegisterKind::Block => self.paste_block(&register.text, before, count),
+        }
+        self.end_undo_group();
+    }
+
+    /// Puts a block yanked in Visual block mode: each line of `text` goes on
+    /// its own line, starting at the cursor's column.
+    fn paste_block(&mut self, text: &str, before: bool, count: usize) {
+        let tab_size = self.config.editor.tab_size;
+        let cursor = self.cursor_position;
+        let column = self.document().row(cursor.y).map_or(0, |row| {
+            if before || cursor.x >= row.len() {
+                row.column_of(cursor.x, tab_size)
+            } else {
+                row.column_of(row.next_grapheme(cursor.x), tab_size)
             }
-            KeyCode::Char('n') => {
-                if let Some(query) = self.last_search_query.clone() {
-                    self.run_search(&query, SearchDirection::Forward);
-                }
+        });
+        let pieces: Vec<&str> = text.split('\n').collect();
+        let piece_width = |piece: &str| {
+            let row = row::Row::from(piece);
+            row.column_of(row.len(), tab_size)
+        };
+        let width = pieces.iter().map(|p| piece_width(p)).max().unwrap_or(0);
+
+        // Add lines at the end of the buffer for the block to go on
+        let last = cursor.y + pieces.len() - 1;
+        loop {
+            let document = self.document();
+            let len = document.content.len_chars();
+            let trailing_newline = len > 0 && document.content.char(len - 1) == '\n';
+            if document.len() > last + usize::from(trailing_newline) {
+                break;
             }
-            KeyCode::Char('N') => {
-                if let Some(query) = self.last_search_query.clone() {
-                    self.run_search(&query, SearchDirection::Backward);
-                }
+            let end = document.char_idx_to_position(len);
+            self.document_mut().insert_str(&end, "\n");
+        }
+
+        let mut start = None;
+        for (i, piece) in pieces.iter().enumerate() {
+            let y = cursor.y + i;
+            let at = self.block_column(y, column, true);
+            start.get_or_insert(at);
+            // Pad each piece to the block's width unless nothing follows it
+            let padded = format!("{}{}", piece, " ".repeat(width - piece_width(piece)));
+            let mut text = padded.repeat(count);
+            if at.x >= self.document().line_len(y) {
+                text.truncate(text.trim_end_matches(' ').len());
             }
-            KeyCode::Left => self.move_cursor(-1, 0),
-            KeyCode::Right => self.move_cursor(1, 0),
-            KeyCode::Up => self.move_cursor(0, -1),
-            KeyCode::Down => self.move_cursor(0, 1),
-            _ => {}
+            self.document_mut().insert_str(&at, &text);
         }
+        let start = start.unwrap_or(cursor);
+        self.move_cursor_absolute(start.x, start.y);
     }
 
     fn process_insert_mode(&mut self, key: KeyEvent) {
         match key.code {
             KeyCode::Esc => {
                 self.completion_active = false;
+                self.finish_block_insert();
+                self.end_undo_group();
                 self.mode = Mode::Normal;
             }
             KeyCode::Char(c) => {
-                self.document.insert(&self.cursor_position, c);
+                self.insert_char(c);
                 self.move_cursor(1, 0);
                 self.update_completion();
             }
@@ -601,25 +1755,24 @@ impl Editor {
                 if self.completion_active {
                     self.insert_completion();
                 } else {
-                    self.document.insert_newline(&self.cursor_position);
+                    let pos = self.cursor_position;
+                    self.document_mut().insert_newline(&pos);
                     self.move_cursor_absolute(0, self.cursor_position.y + 1);
                 }
             }
-            KeyCode::Backspace => {
-                if self.cursor_position.x > 0 || self.cursor_position.y > 0 {
-                    if self.cursor_position.x > 0 {
-                        self.move_cursor(-1, 0);
-                        self.document.delete(&self.cursor_position);
-                    } else if self.cursor_position.y > 0 {
-                        let prev_y = self.cursor_position.y - 1;
-                        if let Some(row) = self.document.row(prev_y) {
-                            let len = row.len();
-                            self.move_cursor_absolute(len, prev_y);
-                            self.document.delete(&self.cursor_position);
-                        }
+            KeyCode::Backspace if self.cursor_position.x > 0 || self.cursor_position.y > 0 => {
+                if self.cursor_position.x > 0 {
+                    self.move_cursor(-1, 0);
+                    self.delete_char();
+                } else if self.cursor_position.y > 0 {
+                    let prev_y = self.cursor_position.y - 1;
+                    if let Some(row) = self.document().row(prev_y) {
+                        let len = row.len();
+                        self.move_cursor_absolute(len, prev_y);
+                        self.delete_char();
                     }
-                    self.update_completion();
                 }
+                self.update_completion();
             }
             KeyCode::Tab => {
                 if self.completion_active {
@@ -627,7 +1780,7 @@ impl Editor {
                 } else {
                     let tab_size = self.config.editor.tab_size;
                     for _ in 0..tab_size {
-                        self.document.insert(&self.cursor_position, ' ');
+                        self.insert_char(' ');
                         self.move_cursor(1, 0);
                     }
                 }
@@ -698,10 +1851,14 @@ impl Editor {
                 self.command_buffer.pop();
             }
             KeyCode::Enter => {
-                let query = self.command_buffer.clone();
-                self.last_search_query = Some(query.clone());
                 self.mode = Mode::Normal;
-                self.run_search(&query, SearchDirection::Forward);
+                // An empty pattern repeats the last search
+                if !self.command_buffer.is_empty() {
+                    self.last_search_query = Some(self.command_buffer.clone());
+                }
+                if let Some(query) = self.last_search_query.clone() {
+                    self.run_search(&query, self.search_direction);
+                }
                 self.command_buffer.clear();
             }
             _ => {}
@@ -709,35 +1866,23 @@ impl Editor {
     }
 
     fn run_search(&mut self, query: &str, direction: SearchDirection) {
-        let start_pos = match direction {
-            SearchDirection::Forward => {
-                // Forward search should start AFTER current char to find next match
-                // But wait, our find logic scans matches >= start_x.
-                // So if we are on match, we find it again.
-                // So for 'n', we should advance by 1?
-                // Or we pass cursor pos, and `find` is inclusive.
-                // We want strictly next.
-
-                // Let's modify start pos.
-                if self.cursor_position.x < usize::MAX {
-                    // Safety match
-                    Position {
-                        x: self.cursor_position.x + 1,
-                        y: self.cursor_position.y,
-                    }
-                } else {
-                    self.cursor_position
-                }
-            }
-            SearchDirection::Backward => {
-                // For backward, we want match < current_x.
-                // Our find logic looks for matches < limit_x
-                // So passing cursor_position is correct (exclusive logic there)
-                self.cursor_position
+        let regex = match search::compile(query) {
+            Ok(regex) => regex,
+            Err(_) => {
+                self.set_status_message(format!("Invalid pattern: {}", query));
+                return;
             }
         };
+        // `find` accepts a match at the start position, so step past the cursor
+        let start_pos = match direction {
+            SearchDirection::Forward => Position {
+                x: self.cursor_position.x + 1,
+                y: self.cursor_position.y,
+            },
+            SearchDirection::Backward => self.cursor_position,
+        };
 
-        if let Some(pos) = self.document.find(query, &start_pos, direction) {
+        if let Some(pos) = self.document().find(&regex, &start_pos, direction) {
             self.move_cursor_absolute(pos.x, pos.y);
             self.selection_start = None; // clear selection if any
             self.set_status_message(String::new());
@@ -746,21 +1891,632 @@ impl Editor {
         }
     }
 
+    /// `:[range]s/pattern/replacement/[flags]`, run as a single undo step.
+    fn substitute(&mut self, substitution: Substitution) {
+        let pattern = if substitution.pattern.is_empty() {
+            match self.last_search_query.clone() {
+                Some(query) => query,
+                None => {
+                    self.set_status_message("No previous regular expression".to_string());
+                    return;
+                }
+            }
+        } else {
+            substitution.pattern.clone()
+        };
+        let regex = match substitution.regex(&pattern) {
+            Ok(regex) => regex,
+            Err(_) => {
+                self.set_status_message(format!("Invalid pattern: {}", pattern));
+                return;
+            }
+        };
+        self.last_search_query = Some(pattern.clone());
+
+        self.begin_undo_group();
+        let mut count = 0;
+        let mut lines = 0;
+        let mut last_line = None;
+        // Bottom up, so a replacement containing a newline doesn't shift
+        // the lines still to be processed
+        for y in substitution.lines.rev() {
+            let n = self.document_mut().substitute_line(
+                y,
+                &regex,
+                &substitution.replacement,
+                substitution.global,
+            );
+            if n > 0 {
+                count += n;
+                lines += 1;
+                last_line.get_or_insert(y);
+            }
+        }
+        let Some(last_line) = last_line else {
+            self.end_undo_group();
+            self.set_status_message(format!("Pattern not found: {}", pattern));
+            return;
+        };
+        self.move_cursor_absolute(0, last_line);
+        self.end_undo_group();
+
+        let plural = |n: usize| if n == 1 { "" } else { "s" };
+        let msg = format!(
+            "{} substitution{} on {} line{}",
+            count,
+            plural(count),
+            lines,
+            plural(lines)
+        );
+        self.set_status_message(msg);
+    }
+
     fn execute_command(&mut self) {
-        let cmd = self.command_buffer.trim();
-        if cmd == "q" {
-            self.should_quit = true;
-        } else if cmd == "w" {
-            if let Err(e) = self.document.save() {
-                self.set_status_message(format!("Error: {}", e));
-            } else {
-                self.set_status_message(format!("Written {} bytes", self.document.size_bytes()));
+        let cmd = self.command_buffer.trim().to_string();
+        // Substitutions are matched first: their patterns may contain spaces
+        let (current, line_count) = (self.cursor_position.y, self.document().len());
+        match Substitution::parse(&cmd, current, line_count) {
+            Some(Ok(substitution)) => return self.substitute(substitution),
+            Some(Err(e)) => return self.set_status_message(e),
+            None => {}
+        }
+        let (name, arg) = match cmd.split_once(' ') {
+            Some((name, arg)) => (name, arg.trim()),
+            None => (cmd.as_str(), ""),
+        };
+        // Vim allows the buffer number to be glued to the command, e.g. `:b2`
+        let (name, arg) = match name.strip_prefix('b') {
+            Some(num) if arg.is_empty() && num.parse::<usize>().is_ok() => ("b", num),
+            _ => (name, arg),
+        };
+
+        match name {
+            "q" | "quit" => self.quit(false),
+            "q!" | "quit!" => self.quit(true),
+            "w" | "write" => self.write_command(arg, false),
+            "w!" | "write!" => self.write_command(arg, true),
+            "sav" | "saveas" => self.save_as(arg, false),
+            "sav!" | "saveas!" => self.save_as(arg, true),
+            "e!" | "edit!" => self.reload_buffer(),
+            "set" | "se" => self.set_options(arg),
+            "wq" => {
+                if self.save_current() {
+                    self.quit(false);
+                }
+            }
+            "x" | "xit" => {
+                if !self.document().dirty || self.save_current() {
+                    self.quit(false);
+                }
+            }
+            "undo" | "u" => self.undo(),
+            "redo" | "red" => self.redo(),
+            "e" | "edit" => self.edit_file(arg),
+            "bn" | "bnext" => {
+                let next = (self.current_buffer + 1) % self.buffers.len();
+                self.switch_buffer(next);
+            }
+            "bp" | "bprevious" => {
+                let prev = (self.current_buffer + self.buffers.len() - 1) % self.buffers.len();
+                self.switch_buffer(prev);
+            }
+            "b" | "buffer" => match arg.parse::<usize>() {
+                Ok(n) if n >= 1 && n <= self.buffers.len() => self.switch_buffer(n - 1),
+                _ => self.set_status_message(format!("Buffer {} does not exist", arg)),
+            },
+            "bd" | "bdelete" => self.delete_buffer(false),
+            "bd!" | "bdelete!" => self.delete_buffer(true),
+            "ls" | "buffers" => self.list_buffers(),
+            "reg" | "registers" | "di" | "display" => self.list_registers(),
+            "sp" | "split" => self.split_window(SplitDirection::Horizontal, arg),
+            "vs" | "vsplit" => self.split_window(SplitDirection::Vertical, arg),
+            "clo" | "close" => self.close_window(),
+            _ => self.set_status_message(format!("Not an editor command: {}", cmd)),
+        }
+    }
+
+    /// Writes the current buffer, reporting the outcome on the status line.
+    fn save_current(&mut self) -> bool {
+        match self.document_mut().save() {
+            Ok(()) => {
+                let msg = format!("Written {} bytes", self.document().size_bytes());
+                self.set_status_message(msg);
+                true
+            }
+            Err(e) => {
+                let name = self.buffers[self.current_buffer].display_name();
+                self.set_status_message(format!("Error writing {}: {}", name, e));
+                false
+            }
+        }
+    }
+
+    /// `:w`, `:w <path>` and `:w >> <path>`.
+    fn write_command(&mut self, arg: &str, force: bool) {
+        if let Some(path) = arg.strip_prefix(">>") {
+            let path = match path.trim() {
+                "" => match &self.document().file_name {
+                    Some(name) => name.clone(),
+                    None => {
+                        self.set_status_message("No file name".to_string());
+                        return;
+                    }
+                },
+                path => path.to_string(),
+            };
+            match self.document().append_to(&path) {
+                Ok(()) => {
+                    let msg = format!(
+                        "Appended {} bytes to {}",
+                        self.document().size_bytes(),
+                        path
+                    );
+                    self.set_status_message(msg);
+                }
+                Err(e) => self.set_status_message(format!("Error writing {}: {}", path, e)),
+            }
+            return;
+        }
+
+        // `!` only applies to the file written here: for the buffer's own
+        // file it lifts read-only and the check for changes on disk
+        if arg.is_empty() || self.document().file_name.as_deref() == Some(arg) {
+            if force {
+                let document = self.document_mut();
+                document.read_only = false;
+                document.allow_overwrite();
+            }
+            self.save_current();
+            return;
+        }
+        if !force && Path::new(arg).exists() {
+            self.set_status_message("File exists (add ! to override)".to_string());
+            return;
+        }
+
+        // An unnamed buffer adopts the path; a named one just writes a copy.
+        if self.document().file_name.is_none() {
+            self.write_as(arg);
+            return;
+        }
+        match self.document().write_copy(arg) {
+            Ok(()) => {
+                let msg = format!("Written {} bytes to {}", self.document().size_bytes(), arg);
+                self.set_status_message(msg);
+            }
+            Err(e) => self.set_status_message(format!("Error writing {}: {}", arg, e)),
+        }
+    }
+
+    /// `:saveas <path>`: renames the buffer to `path` and writes it there.
+    fn save_as(&mut self, path: &str, force: bool) {
+        if path.is_empty() {
+            self.set_status_message("Argument required".to_string());
+            return;
+        }
+        if self.document().file_name.as_deref() == Some(path) {
+            self.write_command("", force);
+            return;
+        }
+        if !force && Path::new(path).exists() {
+            self.set_status_message("File exists (add ! to override)".to_string());
+            return;
+        }
+        self.write_as(path);
+    }
+
+    /// Writes the buffer to `path` and renames it to `path` if that worked.
+    fn write_as(&mut self, path: &str) {
+        match self.document_mut().save_as(path) {
+            Ok(()) => {
+                let msg = format!("Written {} bytes", self.document().size_bytes());
+                self.set_status_message(msg);
+            }
+            Err(e) => self.set_status_message(format!("Error writing {}: {}", path, e)),
+        }
+    }
+
+    /// `:e!`: throws away all changes and re-reads the file from disk.
+    fn reload_buffer(&mut self) {
+        match self.document_mut().reload() {
+            Ok(()) => {
+                let pos = self.cursor_position;
+                self.move_cursor_absolute(pos.x, pos.y);
+                let name = self.buffers[self.current_buffer].display_name();
+                let msg = match self.document_mut().undo_error.take() {
+                    Some(e) => e,
+                    None => format!("Reloaded {}", name),
+                };
+                self.set_status_message(msg);
+                self.prompt_recovery();
+            }
+            Err(e) => self.set_status_message(format!("Error: {}", e)),
+        }
+    }
+
+    /// `:set name=value ...`
+    fn set_options(&mut self, arg: &str) {
+        for option in arg.split_whitespace() {
+            let (name, value) = option.split_once('=').unwrap_or((option, ""));
+            match (name, value) {
+                ("fileformat" | "ff", "unix") => {
+                    self.document_mut().set_line_ending(LineEnding::Lf)
+                }
+                ("fileformat" | "ff", "dos") => {
+                    self.document_mut().set_line_ending(LineEnding::Crlf)
+                }
+                ("fileformat" | "ff", "") => {
+                    let format = match self.document().line_ending {
+                        LineEnding::Lf => "unix",
+                        LineEnding::Crlf => "dos",
+                    };
+                    self.set_status_message(format!("fileformat={}", format));
+                }
+                ("fileformat" | "ff", _) => {
+                    self.set_status_message(format!("Invalid argument: {}", option));
+                    return;
+                }
+                ("filetype" | "ft", "") => {
+                    let file_type = self.document().syntax.file_type.clone();
+                    self.set_status_message(format!("filetype={}", file_type));
+                }
+                ("filetype" | "ft", _) => match Syntax::find(value) {
+                    Some(syntax) => self.document_mut().set_syntax(syntax),
+                    None => {
+                        self.set_status_message(format!("Unknown filetype: {}", value));
+                        return;
+                    }
+                },
+                _ => {
+                    self.set_status_message(format!("Unknown option: {}", name));
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Quits unless a buffer has unsaved changes; `force` discards them.
+    fn quit(&mut self, force: bool) {
+        if !force {
+            let dirty = std::iter::once(self.current_buffer)
+                .chain(0..self.buffers.len())
+                .find(|&i| self.buffers[i].document.dirty);
+            if let Some(index) = dirty {
+                let msg = format!(
+                    "No write since last change for buffer \"{}\" (add ! to override)",
+                    self.buffers[index].display_name()
+                );
+                self.set_status_message(msg);
+                return;
+            }
+        }
+        self.should_quit = true;
+    }
+
+    fn edit_file(&mut self, filename: &str) {
+        if filename.is_empty() {
+            self.set_status_message("No file name".to_string());
+            return;
+        }
+        if let Some(index) = self
+            .buffers
+            .iter()
+            .position(|b| b.document.file_name.as_deref() == Some(filename))
+        {
+            self.switch_buffer(index);
+            return;
+        }
+        match Buffer::open(filename, self.config.editor.large_file_size()) {
+            Ok(mut buffer) => {
+                let undo_error = buffer.document.undo_error.take();
+                self.buffers.push(buffer);
+                self.switch_buffer(self.buffers.len() - 1);
+                if let Some(e) = undo_error {
+                    self.set_status_message(e);
+                }
+                self.prompt_recovery();
+            }
+            Err(e) => self.set_status_message(format!("Error opening {}: {}", filename, e)),
+        }
+    }
+
+    /// Parks the current view state in its buffer and restores the target's.
+    fn switch_buffer(&mut self, index: usize) {
+        if index == self.current_buffer {
+            return;
+        }
+        let buffer = &mut self.buffers[self.current_buffer];
+        buffer.cursor_position = self.cursor_position;
+        buffer.offset = self.offset;
+        buffer.selection_start = self.selection_start;
+
+        self.current_buffer = index;
+        self.load_buffer_view();
+    }
+
+    fn load_buffer_view(&mut self) {
+        let buffer = &self.buffers[self.current_buffer];
+        self.cursor_position = buffer.cursor_position;
+        self.offset = buffer.offset;
+        self.selection_start = buffer.selection_start;
+        self.completion_active = false;
+        // The document may have shrunk or the terminal been resized since
+        let pos = self.cursor_position;
+        self.move_cursor_absolute(pos.x, pos.y);
+    }
+
+    fn delete_buffer(&mut self, force: bool) {
+        if !force && self.document().dirty {
+            self.set_status_message(
+                "No write since last change for buffer (add ! to override)".to_string(),
+            );
+            return;
+        }
+        let removed = self.current_buffer;
+        self.buffers.remove(removed).document.remove_swap();
+        if self.buffers.is_empty() {
+            self.buffers.push(Buffer::default());
+        }
+        self.current_buffer = removed.min(self.buffers.len() - 1);
+
+        // Other windows showing the deleted buffer move on with this one
+        for (i, window) in self.windows.iter_mut().enumerate() {
+            if i == self.current_window {
+                continue;
+            }
+            if window.buffer == removed {
+                let buffer = &self.buffers[self.current_buffer];
+                window.buffer = self.current_buffer;
+                window.cursor_position = buffer.cursor_position;
+                window.offset = buffer.offset;
+                window.selection_start = None;
+            } else if window.buffer > removed {
+                window.buffer -= 1;
+            }
+        }
+        self.load_buffer_view();
+    }
+
+    fn list_buffers(&mut self) {
+        self.info_lines = self
+            .buffers
+            .iter()
+            .enumerate()
+            .map(|(i, buffer)| {
+                let (active, line) = if i == self.current_buffer {
+                    ("%a", self.cursor_position.y)
+                } else {
+                    ("  ", buffer.cursor_position.y)
+                };
+                let modified = if buffer.document.dirty { "+" } else { " " };
+                format!(
+                    "{:>3} {} {} \"{}\" line {}",
+                    i + 1,
+                    active,
+                    modified,
+                    buffer.display_name(),
+                    line + 1
+                )
+            })
+            .collect();
+    }
+
+    /// `:registers`: one line per register with its type (`c`, `l` or `b`)
+    /// and contents, line breaks shown as `^J`.
+    fn list_registers(&mut self) {
+        let registers = self.registers.list();
+        if registers.is_empty() {
+            self.set_status_message("All registers are empty".to_string());
+            return;
+        }
+        self.info_lines = std::iter::once("Type Name Content".to_string())
+            .chain(registers.into_iter().map(|(name, register)| {
+                let kind = match register.kind {
+                    RegisterKind::Chars => 'c',
+                    RegisterKind::Lines => 'l',
+                    RegisterKind::Block => 'b',
+                };
+                let content: String = register
+                    .text
+                    .chars()
+                    .flat_map(|c| match c {
+                        '\n' => vec!['^', 'J'],
+                        '\t' => vec!['^', 'I'],
+                        c if c.is_control() => vec!['?'],
+                        c => vec![c],
+                    })
+                    .take(60)
+                    .collect();
+                format!("  {}  \"{}   {}", kind, name, content)
+            }))
+            .collect();
+    }
+
+    /// Stores the live view state back into the focused window.
+    fn sync_window(&mut self) {
+        let window = &mut self.windows[self.current_window];
+        window.buffer = self.current_buffer;
+        window.cursor_position = self.cursor_position;
+        window.offset = self.offset;
+        window.selection_start = self.selection_start;
+    }
+
+    fn focus_window(&mut self, index: usize) {
+        self.sync_window();
+        self.current_window = index;
+        let window = self.windows[index];
+        self.current_buffer = window.buffer;
+        self.cursor_position = window.cursor_position;
+        self.offset = window.offset;
+        self.selection_start = window.selection_start;
+        self.completion_active = false;
+        // Other windows may have shortened the document meanwhile
+        self.move_cursor_absolute(window.cursor_position.x, window.cursor_position.y);
+    }
+
+    fn split_window(&mut self, direction: SplitDirection, filename: &str) {
+        self.sync_window();
+        let new = self.windows.len();
+        self.windows.push(self.windows[self.current_window]);
+        self.window_layout
+            .split(self.current_window, new, direction);
+        self.focus_window(new);
+        if !filename.is_empty() {
+            self.edit_file(filename);
+        }
+    }
+
+    fn close_window(&mut self) {
+        if self.windows.len() == 1 {
+            self.set_status_message("Cannot close last window".to_string());
+            return;
+        }
+        let closed = self.current_window;
+        self.windows.remove(closed);
+        self.window_layout.remove(closed);
+        // Load the new focus directly; the closed window's state is gone.
+        self.current_window = closed.saturating_sub(1);
+        let window = self.windows[self.current_window];
+        self.current_buffer = window.buffer;
+        self.selection_start = window.selection_start;
+        self.offset = window.offset;
+        self.move_cursor_absolute(window.cursor_position.x, window.cursor_position.y);
+    }
+
+    /// Moves focus to the nearest window in the given direction.
+    fn focus_neighbor(&mut self, dx: i32, dy: i32) {
+        let Ok(region) = self.text_region() else {
+            return;
+        };
+        let panes = self.panes(region);
+        let Some(current) = panes
+            .iter()
+            .find(|pane| pane.window == self.current_window)
+            .map(|pane| pane.area)
+        else {
+            return;
+        };
+
+        let overlaps = |a0: u16, a1: u16, b0: u16, b1: u16| a0 < b1 && b0 < a1;
+        let target = panes
+            .iter()
+            .filter(|pane| pane.window != self.current_window)
+            .filter_map(|pane| {
+                let r = pane.area;
+                let distance = match (dx, dy) {
+                    (-1, 0)
+                        if r.right() <= current.left()
+                            && overlaps(r.top(), r.bottom(), current.top(), current.bottom()) =>
+                    {
+                        current.left() - r.right()
+                    }
+                    (1, 0)
+                        if r.left() >= current.right()
+                            && overlaps(r.top(), r.bottom(), current.top(), current.bottom()) =>
+                    {
+                        r.left() - current.right()
+                    }
+                    (0, -1)
+                        if r.bottom() <= current.top()
+                            && overlaps(r.left(), r.right(), current.left(), current.right()) =>
+                    {
+                        current.top() - r.bottom()
+                    }
+                    (0, 1)
+                        if r.top() >= current.bottom()
+                            && overlaps(r.left(), r.right(), current.left(), current.right()) =>
+                    {
+                        r.top() - current.bottom()
+                    }
+                    _ => return None,
+                };
+                // Prefer the closest window, then the one best aligned with this one
+                let skew = if dx != 0 {
+                    r.top().abs_diff(current.top())
+                } else {
+                    r.left().abs_diff(current.left())
+                };
+                Some((distance, skew, pane.window))
+            })
+            .min()
+            .map(|(_, _, window)| window);
+
+        if let Some(window) = target {
+            self.focus_window(window);
+        }
+    }
+
+    fn process_window_key(&mut self, key: KeyEvent) {
+        match key.code {
+            KeyCode::Char('h') | KeyCode::Left => self.focus_neighbor(-1, 0),
+            KeyCode::Char('j') | KeyCode::Down => self.focus_neighbor(0, 1),
+            KeyCode::Char('k') | KeyCode::Up => self.focus_neighbor(0, -1),
+            KeyCode::Char('l') | KeyCode::Right => self.focus_neighbor(1, 0),
+            KeyCode::Char('w') => {
+                let next = (self.current_window + 1) % self.windows.len();
+                self.focus_window(next);
             }
-        } else if cmd == "wq" {
-            let _ = self.document.save();
-            self.should_quit = true;
+            KeyCode::Char('s') => self.split_window(SplitDirection::Horizontal, ""),
+            KeyCode::Char('v') => self.split_window(SplitDirection::Vertical, ""),
+            KeyCode::Char('c') | KeyCode::Char('q') => self.close_window(),
+            _ => {}
+        }
+    }
+
+    fn document(&self) -> &Document {
+        &self.buffers[self.current_buffer].document
+    }
+
+    fn document_mut(&mut self) -> &mut Document {
+        &mut self.buffers[self.current_buffer].document
+    }
+
+    fn insert_char(&mut self, c: char) {
+        let pos = self.cursor_position;
+        self.document_mut().insert(&pos, c);
+    }
+
+    /// Deletes the grapheme under the cursor (or the line break at the end of a line).
+    fn delete_char(&mut self) {
+        let pos = self.cursor_position;
+        let next = self
+            .document()
+            .row(pos.y)
+            .map_or(pos.x, |row| row.next_grapheme(pos.x));
+        if next > pos.x + 1 {
+            let end = Position {
+                x: next - 1,
+                y: pos.y,
+            };
+            self.document_mut().delete_range(&pos, &end);
+        } else {
+            self.document_mut().delete(&pos);
+        }
+    }
+
+    fn begin_undo_group(&mut self) {
+        let pos = self.cursor_position;
+        self.document_mut().begin_undo_group(pos);
+    }
+
+    fn end_undo_group(&mut self) {
+        let pos = self.cursor_position;
+        self.document_mut().end_undo_group(pos);
+    }
+
+    fn undo(&mut self) {
+        if let Some(pos) = self.document_mut().undo() {
+            self.move_cursor_absolute(pos.x, pos.y);
+        } else {
+            self.set_status_message("Already at oldest change".to_string());
+        }
+    }
+
+    fn redo(&mut self) {
+        if let Some(pos) = self.document_mut().redo() {
+            self.move_cursor_absolute(pos.x, pos.y);
         } else {
-            self.set_status_message(format!("Not an editor command: {}", cmd));
+            self.set_status_message("Already at newest change".to_string());
         }
     }
 
@@ -769,68 +2525,97 @@ impl Editor {
         self.status_time = Instant::now();
     }
 
+    /// Moves `dx` grapheme clusters sideways and `dy` lines up or down. Vertical
+    /// moves keep the cursor in the same display column where possible.
     fn move_cursor(&mut self, dx: i32, dy: i32) {
-        let x = self.cursor_position.x as i32 + dx;
-        let y = self.cursor_position.y as i32 + dy;
-        self.move_cursor_absolute(x.max(0) as usize, y.max(0) as usize);
+        let tab_size = self.config.editor.tab_size;
+        let Position { mut x, y } = self.cursor_position;
+        if let Some(row) = self.document().row(y) {
+            for _ in 0..dx.unsigned_abs() {
+                x = if dx > 0 {
+                    row.next_grapheme(x)
+                } else {
+                    row.prev_grapheme(x)
+                };
+            }
+        }
+
+        let new_y = (y as i64 + dy as i64).max(0) as usize;
+        if new_y != y {
+            let column = self.cursor_column();
+            x = self
+                .document()
+                .row(new_y)
+                .map_or(0, |row| row.index_at_column(column, tab_size));
+        }
+        self.move_cursor_absolute(x, new_y);
     }
 
+    /// Places the cursor at char index `x` of line `y`, clamped to the
+    /// document and snapped to the start of a grapheme cluster.
     fn move_cursor_absolute(&mut self, x: usize, y: usize) {
-        let height = self.document.len();
-        let mut y = y;
-        if y >= height {
-            y = height;
-        }
+        let height = self.document().len();
+        let y = y.min(height);
         self.cursor_position.y = y;
-
-        let row_len = if y < height {
-            self.document.row(y).unwrap().len()
-        } else {
-            0
+        self.cursor_position.x = match self.document().row(y) {
+            Some(row) => row.grapheme_start(x),
+            None => 0,
         };
-
-        let mut x = x;
-        if x > row_len {
-            x = row_len;
-        }
-        self.cursor_position.x = x;
         self.scroll();
     }
 
-    fn scroll(&mut self) {
-        let size = self.terminal.backend.size().unwrap();
-        let height = size.height as usize;
-        let mut width = size.width as usize;
+    /// Display column of the cursor within its line.
+    fn cursor_column(&self) -> usize {
+        let tab_size = self.config.editor.tab_size;
+        self.document()
+            .row(self.cursor_position.y)
+            .map_or(0, |row| row.column_of(self.cursor_position.x, tab_size))
+    }
 
-        if self.config.editor.line_numbers {
-            let digits = self.document.len().to_string().len();
-            width = width.saturating_sub(digits + 2);
-        }
+    /// Scrolls the focused window so the cursor is visible. `offset.y` is a
+    /// line number; `offset.x` is a display column, not a char index.
+    fn scroll(&mut self) {
+        let text = self
+            .focused_pane()
+            .map(|pane| pane.text)
+            .unwrap_or_default();
+        let height = text.height as usize;
+        let width = (text.width as usize).saturating_sub(self.gutter_width(self.document()));
 
         if self.cursor_position.y < self.offset.y {
             self.offset.y = self.cursor_position.y;
-        } else if self.cursor_position.y >= self.offset.y.saturating_add(height).saturating_sub(2) {
+        } else if self.cursor_position.y >= self.offset.y.saturating_add(height) {
             self.offset.y = self
                 .cursor_position
                 .y
                 .saturating_sub(height)
-                .saturating_add(2)
                 .saturating_add(1);
         }
 
-        if self.cursor_position.x < self.offset.x {
-            self.offset.x = self.cursor_position.x;
-        } else if self.cursor_position.x >= self.offset.x.saturating_add(width) {
-            self.offset.x = self
-                .cursor_position
-                .x
-                .saturating_sub(width)
-                .saturating_add(1);
+        // Keep the whole of a wide char or tab under the cursor in view
+        let tab_size = self.config.editor.tab_size;
+        let (column, cell_width) = match self.document().row(self.cursor_position.y) {
+            Some(row) => {
+                let column = row.column_of(self.cursor_position.x, tab_size);
+                let cell_width = row
+                    .graphemes()
+                    .find(|(index, _)| *index == self.cursor_position.x)
+                    .map_or(1, |(_, grapheme)| {
+                        row::grapheme_width(grapheme, column, tab_size)
+                    });
+                (column, cell_width)
+            }
+            None => (0, 1),
+        };
+        if column < self.offset.x {
+            self.offset.x = column;
+        } else if column + cell_width > self.offset.x.saturating_add(width) {
+            self.offset.x = (column + cell_width).saturating_sub(width);
         }
     }
 
     fn update_completion(&mut self) {
-        if let Some(row) = self.document.row(self.cursor_position.y) {
+        if let Some(row) = self.document().row(self.cursor_position.y) {
             let col = self.cursor_position.x;
             let chars: Vec<char> = row.content.chars().collect();
             if col > chars.len() {
@@ -848,21 +2633,22 @@ impl Editor {
             }
 
             let word: String = chars[start..col].iter().collect();
-            if word.len() < 1 {
+            if word.is_empty() {
                 self.completion_active = false;
                 return;
             }
 
             let mut options = Vec::new();
-            let syntax = &self.document.syntax;
+            let syntax = &self.document().syntax;
 
-            let mut candidates: Vec<&str> = Vec::new();
-            candidates.extend_from_slice(syntax.keywords);
-            candidates.extend_from_slice(syntax.types);
-            candidates.extend_from_slice(syntax.control_flow);
+            let candidates = syntax
+                .keywords
+                .iter()
+                .chain(&syntax.types)
+                .chain(&syntax.control_flow);
 
             for cand in candidates {
-                if cand.starts_with(&word) && cand != &word {
+                if cand.starts_with(&word) && *cand != word {
                     options.push(cand.to_string());
                 }
             }
@@ -887,7 +2673,7 @@ impl Editor {
 
         let completion = self.completion_list[self.completion_index].clone();
 
-        if let Some(row) = self.document.row(self.cursor_position.y) {
+        if let Some(row) = self.document().row(self.cursor_position.y) {
             let col = self.cursor_position.x;
             let chars: Vec<char> = row.content.chars().collect();
             let mut start = col;
@@ -897,11 +2683,11 @@ impl Editor {
 
             let end = self.cursor_position;
             let start_pos = Position { x: start, y: end.y };
-            self.document.delete_range(&start_pos, &end);
+            self.document_mut().delete_range(&start_pos, &end);
             self.cursor_position = start_pos;
 
             for c in completion.chars() {
-                self.document.insert(&self.cursor_position, c);
+                self.insert_char(c);
                 self.move_cursor(1, 0);
             }
         }
@@ -909,6 +2695,102 @@ impl Editor {
     }
 }
 
-fn die(e: io::Error) {
-    panic!("{}", e);
+/// "5 minutes ago" for a time in seconds since the Unix epoch.
+fn describe_age(time: u64) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let age = now.saturating_sub(time);
+    let (n, unit) = match age {
+        0..60 => (age, "second"),
+        60..3600 => (age / 60, "minute"),
+        3600..86400 => (age / 3600, "hour"),
+        _ => (age / 86400, "day"),
+    };
+    format!("{} {}{} ago", n, unit, if n == 1 { "" } else { "s" })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ropey::Rope;
+
+    /// Types `keys` (`\x1b` for Escape) into an editor holding `text` with the
+    /// cursor at `x, y`, and returns the text and the cursor afterwards.
+    fn type_keys(text: &str, (x, y): (usize, usize), keys: &str) -> (String, (usize, usize)) {
+        let mut document = Document::default();
+        document.content = Rope::from_str(text);
+        let terminal = Terminal::headless().unwrap();
+        let registers = Registers::without_clipboard();
+        let buffers = vec![Buffer::new(document)];
+        let mut editor = Editor::with_buffers(Config::default(), terminal, registers, buffers);
+        editor.cursor_position = Position { x, y };
+        for c in keys.chars() {
+            let code = match c {
+                '\x1b' => KeyCode::Esc,
+                c => KeyCode::Char(c),
+            };
+            editor.handle_key(KeyEvent::new(code, KeyModifiers::NONE));
+        }
+        let Position { x, y } = editor.cursor_position;
+        (editor.document().content.to_string(), (x, y))
+    }
+
+    #[test]
+    fn normal_mode_commands() {
+        #[rustfmt::skip]
+        let cases = [
+            // Counts
+            ("one two three four", (0, 0), "2w", "one two three four", (8, 0)),
+            ("one two three four", (0, 0), "2dw", "three four", (0, 0)),
+            ("one two three four", (0, 0), "d2w", "three four", (0, 0)),
+            ("one two three four", (0, 0), "2d2w", "", (0, 0)),
+            ("abcdef", (1, 0), "3x", "aef", (1, 0)),
+            // Charwise operators and motions. Leaving Insert mode doesn't
+            // move the cursor back onto the last char typed.
+            ("one two", (0, 0), "de", " two", (0, 0)),
+            ("one two", (4, 0), "db", "two", (0, 0)),
+            ("one two", (5, 0), "d0", "wo", (0, 0)),
+            ("one two", (1, 0), "d$", "o", (1, 0)),
+            ("one two", (0, 0), "cwnew\x1b", "new two", (3, 0)),
+            ("one two", (3, 0), "cwX\x1b", "oneXtwo", (4, 0)),
+            // `dw` on the last word of a line keeps the line break, unless a
+            // count takes it past the last word of the buffer
+            ("one two\nthree", (4, 0), "dw", "one \nthree", (4, 0)),
+            ("one two\n  three", (4, 0), "dw", "one \n  three", (4, 0)),
+            ("one two\nthree", (4, 0), "2dw", "one ", (4, 0)),
+            // Linewise operators and motions
+            ("a\nb\nc", (0, 1), "dd", "a\nc", (0, 1)),
+            ("a\nb\nc", (0, 2), "dd", "a\nb", (0, 1)),
+            ("a\nb\nc\nd", (0, 0), "2dd", "c\nd", (0, 0)),
+            ("a\nb\nc\nd", (0, 1), "dj", "a\nd", (0, 1)),
+            ("a\nb\nc\nd", (0, 2), "dk", "a\nd", (0, 1)),
+            ("a\nb\nc", (0, 2), "dj", "a\nb\nc", (0, 2)),
+            ("a\n  b\nc", (0, 1), "dG", "a", (0, 0)),
+            ("a\nb\nc", (0, 1), "dgg", "c", (0, 0)),
+            ("a\n  b\nc", (0, 1), "ccx\x1b", "a\nx\nc", (1, 1)),
+            ("a\nb", (0, 0), "yyp", "a\na\nb", (0, 1)),
+            ("a\nb", (0, 1), "yyP", "a\nb\nb", (0, 1)),
+            ("a\nb\nc", (0, 0), "yjGp", "a\nb\nc\na\nb", (0, 3)),
+            ("ab", (0, 0), "ylp", "aab", (1, 0)),
+            // `.` repeats the last change, with its own count if given
+            ("a b c d e", (0, 0), "dw.", "c d e", (0, 0)),
+            ("a b c d e", (0, 0), "dw3.", "e", (0, 0)),
+            ("a\nb\nc\nd", (0, 0), "dd.", "c\nd", (0, 0)),
+            ("one two", (0, 0), "cwX\x1bw.", "X X", (3, 0)),
+            ("ab", (0, 0), "ix\x1b.", "xxab", (2, 0)),
+            // Yanks aren't changes
+            ("a\nb", (0, 0), "yyj.", "a\nb", (0, 1)),
+        ];
+        for (text, cursor, keys, after, cursor_after) in cases {
+            assert_eq!(
+                type_keys(text, cursor, keys),
+                (after.to_string(), cursor_after),
+                "{:?} at {:?} after {:?}",
+                text,
+                cursor,
+                keys
+            );
+        }
+    }
 }
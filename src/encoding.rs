@@ -0,0 +1,326 @@
+use std::fmt;
+use std::io::{Error, ErrorKind};
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    Crlf,
+}
+
+impl LineEnding {
+    /// Picks the style used by the majority of lines in `text`.
+    pub fn detect(text: &str) -> Self {
+        let lf = text.matches('\n').count();
+        let crlf = text.matches("\r\n").count();
+        if crlf > 0 && crlf * 2 >= lf {
+            LineEnding::Crlf
+        } else {
+            LineEnding::Lf
+        }
+    }
+}
+
+impl fmt::Display for LineEnding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LineEnding::Lf => write!(f, "LF"),
+            LineEnding::Crlf => write!(f, "CRLF"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    Latin1,
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Encoding::Utf8 => write!(f, "UTF-8"),
+            Encoding::Utf16Le => write!(f, "UTF-16LE"),
+            Encoding::Utf16Be => write!(f, "UTF-16BE"),
+            Encoding::Latin1 => write!(f, "Latin-1"),
+        }
+    }
+}
+
+const UTF8_BOM: &[u8] = &[0xEF, 0xBB, 0xBF];
+const UTF16LE_BOM: &[u8] = &[0xFF, 0xFE];
+const UTF16BE_BOM: &[u8] = &[0xFE, 0xFF];
+
+impl Encoding {
+    pub fn bom(self) -> &'static [u8] {
+        match self {
+            Encoding::Utf8 => UTF8_BOM,
+            Encoding::Utf16Le => UTF16LE_BOM,
+            Encoding::Utf16Be => UTF16BE_BOM,
+            Encoding::Latin1 => &[],
+        }
+    }
+
+    /// Appends `text` in this encoding to `out`.
+    pub fn encode(self, text: &str, out: &mut Vec<u8>) -> Result<(), Error> {
+        match self {
+            Encoding::Utf8 => out.extend_from_slice(text.as_bytes()),
+            Encoding::Utf16Le => {
+                for unit in text.encode_utf16() {
+                    out.extend_from_slice(&unit.to_le_bytes());
+                }
+            }
+            Encoding::Utf16Be => {
+                for unit in text.encode_utf16() {
+                    out.extend_from_slice(&unit.to_be_bytes());
+                }
+            }
+            Encoding::Latin1 => {
+                for c in text.chars() {
+                    let byte = u8::try_from(u32::from(c)).map_err(|_| {
+                        Error::new(
+                            ErrorKind::InvalidData,
+                            format!("'{}' can't be written as Latin-1", c),
+                        )
+                    })?;
+                    out.push(byte);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Decodes a file chunk by chunk, so a large file is never in memory as both
+/// bytes and text. UTF-16 is only recognised by its BOM; a file without BOM
+/// is read as UTF-8, and if that fails, it has to be read again with a Latin-1
+/// decoder, which accepts anything.
+pub struct Decoder {
+    // `None` until enough bytes were seen to look for a BOM
+    encoding: Option<Encoding>,
+    bom: bool,
+    // Bytes of a char split across chunks
+    pending: Vec<u8>,
+}
+
+impl Decoder {
+    /// `latin1` skips the BOM check and reads the file as Latin-1.
+    pub fn new(latin1: bool) -> Self {
+        Self {
+            encoding: latin1.then_some(Encoding::Latin1),
+            bom: false,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding.unwrap_or_default()
+    }
+
+    pub fn has_bom(&self) -> bool {
+        self.bom
+    }
+
+    /// Appends the text of the next chunk of the file to `out`. Fails with
+    /// `InvalidData` when a file without BOM turns out not to be UTF-8.
+    pub fn decode(&mut self, bytes: &[u8], out: &mut String) -> Result<(), Error> {
+        self.pending.extend_from_slice(bytes);
+        if self.encoding.is_none() {
+            if self.pending.len() < UTF8_BOM.len() {
+                return Ok(());
+            }
+            self.detect();
+        }
+        self.decode_pending(out, false)
+    }
+
+    /// Decodes what is left at the end of the file.
+    pub fn finish(&mut self, out: &mut String) -> Result<(), Error> {
+        if self.encoding.is_none() {
+            self.detect();
+        }
+        self.decode_pending(out, true)
+    }
+
+    fn detect(&mut self) {
+        let boms = [
+            (Encoding::Utf8, UTF8_BOM),
+            (Encoding::Utf16Le, UTF16LE_BOM),
+            (Encoding::Utf16Be, UTF16BE_BOM),
+        ];
+        for (encoding, bom) in boms {
+            if self.pending.starts_with(bom) {
+                self.pending.drain(..bom.len());
+                self.encoding = Some(encoding);
+                self.bom = true;
+                return;
+            }
+        }
+        self.encoding = Some(Encoding::Utf8);
+    }
+
+    fn decode_pending(&mut self, out: &mut String, last: bool) -> Result<(), Error> {
+        let bytes = &self.pending;
+        let used = match self.encoding() {
+            Encoding::Utf8 => {
+                let mut used = 0;
+                loop {
+                    match std::str::from_utf8(&bytes[used..]) {
+                        Ok(text) => {
+                            out.push_str(text);
+                            used = bytes.len();
+                            break;
+                        }
+                        Err(e) => {
+                            let valid = used + e.valid_up_to();
+                            // Safe to unwrap: `from_utf8` checked these bytes
+                            out.push_str(std::str::from_utf8(&bytes[used..valid]).unwrap());
+                            used = valid;
+                            let invalid = match e.error_len() {
+                                Some(len) => len,
+                                // A char cut off by the end of the chunk
+                                None if !last => break,
+                                None => bytes.len() - used,
+                            };
+                            if !self.bom {
+                                return Err(Error::new(ErrorKind::InvalidData, "not UTF-8"));
+                            }
+                            out.push(char::REPLACEMENT_CHARACTER);
+                            used += invalid;
+                        }
+                    }
+                }
+                used
+            }
+            Encoding::Utf16Le | Encoding::Utf16Be => {
+                let big_endian = self.encoding() == Encoding::Utf16Be;
+                let mut units: Vec<u16> = bytes
+                    .chunks_exact(2)
+                    .map(|b| {
+                        if big_endian {
+                            u16::from_be_bytes([b[0], b[1]])
+                        } else {
+                            u16::from_le_bytes([b[0], b[1]])
+                        }
+                    })
+                    .collect();
+                // Keep a high surrogate for the low one in the next chunk
+                if !last && units.last().is_some_and(|u| (0xD800..0xDC00).contains(u)) {
+                    units.pop();
+                }
+                out.push_str(&decode_utf16(units.iter().copied()));
+                // A trailing odd byte is dropped at the end of the file
+                if last { bytes.len() } else { units.len() * 2 }
+            }
+            Encoding::Latin1 => {
+                out.extend(bytes.iter().copied().map(char::from));
+                bytes.len()
+            }
+        };
+        self.pending.drain(..used);
+        Ok(())
+    }
+}
+
+fn decode_utf16(units: impl Iterator<Item = u16>) -> String {
+    char::decode_utf16(units)
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "héllo 日本\r\n😀 world\n";
+
+    /// Decodes `bytes` fed to the decoder `size` bytes at a time.
+    fn decode(bytes: &[u8], size: usize, latin1: bool) -> Result<(String, Encoding, bool), Error> {
+        let mut decoder = Decoder::new(latin1);
+        let mut out = String::new();
+        for chunk in bytes.chunks(size) {
+            decoder.decode(chunk, &mut out)?;
+        }
+        decoder.finish(&mut out)?;
+        Ok((out, decoder.encoding(), decoder.has_bom()))
+    }
+
+    fn encode(encoding: Encoding, with_bom: bool) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        if with_bom {
+            bytes.extend_from_slice(encoding.bom());
+        }
+        encoding.encode(TEXT, &mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn utf8_round_trip_in_any_chunk_size() {
+        let bytes = encode(Encoding::Utf8, false);
+        for size in 1..=bytes.len() {
+            let decoded = decode(&bytes, size, false).unwrap();
+            assert_eq!(decoded, (TEXT.to_string(), Encoding::Utf8, false));
+        }
+    }
+
+    #[test]
+    fn utf8_bom_is_dropped_and_remembered() {
+        let bytes = encode(Encoding::Utf8, true);
+        for size in 1..=bytes.len() {
+            let decoded = decode(&bytes, size, false).unwrap();
+            assert_eq!(decoded, (TEXT.to_string(), Encoding::Utf8, true));
+        }
+    }
+
+    #[test]
+    fn utf16_with_bom_round_trips_in_any_chunk_size() {
+        for encoding in [Encoding::Utf16Le, Encoding::Utf16Be] {
+            let bytes = encode(encoding, true);
+            // Odd sizes split code units, and some chunks end between the
+            // two halves of the emoji's surrogate pair
+            for size in 1..=bytes.len() {
+                let decoded = decode(&bytes, size, false).unwrap();
+                assert_eq!(decoded, (TEXT.to_string(), encoding, true));
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_utf8_without_bom_needs_latin1() {
+        let bytes = b"caf\xe9\r\n";
+        let err = decode(bytes, 2, false).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        let decoded = decode(bytes, 2, true).unwrap();
+        assert_eq!(decoded, ("café\r\n".to_string(), Encoding::Latin1, false));
+    }
+
+    #[test]
+    fn char_cut_off_at_end_of_file() {
+        // The first two bytes of "日"
+        let bytes = b"ab\xe6\x97";
+        assert!(decode(bytes, 3, false).is_err());
+        let mut with_bom = UTF8_BOM.to_vec();
+        with_bom.extend_from_slice(bytes);
+        let (text, _, _) = decode(&with_bom, 3, false).unwrap();
+        assert_eq!(text, "ab\u{FFFD}");
+    }
+
+    #[test]
+    fn latin1_rejects_chars_above_ff() {
+        let mut out = Vec::new();
+        Encoding::Latin1.encode("ÿ", &mut out).unwrap();
+        assert_eq!(out, [0xFF]);
+        assert!(Encoding::Latin1.encode("日", &mut out).is_err());
+    }
+
+    #[test]
+    fn line_ending_follows_the_majority() {
+        assert_eq!(LineEnding::detect("a\r\nb\r\n"), LineEnding::Crlf);
+        assert_eq!(LineEnding::detect("a\r\nb\nc\r\n"), LineEnding::Crlf);
+        assert_eq!(LineEnding::detect("a\r\nb\nc\n"), LineEnding::Lf);
+        assert_eq!(LineEnding::detect("no line break"), LineEnding::Lf);
+    }
+}
@@ -0,0 +1,205 @@
+use crate::row::HighlightType;
+use ropey::Rope;
+use streaming_iterator::StreamingIterator;
+use tree_sitter::{InputEdit, Language, Node, Parser, Point, Query, QueryCursor, Tree};
+
+const CONTROL_FLOW_KINDS: &[&str] = &[
+    "if", "else", "match", "for", "while", "loop", "return", "break", "continue", "await", "yield",
+];
+
+/// Incremental tree-sitter highlighter over a whole `Rope`.
+///
+/// Edits are fed in through `edit` as they happen; the tree is only re-parsed
+/// (reusing the old tree) the next time `parse` is called before a redraw.
+pub struct Highlighter {
+    parser: Parser,
+    query: Query,
+    capture_types: Vec<HighlightType>,
+    tree: Option<Tree>,
+    stale: bool,
+}
+
+impl Highlighter {
+    pub fn for_file_type(file_type: &str) -> Option<Self> {
+        match file_type {
+            "Rust" => Self::new(
+                tree_sitter_rust::LANGUAGE.into(),
+                tree_sitter_rust::HIGHLIGHTS_QUERY,
+            ),
+            _ => None,
+        }
+    }
+
+    fn new(language: Language, highlights: &str) -> Option<Self> {
+        let mut parser = Parser::new();
+        parser.set_language(&language).ok()?;
+        let query = Query::new(&language, highlights).ok()?;
+        let capture_types = query
+            .capture_names()
+            .iter()
+            .map(|name| capture_type(name))
+            .collect();
+
+        Some(Self {
+            parser,
+            query,
+            capture_types,
+            tree: None,
+            stale: true,
+        })
+    }
+
+    /// Keeps the previous tree in sync with a change to the text.
+    pub fn edit(&mut self, edit: &InputEdit) {
+        if let Some(tree) = &mut self.tree {
+            tree.edit(edit);
+        }
+        self.stale = true;
+    }
+
+    pub fn parse(&mut self, rope: &Rope) {
+        if !self.stale {
+            return;
+        }
+        let len = rope.len_bytes();
+        let mut read = |byte: usize, _: Point| -> &[u8] {
+            if byte >= len {
+                return &[];
+            }
+            let (chunk, chunk_byte_idx, _, _) = rope.chunk_at_byte(byte);
+            &chunk.as_bytes()[byte - chunk_byte_idx..]
+        };
+        self.tree = self
+            .parser
+            .parse_with_options(&mut read, self.tree.as_ref(), None);
+        self.stale = false;
+    }
+
+    /// Whether the tree is up to date with the rope, so `highlight_line`
+    /// has something to show.
+    pub fn is_ready(&self) -> bool {
+        !self.stale
+    }
+
+    /// Fills `highlighting` (indexed by char within the line) for line `line_idx`.
+    /// Does nothing while the tree is out of date with the rope.
+    pub fn highlight_line(&self, rope: &Rope, line_idx: usize, highlighting: &mut [HighlightType]) {
+        let Some(tree) = &self.tree else {
+            return;
+        };
+        if self.stale || line_idx >= rope.len_lines() {
+            return;
+        }
+
+        let line_start = rope.line_to_byte(line_idx);
+        let line_end = line_start + rope.line(line_idx).len_bytes();
+        let line_start_char = rope.byte_to_char(line_start);
+
+        let mut cursor = QueryCursor::new();
+        cursor.set_byte_range(line_start..line_end);
+        let text = |node: Node| {
+            rope.byte_slice(node.byte_range())
+                .chunks()
+                .map(str::as_bytes)
+        };
+        let mut captures = cursor.captures(&self.query, tree.root_node(), text);
+
+        // A node can be matched by several patterns; the first one wins,
+        // except that doc comments are only told apart from other comments
+        // by a later pattern.
+        let mut last_node = None;
+        while let Some((query_match, capture_index)) = captures.next() {
+            let capture = query_match.captures[*capture_index];
+            let mut node = capture.node;
+            let mut highlight = self.capture_types[capture.index as usize];
+            if last_node == Some(node.id()) && highlight != HighlightType::DocComment {
+                continue;
+            }
+            last_node = Some(node.id());
+
+            if highlight == HighlightType::None {
+                continue;
+            }
+            if highlight == HighlightType::Keyword && CONTROL_FLOW_KINDS.contains(&node.kind()) {
+                highlight = HighlightType::ControlFlow;
+            }
+            if highlight == HighlightType::Lifetime
+                && let Some(parent) = node.parent()
+                && parent.kind() == "lifetime"
+            {
+                node = parent;
+            }
+
+            let start = node.start_byte().max(line_start);
+            let end = node.end_byte().min(line_end);
+            if start >= end {
+                continue;
+            }
+            let start = rope.byte_to_char(start) - line_start_char;
+            let end = (rope.byte_to_char(end) - line_start_char).min(highlighting.len());
+            for slot in highlighting.iter_mut().take(end).skip(start) {
+                *slot = highlight;
+            }
+        }
+    }
+}
+
+fn capture_type(name: &str) -> HighlightType {
+    match name {
+        "function.macro" => return HighlightType::Macro,
+        "comment.documentation" => return HighlightType::DocComment,
+        // Literals like `1` and `true`
+        "constant.builtin" => return HighlightType::Number,
+        _ => {}
+    }
+    match name.split('.').next().unwrap_or(name) {
+        "keyword" => HighlightType::Keyword,
+        "type" | "constructor" => HighlightType::Type,
+        "string" => HighlightType::String,
+        "escape" => HighlightType::Escape,
+        "comment" => HighlightType::Comment,
+        "constant" => HighlightType::Constant,
+        "function" => HighlightType::Function,
+        "label" => HighlightType::Lifetime,
+        "attribute" => HighlightType::Attribute,
+        "operator" => HighlightType::Operator,
+        "punctuation" => HighlightType::Punctuation,
+        _ => HighlightType::None,
+    }
+}
+
+/// Builds the tree-sitter edit for inserting `new_len` bytes at `start_byte`
+/// or removing `old_len` bytes from it. Must be called before the rope changes.
+pub fn input_edit(rope: &Rope, start_byte: usize, old_len: usize, new_text: &str) -> InputEdit {
+    let start_position = byte_to_point(rope, start_byte);
+    let old_end_byte = start_byte + old_len;
+    let old_end_position = byte_to_point(rope, old_end_byte);
+    let new_end_byte = start_byte + new_text.len();
+
+    let mut new_end_position = start_position;
+    for (i, line) in new_text.split('\n').enumerate() {
+        if i == 0 {
+            new_end_position.column += line.len();
+        } else {
+            new_end_position.row += 1;
+            new_end_position.column = line.len();
+        }
+    }
+
+    InputEdit {
+        start_byte,
+        old_end_byte,
+        new_end_byte,
+        start_position,
+        old_end_position,
+        new_end_position,
+    }
+}
+
+fn byte_to_point(rope: &Rope, byte: usize) -> Point {
+    let row = rope.byte_to_line(byte);
+    Point {
+        row,
+        column: byte - rope.line_to_byte(row),
+    }
+}
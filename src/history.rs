@@ -0,0 +1,372 @@
+use crate::editor::Position;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+/// A single primitive change to the rope, addressed by absolute char index.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum Edit {
+    Insert { at: usize, text: String },
+    Delete { at: usize, text: String },
+}
+
+/// A group of edits that is undone and redone as one step.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Transaction {
+    pub edits: Vec<Edit>,
+    pub cursor_before: Position,
+    pub cursor_after: Position,
+}
+
+impl Transaction {
+    fn new(cursor: Position) -> Self {
+        Self {
+            edits: Vec::new(),
+            cursor_before: cursor,
+            cursor_after: cursor,
+        }
+    }
+
+    fn push(&mut self, edit: Edit) {
+        // Coalesce runs of typing / backspacing so a long Insert session
+        // doesn't keep one entry per keystroke.
+        match (self.edits.last_mut(), edit) {
+            (
+                Some(Edit::Insert { at, text }),
+                Edit::Insert {
+                    at: new_at,
+                    text: new_text,
+                },
+            ) if *at + text.chars().count() == new_at => {
+                text.push_str(&new_text);
+            }
+            (
+                Some(Edit::Delete { at, text }),
+                Edit::Delete {
+                    at: new_at,
+                    text: new_text,
+                },
+            ) if new_at + new_text.chars().count() == *at => {
+                text.insert_str(0, &new_text);
+                *at = new_at;
+            }
+            (
+                Some(Edit::Delete { at, text }),
+                Edit::Delete {
+                    at: new_at,
+                    text: new_text,
+                },
+            ) if new_at == *at => {
+                text.push_str(&new_text);
+            }
+            (_, edit) => self.edits.push(edit),
+        }
+    }
+}
+
+/// Undo/redo log for a `Document`.
+///
+/// Edits recorded while a group is open (see `begin`/`commit`) are merged into
+/// a single transaction; edits recorded outside a group become a transaction
+/// of their own.
+pub struct History {
+    undo_stack: Vec<Transaction>,
+    redo_stack: Vec<Transaction>,
+    pending: Option<Transaction>,
+    // Length of `undo_stack` when the text last matched the file; `None`
+    // once no amount of undo or redo leads back there
+    saved: Option<usize>,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self {
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
+            pending: None,
+            saved: Some(0),
+        }
+    }
+}
+
+impl History {
+    pub fn begin(&mut self, cursor: Position) {
+        if self.pending.is_none() {
+            self.pending = Some(Transaction::new(cursor));
+        }
+    }
+
+    pub fn commit(&mut self, cursor: Position) {
+        if let Some(mut transaction) = self.pending.take()
+            && !transaction.edits.is_empty()
+        {
+            transaction.cursor_after = cursor;
+            self.undo_stack.push(transaction);
+        }
+    }
+
+    pub fn record(&mut self, edit: Edit, cursor: Position) {
+        if self
+            .saved
+            .is_some_and(|saved| saved > self.undo_stack.len())
+        {
+            self.saved = None;
+        }
+        self.redo_stack.clear();
+        match &mut self.pending {
+            Some(transaction) => transaction.push(edit),
+            None => {
+                let mut transaction = Transaction::new(cursor);
+                transaction.push(edit);
+                self.undo_stack.push(transaction);
+            }
+        }
+    }
+
+    pub fn undo(&mut self) -> Option<Transaction> {
+        let transaction = self.undo_stack.pop()?;
+        self.redo_stack.push(transaction.clone());
+        Some(transaction)
+    }
+
+    pub fn redo(&mut self) -> Option<Transaction> {
+        let transaction = self.redo_stack.pop()?;
+        self.undo_stack.push(transaction.clone());
+        Some(transaction)
+    }
+
+    /// Remembers the current step as the one the file on disk has. A group
+    /// still open counts as a finished step.
+    pub fn mark_saved(&mut self) {
+        self.saved = Some(self.undo_stack.len() + usize::from(self.has_pending_edits()));
+    }
+
+    /// For changes the history doesn't record, which no undo step reverts.
+    pub fn mark_unsaved(&mut self) {
+        self.saved = None;
+    }
+
+    /// Whether the text is back at the step `mark_saved` remembered.
+    pub fn is_saved(&self) -> bool {
+        !self.has_pending_edits() && self.saved == Some(self.undo_stack.len())
+    }
+
+    fn has_pending_edits(&self) -> bool {
+        self.pending.as_ref().is_some_and(|t| !t.edits.is_empty())
+    }
+}
+
+/// Bumped whenever the layout of undo files changes.
+const UNDO_FILE_VERSION: u32 = 1;
+/// Only this many of the most recent steps, and of the steps that can be
+/// redone, are written to an undo file.
+const MAX_SAVED_TRANSACTIONS: usize = 1000;
+
+/// A `History` as written to disk by `History::save`.
+#[derive(Serialize, Deserialize)]
+struct UndoFile {
+    version: u32,
+    /// `content_hash` of the text the history leads up to. The history only
+    /// applies to a file that still has exactly that text.
+    content_hash: u64,
+    undo_stack: Vec<Transaction>,
+    redo_stack: Vec<Transaction>,
+}
+
+impl History {
+    /// Writes the undo and redo stacks to `path`, for a document whose text
+    /// hashes to `content_hash`. A group still open counts as a finished step.
+    pub fn save(&self, path: &Path, content_hash: u64) -> Result<(), Error> {
+        let mut undo_stack = self.undo_stack.clone();
+        if let Some(pending) = &self.pending
+            && !pending.edits.is_empty()
+        {
+            undo_stack.push(pending.clone());
+        }
+        let skip = undo_stack.len().saturating_sub(MAX_SAVED_TRANSACTIONS);
+        // The next step to redo is at the end of the stack
+        let redo_skip = self.redo_stack.len().saturating_sub(MAX_SAVED_TRANSACTIONS);
+        let file = UndoFile {
+            version: UNDO_FILE_VERSION,
+            content_hash,
+            undo_stack: undo_stack.split_off(skip),
+            redo_stack: self.redo_stack[redo_skip..].to_vec(),
+        };
+        let json = serde_json::to_vec(&file).map_err(Error::other)?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // Write to a temporary file first so a crash can't leave half a history
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, json)?;
+        fs::rename(&tmp_path, path)
+    }
+
+    /// Reads a history written by `save`. Fails with `NotFound` if there is
+    /// none, and with `InvalidData` if it is corrupt or was saved for
+    /// different text than `content_hash` describes.
+    pub fn load(path: &Path, content_hash: u64) -> Result<Self, Error> {
+        let json = fs::read(path)?;
+        let file: UndoFile =
+            serde_json::from_slice(&json).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        if file.version != UNDO_FILE_VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported version {}", file.version),
+            ));
+        }
+        if file.content_hash != content_hash {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "the file was changed outside the editor",
+            ));
+        }
+        Ok(Self {
+            saved: Some(file.undo_stack.len()),
+            undo_stack: file.undo_stack,
+            redo_stack: file.redo_stack,
+            pending: None,
+        })
+    }
+}
+
+/// Where the undo history of `file` is kept: `~/.local/state/meow/undo/`,
+/// see `config::state_file`.
+pub fn undo_file_path(file: &Path) -> Option<PathBuf> {
+    crate::config::state_file("undo", file)
+}
+
+pub const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// 64-bit FNV-1a over `bytes`, continuing from `hash`. Start from
+/// `FNV_OFFSET_BASIS`.
+pub fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for &byte in bytes {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("meow-history-{}-{}", std::process::id(), name))
+    }
+
+    fn insert(history: &mut History, at: usize, text: &str) {
+        let edit = Edit::Insert {
+            at,
+            text: text.to_string(),
+        };
+        history.record(edit, Position::default());
+    }
+
+    fn inserted_text(transaction: &Transaction) -> &str {
+        match &transaction.edits[..] {
+            [Edit::Insert { text, .. }] => text,
+            _ => panic!("expected a single insert"),
+        }
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let path = temp_path("round-trip");
+        let mut history = History::default();
+        insert(&mut history, 0, "a");
+        insert(&mut history, 1, "b");
+        insert(&mut history, 2, "c");
+        history.undo();
+        history.save(&path, 42).unwrap();
+
+        let mut loaded = History::load(&path, 42).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(loaded.is_saved());
+        assert_eq!(inserted_text(&loaded.redo().unwrap()), "c");
+        assert!(loaded.redo().is_none());
+        assert_eq!(inserted_text(&loaded.undo().unwrap()), "c");
+        assert_eq!(inserted_text(&loaded.undo().unwrap()), "b");
+        assert_eq!(inserted_text(&loaded.undo().unwrap()), "a");
+        assert!(loaded.undo().is_none());
+    }
+
+    #[test]
+    fn redo_stack_after_load_is_cleared_by_a_new_edit() {
+        let path = temp_path("redo");
+        let mut history = History::default();
+        insert(&mut history, 0, "a");
+        history.undo();
+        history.save(&path, 1).unwrap();
+
+        let mut loaded = History::load(&path, 1).unwrap();
+        fs::remove_file(&path).unwrap();
+        insert(&mut loaded, 0, "b");
+        assert!(loaded.redo().is_none());
+        assert_eq!(inserted_text(&loaded.undo().unwrap()), "b");
+    }
+
+    #[test]
+    fn open_group_is_saved_as_a_step() {
+        let path = temp_path("pending");
+        let mut history = History::default();
+        history.begin(Position::default());
+        insert(&mut history, 0, "a");
+        insert(&mut history, 1, "b");
+        history.save(&path, 1).unwrap();
+
+        let mut loaded = History::load(&path, 1).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(inserted_text(&loaded.undo().unwrap()), "ab");
+    }
+
+    #[test]
+    fn load_rejects_other_text_and_versions() {
+        let path = temp_path("reject");
+        assert_eq!(
+            History::load(&path, 1).err().unwrap().kind(),
+            ErrorKind::NotFound
+        );
+
+        let mut history = History::default();
+        insert(&mut history, 0, "a");
+        history.save(&path, 1).unwrap();
+        let err = History::load(&path, 2).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        let json = r#"{"version":99,"content_hash":1,"undo_stack":[],"redo_stack":[]}"#;
+        fs::write(&path, json).unwrap();
+        let err = History::load(&path, 1).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        fs::write(&path, "not json").unwrap();
+        let err = History::load(&path, 1).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn save_keeps_the_most_recent_steps() {
+        let path = temp_path("cap");
+        let mut history = History::default();
+        let total = MAX_SAVED_TRANSACTIONS + 5;
+        for i in 0..total {
+            insert(&mut history, i, &i.to_string());
+        }
+        for _ in 0..total - 3 {
+            history.undo();
+        }
+        history.save(&path, 1).unwrap();
+
+        let mut loaded = History::load(&path, 1).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.undo_stack.len(), 3);
+        assert_eq!(loaded.redo_stack.len(), MAX_SAVED_TRANSACTIONS);
+        // The steps nearest the current text are kept
+        assert_eq!(inserted_text(&loaded.redo().unwrap()), "3");
+        assert_eq!(inserted_text(&loaded.undo().unwrap()), "3");
+        assert_eq!(inserted_text(&loaded.undo().unwrap()), "2");
+    }
+}
@@ -0,0 +1,218 @@
+use crate::encoding::{Decoder, Encoding, LineEnding};
+use crate::history;
+use ropey::{Rope, RopeBuilder};
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read};
+
+/// How much of the file is read and decoded at a time.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A file read into a rope, with what is needed to write it back the same way.
+pub struct Loaded {
+    /// The text, always with `\n` line breaks.
+    pub content: Rope,
+    pub encoding: Encoding,
+    pub bom: bool,
+    pub line_ending: LineEnding,
+    /// FNV-1a hash of the bytes on disk.
+    pub hash: u64,
+}
+
+/// Streams the file at `path` into a rope without holding all of its bytes
+/// or text in memory at once.
+///
+/// CRLF line breaks are converted when most lines use them. Whether they do
+/// is guessed from the first chunk; in the rare file where the guess turns out
+/// wrong, the file is read a second time. So is a file without BOM that isn't
+/// UTF-8 after all, this time as Latin-1.
+pub fn load(path: &str) -> Result<Loaded, Error> {
+    let mut latin1 = false;
+    let mut crlf = None;
+    loop {
+        match load_pass(path, latin1, crlf) {
+            Err(e) if e.kind() == ErrorKind::InvalidData && !latin1 => latin1 = true,
+            Ok((_, guessed, actual)) if guessed != actual => crlf = Some(actual),
+            result => return result.map(|(loaded, _, _)| loaded),
+        }
+    }
+}
+
+/// One read of the file. Returns whether CRLF line breaks were converted and
+/// whether they should have been.
+fn load_pass(path: &str, latin1: bool, crlf: Option<bool>) -> Result<(Loaded, bool, bool), Error> {
+    let mut file = File::open(path)?;
+    let mut decoder = Decoder::new(latin1);
+    let mut sink = Sink {
+        builder: RopeBuilder::new(),
+        crlf,
+        lf_count: 0,
+        crlf_count: 0,
+    };
+    let mut hash = history::FNV_OFFSET_BASIS;
+    let mut buf = vec![0; CHUNK_SIZE];
+    // Decoded text not yet in the rope: whatever follows the last line break,
+    // so a CRLF is never split between two pieces
+    let mut text = String::new();
+    loop {
+        let n = match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        hash = history::fnv1a(hash, &buf[..n]);
+        decoder.decode(&buf[..n], &mut text)?;
+        if let Some(end) = text.rfind('\n') {
+            let rest = text.split_off(end + 1);
+            sink.add(&text);
+            text = rest;
+        }
+    }
+    decoder.finish(&mut text)?;
+    sink.add(&text);
+
+    let converted = sink.crlf.unwrap_or(false);
+    let actual = sink.crlf_count > 0 && sink.crlf_count * 2 >= sink.lf_count;
+    let loaded = Loaded {
+        content: sink.builder.finish(),
+        encoding: decoder.encoding(),
+        bom: decoder.has_bom(),
+        line_ending: if converted {
+            LineEnding::Crlf
+        } else {
+            LineEnding::Lf
+        },
+        hash,
+    };
+    Ok((loaded, converted, actual))
+}
+
+/// FNV-1a hash of the file's bytes, read a chunk at a time.
+pub fn hash_file(path: &str) -> Result<u64, Error> {
+    let mut file = File::open(path)?;
+    let mut hash = history::FNV_OFFSET_BASIS;
+    let mut buf = vec![0; CHUNK_SIZE];
+    loop {
+        match file.read(&mut buf) {
+            Ok(0) => return Ok(hash),
+            Ok(n) => hash = history::fnv1a(hash, &buf[..n]),
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Collects decoded text in the rope, counting line breaks as it goes.
+struct Sink {
+    builder: RopeBuilder,
+    // Whether CRLFs are converted; decided by the first piece unless given
+    crlf: Option<bool>,
+    lf_count: usize,
+    crlf_count: usize,
+}
+
+impl Sink {
+    fn add(&mut self, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        self.lf_count += text.matches('\n').count();
+        self.crlf_count += text.matches("\r\n").count();
+        let crlf = *self
+            .crlf
+            .get_or_insert_with(|| LineEnding::detect(text) == LineEnding::Crlf);
+        if crlf {
+            self.builder.append(&text.replace("\r\n", "\n"));
+        } else {
+            self.builder.append(text);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Writes `bytes` to a temporary file and loads it.
+    fn load_bytes(name: &str, bytes: &[u8]) -> Loaded {
+        let path =
+            std::env::temp_dir().join(format!("meow-loader-{}-{}", std::process::id(), name));
+        fs::write(&path, bytes).unwrap();
+        let loaded = load(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+        assert_eq!(
+            loaded.hash,
+            history::fnv1a(history::FNV_OFFSET_BASIS, bytes)
+        );
+        loaded
+    }
+
+    #[test]
+    fn crlf_file_is_converted() {
+        let loaded = load_bytes("crlf", b"a\r\nb\r\n");
+        assert_eq!(loaded.content.to_string(), "a\nb\n");
+        assert_eq!(loaded.line_ending, LineEnding::Crlf);
+        assert_eq!(loaded.encoding, Encoding::Utf8);
+    }
+
+    #[test]
+    fn mixed_line_endings() {
+        // Mostly CRLF: the lone LF stays a line break
+        let loaded = load_bytes("mostly-crlf", b"a\r\nb\nc\r\n");
+        assert_eq!(loaded.content.to_string(), "a\nb\nc\n");
+        assert_eq!(loaded.line_ending, LineEnding::Crlf);
+        // Mostly LF: the CR is kept as part of the text
+        let loaded = load_bytes("mostly-lf", b"a\nb\nc\r\n");
+        assert_eq!(loaded.content.to_string(), "a\nb\nc\r\n");
+        assert_eq!(loaded.line_ending, LineEnding::Lf);
+    }
+
+    #[test]
+    fn utf16_with_bom() {
+        let text = "日本\r\n😀\r\n";
+        for encoding in [Encoding::Utf16Le, Encoding::Utf16Be] {
+            let mut bytes = encoding.bom().to_vec();
+            encoding.encode(text, &mut bytes).unwrap();
+            let loaded = load_bytes("utf16", &bytes);
+            assert_eq!(loaded.content.to_string(), "日本\n😀\n");
+            assert_eq!(loaded.encoding, encoding);
+            assert!(loaded.bom);
+            assert_eq!(loaded.line_ending, LineEnding::Crlf);
+        }
+    }
+
+    #[test]
+    fn falls_back_to_latin1_after_a_chunk_of_utf8() {
+        // The first chunk ends in a byte that could start a UTF-8 char; only
+        // the next chunk shows it doesn't
+        let mut bytes = b"x\n".repeat(CHUNK_SIZE / 2 - 1);
+        bytes.extend_from_slice(b"a\xe9");
+        bytes.extend_from_slice(b"t\n");
+        assert_eq!(bytes[CHUNK_SIZE - 1], 0xE9);
+        let loaded = load_bytes("latin1", &bytes);
+        assert_eq!(loaded.encoding, Encoding::Latin1);
+        assert!(!loaded.bom);
+        let expected: String = bytes.iter().copied().map(char::from).collect();
+        assert_eq!(loaded.content.to_string(), expected);
+    }
+
+    #[test]
+    fn second_pass_when_the_line_ending_guess_was_wrong() {
+        // The first chunk only has LF line breaks, the rest of the file is
+        // mostly CRLF. "é" is split across the first two chunks, and the
+        // second chunk ends between a CR and its LF.
+        let mut bytes = b"x\n".repeat(CHUNK_SIZE / 2 - 1);
+        bytes.push(b'a');
+        bytes.extend_from_slice("é".as_bytes());
+        assert_eq!(bytes.len(), CHUNK_SIZE + 1);
+        bytes.resize(2 * CHUNK_SIZE - 1, b'y');
+        bytes.extend_from_slice(b"\r\n");
+        bytes.extend_from_slice(&b"z\r\n".repeat(CHUNK_SIZE / 2));
+        let loaded = load_bytes("guess", &bytes);
+        assert_eq!(loaded.line_ending, LineEnding::Crlf);
+        let expected = String::from_utf8(bytes).unwrap().replace("\r\n", "\n");
+        assert_eq!(loaded.content.to_string(), expected);
+    }
+}
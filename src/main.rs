@@ -1,16 +1,31 @@
+mod buffer;
 mod config;
+mod crash;
 mod document;
 mod editor;
+mod encoding;
+mod highlighter;
+mod history;
+mod loader;
+mod motion;
+mod normal;
+mod register;
 mod row;
+mod search;
+mod selection;
+mod swap;
 mod syntax;
 mod terminal;
+mod textobject;
 mod theme;
+mod window;
 
 use config::Config;
 use editor::Editor;
 
-fn main() {
+fn main() -> anyhow::Result<()> {
+    crash::install_panic_hook();
     let config = Config::load();
-    let mut editor = Editor::new(config);
-    editor.run();
+    let mut editor = Editor::new(config)?;
+    editor.run()
 }
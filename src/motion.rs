@@ -0,0 +1,392 @@
+use crate::document::Document;
+use crate::editor::Position;
+
+/// A cursor movement, usable on its own or as the target of an operator.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Motion {
+    Left,
+    Right,
+    Up,
+    Down,
+    /// `w` / `W`: start of the next word (or WORD).
+    WordForward {
+        big: bool,
+    },
+    /// `b` / `B`: start of the previous word.
+    WordBackward {
+        big: bool,
+    },
+    /// `e` / `E`: end of the current or next word.
+    WordEnd {
+        big: bool,
+    },
+    /// `0`
+    LineStart,
+    /// `^`
+    FirstNonBlank,
+    /// `$`
+    LineEnd,
+    /// `gg`, or line N with a count.
+    FirstLine,
+    /// `G`, or line N with a count.
+    LastLine,
+    /// `}`
+    ParagraphForward,
+    /// `{`
+    ParagraphBackward,
+}
+
+impl Motion {
+    pub fn from_key(c: char) -> Option<Self> {
+        let motion = match c {
+            'h' => Motion::Left,
+            'l' => Motion::Right,
+            'j' => Motion::Down,
+            'k' => Motion::Up,
+            'w' => Motion::WordForward { big: false },
+            'W' => Motion::WordForward { big: true },
+            'b' => Motion::WordBackward { big: false },
+            'B' => Motion::WordBackward { big: true },
+            'e' => Motion::WordEnd { big: false },
+            'E' => Motion::WordEnd { big: true },
+            '0' => Motion::LineStart,
+            '^' => Motion::FirstNonBlank,
+            '$' => Motion::LineEnd,
+            'G' => Motion::LastLine,
+            '}' => Motion::ParagraphForward,
+            '{' => Motion::ParagraphBackward,
+            _ => return None,
+        };
+        Some(motion)
+    }
+
+    /// Operators act on whole lines for these motions.
+    pub fn is_linewise(self) -> bool {
+        matches!(
+            self,
+            Motion::Up | Motion::Down | Motion::FirstLine | Motion::LastLine
+        )
+    }
+
+    /// Operators include the char the motion lands on for these motions.
+    pub fn is_inclusive(self) -> bool {
+        matches!(self, Motion::WordEnd { .. } | Motion::LineEnd)
+    }
+
+    /// Where the motion, repeated `count` times, takes the cursor from `from`.
+    /// `count` is `None` when no count was typed, which matters for `gg`/`G`.
+    pub fn apply(
+        self,
+        document: &Document,
+        from: Position,
+        count: Option<usize>,
+        tab_size: usize,
+    ) -> Position {
+        let n = count.unwrap_or(1).max(1);
+        let last_line = document.len().saturating_sub(1);
+        match self {
+            Motion::Left | Motion::Right => {
+                let Some(row) = document.row(from.y) else {
+                    return from;
+                };
+                let mut x = from.x;
+                for _ in 0..n {
+                    x = if self == Motion::Right {
+                        row.next_grapheme(x)
+                    } else {
+                        row.prev_grapheme(x)
+                    };
+                }
+                Position { x, y: from.y }
+            }
+            Motion::Up | Motion::Down => {
+                let y = if self == Motion::Down {
+                    (from.y + n).min(last_line)
+                } else {
+                    from.y.saturating_sub(n)
+                };
+                let column = document
+                    .row(from.y)
+                    .map_or(0, |row| row.column_of(from.x, tab_size));
+                let x = document
+                    .row(y)
+                    .map_or(0, |row| row.index_at_column(column, tab_size));
+                Position { x, y }
+            }
+            Motion::WordForward { big } => {
+                let mut idx = document.position_to_char_idx(&from);
+                for _ in 0..n {
+                    idx = next_word_start(document, idx, big);
+                }
+                document.char_idx_to_position(idx)
+            }
+            Motion::WordBackward { big } => {
+                let mut idx = document.position_to_char_idx(&from);
+                for _ in 0..n {
+                    idx = prev_word_start(document, idx, big);
+                }
+                document.char_idx_to_position(idx)
+            }
+            Motion::WordEnd { big } => {
+                let mut idx = document.position_to_char_idx(&from);
+                for _ in 0..n {
+                    idx = word_end(document, idx, big);
+                }
+                document.char_idx_to_position(idx)
+            }
+            Motion::LineStart => Position { x: 0, y: from.y },
+            Motion::FirstNonBlank => Position {
+                x: first_non_blank(document, from.y),
+                y: from.y,
+            },
+            Motion::LineEnd => {
+                let y = (from.y + n - 1).min(last_line);
+                Position {
+                    x: document.line_len(y).saturating_sub(1),
+                    y,
+                }
+            }
+            Motion::FirstLine | Motion::LastLine => {
+                let y = match count {
+                    Some(line) => line.saturating_sub(1).min(last_line),
+                    None if self == Motion::FirstLine => 0,
+                    None => last_line,
+                };
+                Position {
+                    x: first_non_blank(document, y),
+                    y,
+                }
+            }
+            Motion::ParagraphForward => {
+                let mut y = from.y;
+                for _ in 0..n {
+                    // Blank lines under the cursor don't end the paragraph
+                    y = (y..=last_line)
+                        .skip_while(|&y| is_blank_line(document, y))
+                        .find(|&y| is_blank_line(document, y))
+                        .unwrap_or(last_line);
+                }
+                Position {
+                    x: document.line_len(y),
+                    y,
+                }
+            }
+            Motion::ParagraphBackward => {
+                let mut y = from.y;
+                for _ in 0..n {
+                    y = (0..=y)
+                        .rev()
+                        .skip_while(|&y| is_blank_line(document, y))
+                        .find(|&y| is_blank_line(document, y))
+                        .unwrap_or(0);
+                }
+                Position { x: 0, y }
+            }
+        }
+    }
+}
+
+/// Target of `cw`: unlike `w` it stops at the end of the word under the
+/// cursor, then at the end of each following word for a count.
+pub fn change_word_end(document: &Document, from: Position, count: usize, big: bool) -> Position {
+    let text = &document.content;
+    let mut idx = document.position_to_char_idx(&from);
+    if idx < text.len_chars() {
+        let class = char_class(text.char(idx), big);
+        while idx + 1 < text.len_chars() && char_class(text.char(idx + 1), big) == class {
+            idx += 1;
+        }
+    }
+    for _ in 1..count {
+        idx = word_end(document, idx, big);
+    }
+    document.char_idx_to_position(idx)
+}
+
+/// Char index of the first non-whitespace char on line `y`.
+pub fn first_non_blank(document: &Document, y: usize) -> usize {
+    if y >= document.len() {
+        return 0;
+    }
+    document
+        .content
+        .line(y)
+        .chars()
+        .position(|c| !c.is_whitespace())
+        .unwrap_or_else(|| document.line_len(y))
+}
+
+fn is_blank_line(document: &Document, y: usize) -> bool {
+    document.line_len(y) == 0
+}
+
+#[derive(PartialEq)]
+enum CharClass {
+    Blank,
+    Word,
+    Punctuation,
+}
+
+/// Words are runs of letters, digits and `_`, or runs of other non-blank
+/// chars. A WORD (`big`) is any run of non-blank chars.
+fn char_class(c: char, big: bool) -> CharClass {
+    if c.is_whitespace() {
+        CharClass::Blank
+    } else if big || c.is_alphanumeric() || c == '_' {
+        CharClass::Word
+    } else {
+        CharClass::Punctuation
+    }
+}
+
+// An empty line counts as a word of its own, so `w` and `b` stop on it.
+fn is_empty_line_at(document: &Document, idx: usize) -> bool {
+    document.content.char(idx) == '\n' && (idx == 0 || document.content.char(idx - 1) == '\n')
+}
+
+fn next_word_start(document: &Document, idx: usize, big: bool) -> usize {
+    let text = &document.content;
+    let len = text.len_chars();
+    if idx >= len {
+        return len;
+    }
+    let class = char_class(text.char(idx), big);
+    let mut i = idx;
+    if class != CharClass::Blank {
+        while i < len && char_class(text.char(i), big) == class {
+            i += 1;
+        }
+    }
+    while i < len && char_class(text.char(i), big) == CharClass::Blank {
+        if i + 1 < len && text.char(i) == '\n' && text.char(i + 1) == '\n' {
+            return i + 1;
+        }
+        i += 1;
+    }
+    i
+}
+
+fn prev_word_start(document: &Document, idx: usize, big: bool) -> usize {
+    let text = &document.content;
+    if idx == 0 {
+        return 0;
+    }
+    let mut i = idx.min(text.len_chars()) - 1;
+    while char_class(text.char(i), big) == CharClass::Blank {
+        if is_empty_line_at(document, i) {
+            return i;
+        }
+        if i == 0 {
+            return 0;
+        }
+        i -= 1;
+    }
+    let class = char_class(text.char(i), big);
+    while i > 0 && char_class(text.char(i - 1), big) == class {
+        i -= 1;
+    }
+    i
+}
+
+fn word_end(document: &Document, idx: usize, big: bool) -> usize {
+    let text = &document.content;
+    let len = text.len_chars();
+    let mut i = idx + 1;
+    while i < len && char_class(text.char(i), big) == CharClass::Blank {
+        i += 1;
+    }
+    if i >= len {
+        return len.saturating_sub(1).max(idx);
+    }
+    let class = char_class(text.char(i), big);
+    while i + 1 < len && char_class(text.char(i + 1), big) == class {
+        i += 1;
+    }
+    i
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ropey::Rope;
+
+    /// Where `count` times the motion of `key` (`g` for `gg`) takes the
+    /// cursor from `x, y`.
+    fn go(text: &str, x: usize, y: usize, count: Option<usize>, key: char) -> (usize, usize) {
+        let mut document = Document::default();
+        document.content = Rope::from_str(text);
+        let motion = match key {
+            'g' => Motion::FirstLine,
+            key => Motion::from_key(key).unwrap(),
+        };
+        let to = motion.apply(&document, Position { x, y }, count, 4);
+        (to.x, to.y)
+    }
+
+    #[test]
+    fn paragraph_forward() {
+        let text = "one\nmore\n\ntwo\nthree\n\nfour";
+        // From the first line, the last line and a blank line of a paragraph
+        assert_eq!(go(text, 0, 0, None, '}'), (0, 2));
+        assert_eq!(go(text, 2, 1, None, '}'), (0, 2));
+        assert_eq!(go(text, 0, 2, None, '}'), (0, 5));
+        assert_eq!(go(text, 0, 3, Some(2), '}'), (4, 6));
+        // At the end of the buffer
+        assert_eq!(go(text, 4, 6, None, '}'), (4, 6));
+        assert_eq!(go(text, 0, 0, Some(9), '}'), (4, 6));
+        assert_eq!(go("one\nmore\n\ntwo\n", 0, 1, None, '}'), (0, 2));
+        assert_eq!(go("a\n\n\nb", 0, 1, None, '}'), (1, 3));
+    }
+
+    #[test]
+    fn paragraph_backward() {
+        let text = "one\nmore\n\ntwo\nthree\n\nfour";
+        assert_eq!(go(text, 0, 3, None, '{'), (0, 2));
+        assert_eq!(go(text, 3, 4, None, '{'), (0, 2));
+        assert_eq!(go(text, 0, 5, None, '{'), (0, 2));
+        assert_eq!(go(text, 2, 6, Some(2), '{'), (0, 2));
+        // At the start of the buffer
+        assert_eq!(go(text, 0, 2, None, '{'), (0, 0));
+        assert_eq!(go(text, 2, 0, None, '{'), (0, 0));
+        assert_eq!(go("one\n\ntwo\n\nthree\n", 0, 2, None, '{'), (0, 1));
+        assert_eq!(go("a\n\n\nb", 0, 2, None, '{'), (0, 0));
+    }
+
+    #[test]
+    fn words() {
+        let text = "foo.bar baz\n\n  qux";
+        assert_eq!(go(text, 0, 0, None, 'w'), (3, 0));
+        assert_eq!(go(text, 0, 0, None, 'W'), (8, 0));
+        assert_eq!(go(text, 0, 0, Some(3), 'w'), (8, 0));
+        // An empty line is a word of its own
+        assert_eq!(go(text, 8, 0, None, 'w'), (0, 1));
+        assert_eq!(go(text, 0, 1, None, 'w'), (2, 2));
+        assert_eq!(go(text, 2, 2, None, 'b'), (0, 1));
+        assert_eq!(go(text, 8, 0, None, 'B'), (0, 0));
+        assert_eq!(go(text, 8, 0, Some(2), 'b'), (3, 0));
+        assert_eq!(go(text, 0, 0, None, 'e'), (2, 0));
+        assert_eq!(go(text, 0, 0, None, 'E'), (6, 0));
+        assert_eq!(go(text, 2, 0, None, 'e'), (3, 0));
+    }
+
+    #[test]
+    fn lines() {
+        let text = "  one\ntwo\n\tthree";
+        assert_eq!(go(text, 3, 0, None, '0'), (0, 0));
+        assert_eq!(go(text, 0, 0, None, '^'), (2, 0));
+        assert_eq!(go(text, 0, 0, None, '$'), (4, 0));
+        assert_eq!(go(text, 0, 0, Some(2), '$'), (2, 1));
+        // `G` and `gg` go to the first non-blank of the line numbered by the count
+        assert_eq!(go(text, 0, 0, None, 'G'), (1, 2));
+        assert_eq!(go(text, 0, 2, None, 'g'), (2, 0));
+        assert_eq!(go(text, 0, 2, Some(2), 'g'), (0, 1));
+        assert_eq!(go(text, 0, 0, Some(9), 'G'), (1, 2));
+        // `j` and `k` keep the display column across a tab
+        assert_eq!(go(text, 2, 0, None, 'j'), (2, 1));
+        assert_eq!(go(text, 2, 1, None, 'j'), (0, 2));
+        assert_eq!(go(text, 3, 2, None, 'k'), (3, 1));
+        assert_eq!(go(text, 0, 0, Some(5), 'j').1, 2);
+        assert_eq!(go(text, 1, 0, Some(2), 'l'), (3, 0));
+        assert_eq!(go(text, 1, 0, Some(5), 'h'), (0, 0));
+    }
+}
@@ -0,0 +1,354 @@
+use crate::motion::Motion;
+use crate::register::Registers;
+use crate::textobject::TextObject;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Operator {
+    Delete,
+    Change,
+    Yank,
+}
+
+impl Operator {
+    fn from_key(c: char) -> Option<Self> {
+        match c {
+            'd' => Some(Operator::Delete),
+            'c' => Some(Operator::Change),
+            'y' => Some(Operator::Yank),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Action {
+    Move(Motion),
+    /// An operator over the text a motion moves across, e.g. `dw` or `y3j`.
+    Operate(Operator, Motion),
+    /// `dd`, `cc`, `yy`: the operator over whole lines.
+    OperateLines(Operator),
+    /// An operator over a text object, e.g. `diw` or `ca(`.
+    OperateObject(Operator, TextObject),
+    /// Visual mode only: select a text object.
+    Select(TextObject),
+    /// `x`
+    DeleteChar,
+    /// `p`, or `P` to paste before the cursor.
+    Paste {
+        before: bool,
+    },
+    /// `i`
+    Insert,
+    /// `.`
+    Repeat,
+}
+
+/// A complete Normal-mode command such as `3dw` or `"a2yy`.
+#[derive(Clone, Copy, Debug)]
+pub struct Command {
+    /// The register named with `"x`, if any.
+    pub register: Option<char>,
+    /// `None` when no count was typed. Counts before and after an operator
+    /// multiply, so `2d3w` has a count of 6.
+    pub count: Option<usize>,
+    pub action: Action,
+}
+
+impl Command {
+    /// Whether `.` should repeat this command.
+    pub fn is_change(&self) -> bool {
+        match self.action {
+            Action::Operate(operator, _)
+            | Action::OperateLines(operator)
+            | Action::OperateObject(operator, _) => operator != Operator::Yank,
+            Action::DeleteChar | Action::Paste { .. } | Action::Insert => true,
+            Action::Move(_) | Action::Select(_) | Action::Repeat => false,
+        }
+    }
+}
+
+pub enum Parsed {
+    /// The keys so far are the start of a command; wait for more.
+    Pending,
+    Done(Command),
+    /// An operator was followed by something that isn't a motion.
+    Invalid,
+    /// The last key isn't part of the grammar and should be handled on its own.
+    NotACommand,
+}
+
+/// Parses the keys typed in Normal mode so far:
+/// `[count] motion`, `[count] operator [count] (motion | text object | operator)`,
+/// `[count] x`, `[count] p`, `i` and `[count] .`. Any of them may start with a
+/// register, e.g. `"ayy` or `2"bp`.
+pub fn parse(keys: &str) -> Parsed {
+    let mut chars = keys.chars().peekable();
+    let (count, register) = match take_prefix(&mut chars) {
+        Ok(prefix) => prefix,
+        Err(parsed) => return parsed,
+    };
+    let Some(c) = chars.next() else {
+        return Parsed::Pending;
+    };
+
+    if let Some(operator) = Operator::from_key(c) {
+        let inner_count = take_count(&mut chars);
+        let Some(c) = chars.next() else {
+            return Parsed::Pending;
+        };
+        let count = multiply(count, inner_count);
+        let action = if Operator::from_key(c) == Some(operator) {
+            Action::OperateLines(operator)
+        } else if c == 'i' || c == 'a' {
+            match text_object(c, &mut chars) {
+                Some(Some(object)) => Action::OperateObject(operator, object),
+                Some(None) => return Parsed::Pending,
+                None => return Parsed::Invalid,
+            }
+        } else {
+            match motion(c, &mut chars) {
+                Some(Some(motion)) => Action::Operate(operator, motion),
+                Some(None) => return Parsed::Pending,
+                None => return Parsed::Invalid,
+            }
+        };
+        return Parsed::Done(Command {
+            register,
+            count,
+            action,
+        });
+    }
+
+    let action = match c {
+        'x' => Action::DeleteChar,
+        'p' => Action::Paste { before: false },
+        'P' => Action::Paste { before: true },
+        'i' => Action::Insert,
+        '.' => Action::Repeat,
+        c => match motion(c, &mut chars) {
+            Some(Some(motion)) => Action::Move(motion),
+            Some(None) => return Parsed::Pending,
+            None => return Parsed::NotACommand,
+        },
+    };
+    Parsed::Done(Command {
+        register,
+        count,
+        action,
+    })
+}
+
+/// Parses keys typed in Visual mode, where `i` and `a` start a text object
+/// rather than entering Insert mode.
+pub fn parse_visual(keys: &str) -> Parsed {
+    let mut chars = keys.chars().peekable();
+    let (count, register) = match take_prefix(&mut chars) {
+        Ok(prefix) => prefix,
+        Err(parsed) => return parsed,
+    };
+    match chars.next() {
+        Some(c @ ('i' | 'a')) => match text_object(c, &mut chars) {
+            Some(Some(object)) => Parsed::Done(Command {
+                register,
+                count,
+                action: Action::Select(object),
+            }),
+            Some(None) => Parsed::Pending,
+            None => Parsed::Invalid,
+        },
+        _ => parse(keys),
+    }
+}
+
+/// The register named by `keys` so far, for Visual-mode operators which
+/// aren't parsed as commands: `"ay` yanks into register `a`.
+pub fn pending_register(keys: &str) -> Option<char> {
+    take_prefix(&mut keys.chars().peekable())
+        .ok()
+        .and_then(|(_, register)| register)
+}
+
+/// Reads `[count] ["x] [count]`. The counts on either side of the register
+/// multiply like those around an operator.
+fn take_prefix(
+    chars: &mut std::iter::Peekable<std::str::Chars>,
+) -> Result<(Option<usize>, Option<char>), Parsed> {
+    let count = take_count(chars);
+    if chars.peek() != Some(&'"') {
+        return Ok((count, None));
+    }
+    chars.next();
+    let register = match chars.next() {
+        None => return Err(Parsed::Pending),
+        Some(c) if Registers::is_valid(c) => c,
+        Some(_) => return Err(Parsed::Invalid),
+    };
+    Ok((multiply(count, take_count(chars)), Some(register)))
+}
+
+fn multiply(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.saturating_mul(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// `Some(None)` means the object key hasn't been typed yet.
+fn text_object(
+    prefix: char,
+    chars: &mut std::iter::Peekable<std::str::Chars>,
+) -> Option<Option<TextObject>> {
+    match chars.next() {
+        None => Some(None),
+        Some(c) => TextObject::from_key(prefix == 'i', c).map(Some),
+    }
+}
+
+fn take_count(chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<usize> {
+    // A leading `0` is the "start of line" motion, not part of a count
+    let mut count: Option<usize> = None;
+    while let Some(&c) = chars.peek() {
+        match c.to_digit(10) {
+            Some(d) if d > 0 || count.is_some() => {
+                count = Some(
+                    count
+                        .unwrap_or(0)
+                        .saturating_mul(10)
+                        .saturating_add(d as usize),
+                );
+                chars.next();
+            }
+            _ => break,
+        }
+    }
+    count
+}
+
+/// `Some(None)` means the key starts a longer motion (`g` waiting for `g`).
+fn motion(c: char, chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<Option<Motion>> {
+    if c == 'g' {
+        return match chars.next() {
+            None => Some(None),
+            Some('g') => Some(Some(Motion::FirstLine)),
+            Some(_) => None,
+        };
+    }
+    Motion::from_key(c).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn done(keys: &str) -> Command {
+        match parse(keys) {
+            Parsed::Done(command) => command,
+            _ => panic!("{:?} is not a complete command", keys),
+        }
+    }
+
+    fn command(keys: &str) -> (Option<char>, Option<usize>, Action) {
+        let command = done(keys);
+        (command.register, command.count, command.action)
+    }
+
+    fn is_pending(keys: &str) -> bool {
+        matches!(parse(keys), Parsed::Pending)
+    }
+
+    const WORD: Motion = Motion::WordForward { big: false };
+
+    #[test]
+    fn counts() {
+        assert_eq!(command("w"), (None, None, Action::Move(WORD)));
+        assert_eq!(command("3w"), (None, Some(3), Action::Move(WORD)));
+        assert_eq!(command("12j"), (None, Some(12), Action::Move(Motion::Down)));
+        // A leading `0` is a motion, a later one part of the count
+        assert_eq!(command("0"), (None, None, Action::Move(Motion::LineStart)));
+        assert_eq!(command("10x"), (None, Some(10), Action::DeleteChar));
+        // Counts around an operator or a register multiply
+        let delete_words = Action::Operate(Operator::Delete, WORD);
+        assert_eq!(command("2d3w"), (None, Some(6), delete_words));
+        assert_eq!(command("d3w"), (None, Some(3), delete_words));
+        assert_eq!(command("2\"a3dw"), (Some('a'), Some(6), delete_words));
+        assert_eq!(command("3."), (None, Some(3), Action::Repeat));
+    }
+
+    #[test]
+    fn operators() {
+        assert_eq!(
+            command("dd"),
+            (None, None, Action::OperateLines(Operator::Delete))
+        );
+        assert_eq!(
+            command("c2c"),
+            (None, Some(2), Action::OperateLines(Operator::Change))
+        );
+        assert_eq!(
+            command("\"byy"),
+            (Some('b'), None, Action::OperateLines(Operator::Yank))
+        );
+        assert_eq!(
+            command("y$"),
+            (None, None, Action::Operate(Operator::Yank, Motion::LineEnd))
+        );
+        assert_eq!(
+            command("dgg"),
+            (
+                None,
+                None,
+                Action::Operate(Operator::Delete, Motion::FirstLine)
+            )
+        );
+        assert_eq!(
+            command("ci("),
+            (
+                None,
+                None,
+                Action::OperateObject(Operator::Change, TextObject::from_key(true, '(').unwrap())
+            )
+        );
+        assert_eq!(command("P"), (None, None, Action::Paste { before: true }));
+        // After an operator, a key that is neither the operator again nor a
+        // motion or text object
+        assert!(matches!(parse("dy"), Parsed::Invalid));
+        assert!(matches!(parse("dq"), Parsed::Invalid));
+        assert!(matches!(parse("diq"), Parsed::Invalid));
+        assert!(matches!(parse("\"!"), Parsed::Invalid));
+    }
+
+    #[test]
+    fn incomplete_commands() {
+        for keys in ["", "3", "d", "2d3", "di", "g", "dg", "\"", "\"a", "2\"a"] {
+            assert!(is_pending(keys), "{:?}", keys);
+        }
+        assert!(matches!(parse("q"), Parsed::NotACommand));
+        assert!(matches!(parse("3:"), Parsed::NotACommand));
+    }
+
+    #[test]
+    fn visual_text_objects() {
+        let Parsed::Done(command) = parse_visual("2aw") else {
+            panic!("`2aw` is not a complete command");
+        };
+        assert_eq!(command.count, Some(2));
+        assert_eq!(
+            command.action,
+            Action::Select(TextObject::from_key(false, 'w').unwrap())
+        );
+        assert!(matches!(parse_visual("i"), Parsed::Pending));
+        assert!(matches!(parse_visual("j"), Parsed::Done(_)));
+        assert_eq!(pending_register("2\"a"), Some('a'));
+        assert_eq!(pending_register("2"), None);
+    }
+
+    #[test]
+    fn changes_are_repeatable() {
+        for keys in ["x", "p", "i", "dw", "cc", "dip"] {
+            assert!(done(keys).is_change(), "{:?}", keys);
+        }
+        for keys in ["yy", "yw", "w", "."] {
+            assert!(!done(keys).is_change(), "{:?}", keys);
+        }
+    }
+}
@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+
+/// How text in a register was yanked, which decides how `p` puts it back.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RegisterKind {
+    /// Inserted at the cursor.
+    Chars,
+    /// Whole lines, put above or below the cursor line. The text ends with `\n`.
+    Lines,
+    /// A Visual block, one line of text per row, put column-wise.
+    Block,
+}
+
+#[derive(Clone, Debug)]
+pub struct Register {
+    pub text: String,
+    pub kind: RegisterKind,
+}
+
+impl Register {
+    pub fn new(text: String, kind: RegisterKind) -> Self {
+        Self { text, kind }
+    }
+}
+
+/// The editor's registers:
+///
+/// - `""` the unnamed register, holding the last yank or delete
+/// - `"0` the last yank, and `"1`–`"9` the last nine deletes
+/// - `"a`–`"z`, which `"A`–`"Z` append to
+/// - `"+` (and `"*`) the system clipboard
+/// - `"_` the black hole, which discards whatever is written to it
+///
+/// Writes to the unnamed register are mirrored to the system clipboard when
+/// there is one. Without one, `"+` is kept internally like any other register.
+pub struct Registers {
+    values: HashMap<char, Register>,
+    clipboard: Option<arboard::Clipboard>,
+    // The text last copied to the clipboard, to tell whether something else
+    // has copied to it since
+    clipboard_text: Option<String>,
+}
+
+const UNNAMED: char = '"';
+
+impl Registers {
+    pub fn new() -> Self {
+        Self {
+            values: HashMap::new(),
+            clipboard: arboard::Clipboard::new().ok(),
+            clipboard_text: None,
+        }
+    }
+
+    /// Registers without a system clipboard, so tests don't touch the real one.
+    #[cfg(test)]
+    pub fn without_clipboard() -> Self {
+        Self {
+            values: HashMap::new(),
+            clipboard: None,
+            clipboard_text: None,
+        }
+    }
+
+    /// Whether `name` may follow `"` in a command.
+    pub fn is_valid(name: char) -> bool {
+        name.is_ascii_alphanumeric() || matches!(name, '"' | '+' | '*' | '_')
+    }
+
+    /// Stores yanked text in register `name`, or in `"0` when no register
+    /// was given.
+    pub fn yank(&mut self, name: Option<char>, register: Register) {
+        match name {
+            None | Some(UNNAMED) => {
+                self.values.insert('0', register.clone());
+                self.set_unnamed(register);
+            }
+            Some(name) => self.write(name, register),
+        }
+    }
+
+    /// Stores deleted text in register `name`, or shifts it into the
+    /// numbered registers when no register was given.
+    pub fn delete(&mut self, name: Option<char>, register: Register) {
+        match name {
+            None | Some(UNNAMED) => {
+                for n in (1..9).rev() {
+                    let from = char::from_digit(n, 10).unwrap();
+                    let to = char::from_digit(n + 1, 10).unwrap();
+                    if let Some(value) = self.values.remove(&from) {
+                        self.values.insert(to, value);
+                    }
+                }
+                self.values.insert('1', register.clone());
+                self.set_unnamed(register);
+            }
+            Some(name) => self.write(name, register),
+        }
+    }
+
+    /// Contents of register `name` (the unnamed register for `None`).
+    pub fn get(&mut self, name: Option<char>) -> Option<Register> {
+        match name.unwrap_or(UNNAMED) {
+            '_' => None,
+            UNNAMED => self
+                .changed_clipboard()
+                .or_else(|| self.values.get(&UNNAMED).cloned()),
+            '+' | '*' => self
+                .changed_clipboard()
+                .or_else(|| self.values.get(&'+').cloned()),
+            name => self.values.get(&name.to_ascii_lowercase()).cloned(),
+        }
+    }
+
+    /// All non-empty registers in the order `:registers` lists them.
+    pub fn list(&mut self) -> Vec<(char, Register)> {
+        let names = std::iter::once(UNNAMED)
+            .chain('0'..='9')
+            .chain('a'..='z')
+            .chain(std::iter::once('+'));
+        names
+            .filter_map(|name| Some((name, self.get(Some(name))?)))
+            .collect()
+    }
+
+    fn write(&mut self, name: char, register: Register) {
+        let register = match name {
+            '_' => return,
+            'A'..='Z' => {
+                let name = name.to_ascii_lowercase();
+                match self.values.get(&name) {
+                    Some(old) => append(old, register),
+                    None => register,
+                }
+            }
+            _ => register,
+        };
+        let name = match name {
+            '*' => '+',
+            name => name.to_ascii_lowercase(),
+        };
+        if name == '+' {
+            self.copy_to_clipboard(&register.text);
+        }
+        self.values.insert(name, register.clone());
+        self.values.insert(UNNAMED, register);
+    }
+
+    fn set_unnamed(&mut self, register: Register) {
+        self.copy_to_clipboard(&register.text);
+        self.values.insert(UNNAMED, register);
+    }
+
+    fn copy_to_clipboard(&mut self, text: &str) {
+        if let Some(clipboard) = &mut self.clipboard
+            && clipboard.set_text(text).is_ok()
+        {
+            self.clipboard_text = Some(text.to_string());
+        }
+    }
+
+    /// The clipboard contents if another program has copied something since
+    /// we last wrote to it. Text ending in a line break is pasted as lines.
+    fn changed_clipboard(&mut self) -> Option<Register> {
+        let text = self.clipboard.as_mut()?.get_text().ok()?;
+        if text.is_empty() || self.clipboard_text.as_deref() == Some(text.as_str()) {
+            return None;
+        }
+        let kind = if text.ends_with('\n') {
+            RegisterKind::Lines
+        } else {
+            RegisterKind::Chars
+        };
+        Some(Register::new(text, kind))
+    }
+}
+
+/// `"Ay`: adds `register` to the end of `old`. Appending lines to anything
+/// makes the result linewise.
+fn append(old: &Register, register: Register) -> Register {
+    let mut text = old.text.clone();
+    let kind = if old.kind == RegisterKind::Lines || register.kind == RegisterKind::Lines {
+        if !text.is_empty() && !text.ends_with('\n') {
+            text.push('\n');
+        }
+        RegisterKind::Lines
+    } else {
+        old.kind
+    };
+    text.push_str(&register.text);
+    if kind == RegisterKind::Lines && !text.ends_with('\n') {
+        text.push('\n');
+    }
+    Register::new(text, kind)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registers() -> Registers {
+        Registers::without_clipboard()
+    }
+
+    fn chars(text: &str) -> Register {
+        Register::new(text.to_string(), RegisterKind::Chars)
+    }
+
+    fn text(registers: &mut Registers, name: Option<char>) -> Option<String> {
+        registers.get(name).map(|r| r.text)
+    }
+
+    #[test]
+    fn yank_goes_to_zero_and_unnamed() {
+        let mut r = registers();
+        r.yank(None, chars("a"));
+        assert_eq!(text(&mut r, None).unwrap(), "a");
+        assert_eq!(text(&mut r, Some('0')).unwrap(), "a");
+        assert_eq!(text(&mut r, Some('1')), None);
+    }
+
+    #[test]
+    fn deletes_shift_through_numbered_registers() {
+        let mut r = registers();
+        r.yank(None, chars("yanked"));
+        for n in 1..=10 {
+            r.delete(None, chars(&n.to_string()));
+        }
+        assert_eq!(text(&mut r, Some('1')).unwrap(), "10");
+        assert_eq!(text(&mut r, Some('2')).unwrap(), "9");
+        // "1" fell off the end
+        assert_eq!(text(&mut r, Some('9')).unwrap(), "2");
+        assert_eq!(text(&mut r, Some('0')).unwrap(), "yanked");
+        assert_eq!(text(&mut r, None).unwrap(), "10");
+    }
+
+    #[test]
+    fn uppercase_appends() {
+        let mut r = registers();
+        r.yank(Some('a'), chars("foo"));
+        r.yank(Some('A'), chars("bar"));
+        let a = r.get(Some('a')).unwrap();
+        assert_eq!((a.text.as_str(), a.kind), ("foobar", RegisterKind::Chars));
+        assert_eq!(text(&mut r, Some('A')).unwrap(), "foobar");
+        assert_eq!(text(&mut r, None).unwrap(), "foobar");
+
+        // Appending lines makes the register linewise
+        r.delete(
+            Some('A'),
+            Register::new("line\n".to_string(), RegisterKind::Lines),
+        );
+        let a = r.get(Some('a')).unwrap();
+        assert_eq!(
+            (a.text.as_str(), a.kind),
+            ("foobar\nline\n", RegisterKind::Lines)
+        );
+
+        // Appending to an empty register just sets it
+        r.yank(Some('B'), chars("x"));
+        assert_eq!(text(&mut r, Some('b')).unwrap(), "x");
+    }
+
+    #[test]
+    fn black_hole_discards() {
+        let mut r = registers();
+        r.yank(None, chars("kept"));
+        r.delete(Some('_'), chars("gone"));
+        r.yank(Some('_'), chars("gone"));
+        assert_eq!(text(&mut r, Some('_')), None);
+        assert_eq!(text(&mut r, None).unwrap(), "kept");
+        assert_eq!(text(&mut r, Some('1')), None);
+    }
+
+    #[test]
+    fn clipboard_register_without_a_clipboard() {
+        let mut r = registers();
+        r.yank(Some('+'), chars("plus"));
+        assert_eq!(text(&mut r, Some('+')).unwrap(), "plus");
+        assert_eq!(text(&mut r, Some('*')).unwrap(), "plus");
+        r.yank(Some('*'), chars("star"));
+        assert_eq!(text(&mut r, Some('+')).unwrap(), "star");
+        assert_eq!(text(&mut r, None).unwrap(), "star");
+    }
+
+    #[test]
+    fn list_in_order() {
+        let mut r = registers();
+        r.yank(Some('b'), chars("b"));
+        r.delete(None, chars("d"));
+        r.yank(Some('+'), chars("p"));
+        let names: Vec<char> = r.list().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ['"', '1', 'b', '+']);
+        assert!(Registers::is_valid('a') && Registers::is_valid('_'));
+        assert!(!Registers::is_valid('-'));
+    }
+}
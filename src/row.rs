@@ -1,3 +1,7 @@
+use crate::syntax::Syntax;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum HighlightType {
     None,
@@ -7,9 +11,64 @@ pub enum HighlightType {
     ControlFlow,
     String,
     Comment,
+    Function,
+    Lifetime,
+    Macro,
+    Attribute,
+    Operator,
+    Punctuation,
+    Constant,
+    Escape,
+    DocComment,
+}
+
+impl HighlightType {
+    /// Every kind, in declaration order, so `kind as usize` indexes it.
+    pub const ALL: [HighlightType; 16] = [
+        HighlightType::None,
+        HighlightType::Number,
+        HighlightType::Keyword,
+        HighlightType::Type,
+        HighlightType::ControlFlow,
+        HighlightType::String,
+        HighlightType::Comment,
+        HighlightType::Function,
+        HighlightType::Lifetime,
+        HighlightType::Macro,
+        HighlightType::Attribute,
+        HighlightType::Operator,
+        HighlightType::Punctuation,
+        HighlightType::Constant,
+        HighlightType::Escape,
+        HighlightType::DocComment,
+    ];
+
+    /// The kind a theme key styles, e.g. `Type` for `type_color`. Also
+    /// takes `type` and `macro`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "none" => HighlightType::None,
+            "number" => HighlightType::Number,
+            "keyword" => HighlightType::Keyword,
+            "type" | "type_color" => HighlightType::Type,
+            "control_flow" => HighlightType::ControlFlow,
+            "string" => HighlightType::String,
+            "comment" => HighlightType::Comment,
+            "function" => HighlightType::Function,
+            "lifetime" => HighlightType::Lifetime,
+            "macro" | "macro_color" => HighlightType::Macro,
+            "attribute" => HighlightType::Attribute,
+            "operator" => HighlightType::Operator,
+            "punctuation" => HighlightType::Punctuation,
+            "constant" => HighlightType::Constant,
+            "escape" => HighlightType::Escape,
+            "doc_comment" => HighlightType::DocComment,
+            _ => return None,
+        })
+    }
 }
 
-#[derive(Default)]
+#[derive(Default, Clone)]
 pub struct Row {
     pub content: String,
     pub len: usize,
@@ -39,88 +98,428 @@ impl Row {
         self.len == 0
     }
 
-    pub fn update_highlighting(&mut self, syntax: &crate::syntax::Syntax) {
+    /// Grapheme clusters of the line, each with the char index it starts at.
+    pub fn graphemes(&self) -> impl Iterator<Item = (usize, &str)> {
+        let mut index = 0;
+        self.content.graphemes(true).map(move |grapheme| {
+            let start = index;
+            index += grapheme.chars().count();
+            (start, grapheme)
+        })
+    }
+
+    /// Display column at which char index `x` is drawn.
+    pub fn column_of(&self, x: usize, tab_size: usize) -> usize {
+        let mut column = 0;
+        for (index, grapheme) in self.graphemes() {
+            if index >= x {
+                break;
+            }
+            column += grapheme_width(grapheme, column, tab_size);
+        }
+        column
+    }
+
+    /// Char index of the grapheme drawn at display `column`, or the line
+    /// length if the column is past the end.
+    pub fn index_at_column(&self, column: usize, tab_size: usize) -> usize {
+        let mut current = 0;
+        for (index, grapheme) in self.graphemes() {
+            current += grapheme_width(grapheme, current, tab_size);
+            if current > column {
+                return index;
+            }
+        }
+        self.len
+    }
+
+    /// Char index where the grapheme after the one containing `x` starts.
+    pub fn next_grapheme(&self, x: usize) -> usize {
+        self.graphemes()
+            .map(|(index, _)| index)
+            .find(|&index| index > x)
+            .unwrap_or(self.len)
+    }
+
+    /// Char index where the grapheme before the one containing `x` starts.
+    pub fn prev_grapheme(&self, x: usize) -> usize {
+        self.graphemes()
+            .map(|(index, _)| index)
+            .take_while(|&index| index < x)
+            .last()
+            .unwrap_or(0)
+    }
+
+    /// Moves `x` back to the start of the grapheme it falls inside.
+    pub fn grapheme_start(&self, x: usize) -> usize {
+        if x >= self.len {
+            return self.len;
+        }
+        self.graphemes()
+            .map(|(index, _)| index)
+            .take_while(|&index| index <= x)
+            .last()
+            .unwrap_or(0)
+    }
+
+    /// Highlights the line by `syntax`'s keywords, strings and comments.
+    /// `state` is where the line before left off, e.g. inside a block
+    /// comment; returns where this line leaves off.
+    pub fn update_highlighting(&mut self, syntax: &Syntax, state: LineState) -> LineState {
+        self.scan(syntax, state, true)
+    }
+
+    /// The state the line ends in when it starts in `state`, found without
+    /// looking for keywords.
+    pub fn end_state(&mut self, syntax: &Syntax, state: LineState) -> LineState {
+        self.scan(syntax, state, false)
+    }
+
+    fn scan(&mut self, syntax: &Syntax, state: LineState, keywords: bool) -> LineState {
         self.highlighting = vec![HighlightType::None; self.len];
         let chars: Vec<char> = self.content.chars().collect();
+        let to_chars = |text: &str| -> Vec<char> { text.chars().collect() };
+        let quotes: Vec<(Vec<char>, Vec<char>)> = syntax
+            .strings
+            .iter()
+            .map(|quote| (to_chars(&quote.start), to_chars(&quote.end)))
+            .collect();
+        let block_comments: Vec<(Vec<char>, Vec<char>)> = syntax
+            .block_comments
+            .iter()
+            .map(|(start, end)| (to_chars(start), to_chars(end)))
+            .collect();
+        let comment_start: Vec<char> = to_chars(&syntax.single_line_comment);
+        let doc_comments: Vec<Vec<char>> = syntax
+            .doc_comments
+            .iter()
+            .filter(|prefix| !prefix.is_empty())
+            .map(|prefix| to_chars(prefix))
+            .collect();
+        let char_quote: Vec<char> = to_chars(&syntax.char_delimiter);
+        let matches = pattern_matches(&self.content, syntax);
+        let mut next_match = 0;
+        let mut state = state;
+        // Whether a backslash ends the line inside a string
+        let mut continued = false;
         let mut i = 0;
-        let mut in_string = false;
 
         while i < chars.len() {
             let c = chars[i];
 
-            if in_string {
-                self.highlighting[i] = HighlightType::String;
-                if c == '"' {
-                    in_string = false;
+            match state {
+                LineState::Comment(n) | LineState::DocComment(n) => {
+                    let kind = if state == LineState::Comment(n) {
+                        HighlightType::Comment
+                    } else {
+                        HighlightType::DocComment
+                    };
+                    let end = &block_comments[n].1;
+                    if chars[i..].starts_with(end) {
+                        self.highlighting[i..i + end.len()].fill(kind);
+                        i += end.len();
+                        state = LineState::Normal;
+                    } else {
+                        self.highlighting[i] = kind;
+                        i += 1;
+                    }
+                    continue;
+                }
+                LineState::String(n) => {
+                    let end = &quotes[n].1;
+                    if c == '\\' && !syntax.strings[n].raw {
+                        let len = if i + 1 < chars.len() { 2 } else { 1 };
+                        continued = len == 1;
+                        self.highlighting[i..i + len].fill(HighlightType::Escape);
+                        i += len;
+                    } else if chars[i..].starts_with(end) {
+                        self.highlighting[i..i + end.len()].fill(HighlightType::String);
+                        i += end.len();
+                        state = LineState::Normal;
+                    } else {
+                        self.highlighting[i] = HighlightType::String;
+                        i += 1;
+                    }
+                    continue;
                 }
-                i += 1;
+                LineState::Normal => {}
+            }
+
+            while matches
+                .get(next_match)
+                .is_some_and(|&(start, _, _)| start < i)
+            {
+                next_match += 1;
+            }
+            if let Some(&(start, end, kind)) = matches.get(next_match)
+                && start == i
+            {
+                self.highlighting[i..end].fill(kind);
+                i = end;
                 continue;
             }
 
-            if c == '"' {
-                in_string = true;
-                self.highlighting[i] = HighlightType::String;
-                i += 1;
+            let doc = doc_comments
+                .iter()
+                .any(|prefix| chars[i..].starts_with(prefix));
+
+            if let Some(n) = block_comments
+                .iter()
+                .position(|(start, _)| chars[i..].starts_with(start))
+            {
+                let len = block_comments[n].0.len();
+                let (kind, next) = if doc {
+                    (HighlightType::DocComment, LineState::DocComment(n))
+                } else {
+                    (HighlightType::Comment, LineState::Comment(n))
+                };
+                self.highlighting[i..i + len].fill(kind);
+                i += len;
+                state = next;
                 continue;
             }
 
             // Comment
-            let comment_start = syntax.single_line_comment;
-            if !comment_start.is_empty()
-                && i + comment_start.len() <= chars.len()
-                && &self.content[i..i + comment_start.len()] == comment_start
-            {
-                while i < chars.len() {
-                    self.highlighting[i] = HighlightType::Comment;
-                    i += 1;
-                }
+            if !comment_start.is_empty() && chars[i..].starts_with(&comment_start) {
+                let kind = if doc {
+                    HighlightType::DocComment
+                } else {
+                    HighlightType::Comment
+                };
+                self.highlighting[i..].fill(kind);
                 break;
             }
 
-            if c.is_ascii_digit() {
-                self.highlighting[i] = HighlightType::Number;
+            if let Some(len) = char_literal(&chars[i..], &char_quote) {
+                self.highlighting[i..i + len].fill(HighlightType::String);
+                i += len;
+                continue;
+            }
+
+            if let Some(n) = quotes
+                .iter()
+                .position(|(start, _)| chars[i..].starts_with(start))
+            {
+                let len = quotes[n].0.len();
+                self.highlighting[i..i + len].fill(HighlightType::String);
+                i += len;
+                state = LineState::String(n);
+                continue;
             }
 
-            // Token detection (Keywords, Types, ControlFlow)
-            let mut matched_token = false;
-            for (tokens, highlight_type) in [
-                (syntax.types, HighlightType::Type),
-                (syntax.control_flow, HighlightType::ControlFlow),
-                (syntax.keywords, HighlightType::Keyword),
-            ] {
-                for &token in tokens {
-                    let token_chars: Vec<char> = token.chars().collect();
-                    if i + token_chars.len() <= chars.len() {
-                        let matches = chars[i..i + token_chars.len()]
-                            .iter()
-                            .zip(token_chars.iter())
-                            .all(|(a, b)| a == b);
-
-                        if matches {
-                            // Check boundaries (must be separated by non-alphanumeric, except '_')
-                            let before_ok =
-                                i == 0 || !chars[i - 1].is_alphanumeric() && chars[i - 1] != '_';
-                            let after_ok = i + token_chars.len() == chars.len()
-                                || !chars[i + token_chars.len()].is_alphanumeric()
-                                    && chars[i + token_chars.len()] != '_';
-
-                            if before_ok && after_ok {
-                                for j in 0..token_chars.len() {
-                                    self.highlighting[i + j] = highlight_type;
-                                }
-                                i += token_chars.len() - 1;
-                                matched_token = true;
-                                break;
-                            }
-                        }
-                    }
+            // Token detection (Keywords, Types, ControlFlow), at the start
+            // of a word (separated by non-alphanumeric, except '_')
+            let word_start = i == 0 || !chars[i - 1].is_alphanumeric() && chars[i - 1] != '_';
+            if keywords && word_start {
+                let token = [
+                    (&syntax.types, HighlightType::Type),
+                    (&syntax.control_flow, HighlightType::ControlFlow),
+                    (&syntax.keywords, HighlightType::Keyword),
+                ]
+                .into_iter()
+                .find_map(|(tokens, highlight_type)| {
+                    tokens.iter().find_map(|token| {
+                        let len = token.chars().count();
+                        let end = i + len;
+                        let matches = len > 0
+                            && end <= chars.len()
+                            && chars[i..end].iter().copied().eq(token.chars())
+                            && (end == chars.len()
+                                || !chars[end].is_alphanumeric() && chars[end] != '_');
+                        matches.then_some((len, highlight_type))
+                    })
+                });
+                if let Some((len, highlight_type)) = token {
+                    self.highlighting[i..i + len].fill(highlight_type);
+                    i += len;
+                    continue;
                 }
-                if matched_token {
-                    break;
+            }
+
+            // Other names, skipped whole so their digits aren't numbers. One
+            // followed by `(` is a function call
+            if word_start && (c.is_alphabetic() || c == '_') {
+                let end = (i..chars.len())
+                    .find(|&j| !chars[j].is_alphanumeric() && chars[j] != '_')
+                    .unwrap_or(chars.len());
+                if keywords && chars.get(end) == Some(&'(') {
+                    self.highlighting[i..end].fill(HighlightType::Function);
                 }
+                i = end;
+                continue;
+            }
+
+            if keywords {
+                self.highlighting[i] = match c {
+                    '0'..='9' => HighlightType::Number,
+                    c if syntax.operators.contains(c) => HighlightType::Operator,
+                    c if syntax.punctuation.contains(c) => HighlightType::Punctuation,
+                    _ => HighlightType::None,
+                };
             }
 
             i += 1;
         }
+
+        match state {
+            LineState::String(n) if !syntax.strings[n].multiline && !continued => LineState::Normal,
+            state => state,
+        }
+    }
+}
+
+/// Char ranges of `line` matched by `syntax.patterns` (or their first group),
+/// by where they start; for the same start, the pattern given first comes
+/// first.
+fn pattern_matches(line: &str, syntax: &Syntax) -> Vec<(usize, usize, HighlightType)> {
+    let patterns = syntax.pattern_regexes();
+    if patterns.is_empty() {
+        return Vec::new();
+    }
+    let char_index = |byte: usize| line[..byte].chars().count();
+    let mut matches = Vec::new();
+    for (regex, kind) in patterns {
+        for captures in regex.captures_iter(line) {
+            let Some(range) = captures.get(1).or_else(|| captures.get(0)) else {
+                continue;
+            };
+            if !range.is_empty() {
+                matches.push((char_index(range.start()), char_index(range.end()), *kind));
+            }
+        }
+    }
+    matches.sort_by_key(|&(start, _, _)| start);
+    matches
+}
+
+/// Where a line starts or ends for `Row::update_highlighting`: in ordinary
+/// code, or inside a comment or string that goes on to the next line.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LineState {
+    #[default]
+    Normal,
+    /// In `syntax.block_comments[n]`.
+    Comment(usize),
+    /// In `syntax.block_comments[n]`, which started with one of
+    /// `syntax.doc_comments`.
+    DocComment(usize),
+    /// In `syntax.strings[n]`.
+    String(usize),
+}
+
+/// Length of the character literal at the start of `chars`, like `'a'` or
+/// `'\u{7FFF}'`, if there is one. A quote without a closing one, like a Rust
+/// lifetime, isn't one.
+fn char_literal(chars: &[char], quote: &[char]) -> Option<usize> {
+    if quote.is_empty() || !chars.starts_with(quote) {
+        return None;
+    }
+    let rest = &chars[quote.len()..];
+    let len = match rest.first()? {
+        '\\' => {
+            // Escapes run to the closing quote, within reason
+            (2..rest.len().min(12)).find(|&i| rest[i..].starts_with(quote))?
+        }
+        _ if rest[1..].starts_with(quote) => 1,
+        _ => return None,
+    };
+    Some(quote.len() * 2 + len)
+}
+
+/// Number of terminal columns `grapheme` takes up when drawn at `column`.
+/// Tabs reach to the next tab stop; everything else takes at least one column.
+pub fn grapheme_width(grapheme: &str, column: usize, tab_size: usize) -> usize {
+    if grapheme == "\t" {
+        let tab_size = tab_size.max(1);
+        tab_size - column % tab_size
+    } else {
+        grapheme.width().max(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FAMILY: &str = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+
+    #[test]
+    fn grapheme_widths() {
+        assert_eq!(grapheme_width("a", 0, 4), 1);
+        assert_eq!(grapheme_width("日", 0, 4), 2);
+        assert_eq!(grapheme_width("e\u{301}", 0, 4), 1);
+        assert_eq!(grapheme_width(FAMILY, 0, 4), 2);
+        // Zero-width on its own still takes a column
+        assert_eq!(grapheme_width("\u{200B}", 0, 4), 1);
+        // Tabs reach the next tab stop
+        assert_eq!(grapheme_width("\t", 0, 4), 4);
+        assert_eq!(grapheme_width("\t", 3, 4), 1);
+        assert_eq!(grapheme_width("\t", 4, 4), 4);
+        assert_eq!(grapheme_width("\t", 5, 8), 3);
+        assert_eq!(grapheme_width("\t", 7, 0), 1);
+    }
+
+    #[test]
+    fn columns_with_tabs() {
+        let row = Row::from("a\tb\t\tc");
+        assert_eq!(row.column_of(0, 4), 0);
+        assert_eq!(row.column_of(1, 4), 1);
+        assert_eq!(row.column_of(2, 4), 4);
+        assert_eq!(row.column_of(3, 4), 5);
+        assert_eq!(row.column_of(5, 4), 12);
+        assert_eq!(row.column_of(5, 8), 24);
+        // Any column the tab covers is the tab
+        assert_eq!(row.index_at_column(1, 4), 1);
+        assert_eq!(row.index_at_column(3, 4), 1);
+        assert_eq!(row.index_at_column(4, 4), 2);
+        assert_eq!(row.index_at_column(11, 4), 4);
+        assert_eq!(row.index_at_column(12, 4), 5);
+        assert_eq!(row.index_at_column(100, 4), row.len());
+    }
+
+    #[test]
+    fn columns_with_wide_and_combined_chars() {
+        // "日", "e" + combining acute, the family emoji (5 chars), "x"
+        let row = Row::from(format!("日e\u{301}{}x", FAMILY).as_str());
+        assert_eq!(row.len(), 9);
+        assert_eq!(row.column_of(1, 4), 2);
+        assert_eq!(row.column_of(3, 4), 3);
+        assert_eq!(row.column_of(8, 4), 5);
+        assert_eq!(row.index_at_column(0, 4), 0);
+        assert_eq!(row.index_at_column(1, 4), 0);
+        assert_eq!(row.index_at_column(2, 4), 1);
+        assert_eq!(row.index_at_column(3, 4), 3);
+        assert_eq!(row.index_at_column(4, 4), 3);
+        assert_eq!(row.index_at_column(5, 4), 8);
+    }
+
+    #[test]
+    fn grapheme_steps() {
+        let row = Row::from(format!("a{}e\u{301}日", FAMILY).as_str());
+        // Starts: a 0, family 1, e + accent 6, 日 8
+        assert_eq!(row.next_grapheme(0), 1);
+        assert_eq!(row.next_grapheme(1), 6);
+        assert_eq!(row.next_grapheme(3), 6);
+        assert_eq!(row.next_grapheme(6), 8);
+        assert_eq!(row.next_grapheme(8), 9);
+        assert_eq!(row.next_grapheme(9), 9);
+        assert_eq!(row.prev_grapheme(9), 8);
+        assert_eq!(row.prev_grapheme(8), 6);
+        assert_eq!(row.prev_grapheme(7), 6);
+        assert_eq!(row.prev_grapheme(6), 1);
+        assert_eq!(row.prev_grapheme(1), 0);
+        assert_eq!(row.prev_grapheme(0), 0);
+        assert_eq!(row.grapheme_start(4), 1);
+        assert_eq!(row.grapheme_start(7), 6);
+        assert_eq!(row.grapheme_start(20), 9);
+    }
+
+    #[test]
+    fn empty_row() {
+        let row = Row::from("");
+        assert_eq!(row.column_of(3, 4), 0);
+        assert_eq!(row.index_at_column(3, 4), 0);
+        assert_eq!(row.next_grapheme(0), 0);
+        assert_eq!(row.prev_grapheme(0), 0);
     }
 }
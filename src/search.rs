@@ -0,0 +1,260 @@
+use regex::{Regex, RegexBuilder};
+use std::ops::Range;
+
+/// Compiles a `/` or `?` pattern with smart-case: the search ignores case
+/// unless the pattern contains an upper-case letter. As in Vim, `\c` or `\C`
+/// anywhere in the pattern forces case-insensitive or case-sensitive matching.
+pub fn compile(pattern: &str) -> Result<Regex, regex::Error> {
+    compile_with_case(pattern, None)
+}
+
+fn compile_with_case(pattern: &str, ignore_case: Option<bool>) -> Result<Regex, regex::Error> {
+    let mut cleaned = String::with_capacity(pattern.len());
+    let mut forced = None;
+    let mut has_upper = false;
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            has_upper |= c.is_uppercase();
+            cleaned.push(c);
+            continue;
+        }
+        // Escapes like `\S` or `\W` are classes, not upper-case letters
+        match chars.next() {
+            Some('c') => forced = Some(true),
+            Some('C') => forced = Some(false),
+            Some(next) => {
+                cleaned.push('\\');
+                cleaned.push(next);
+            }
+            None => cleaned.push('\\'),
+        }
+    }
+
+    let ignore_case = forced.or(ignore_case).unwrap_or(!has_upper);
+    RegexBuilder::new(&cleaned)
+        .case_insensitive(ignore_case)
+        .build()
+}
+
+/// A parsed `:s/pattern/replacement/flags` command.
+pub struct Substitution {
+    /// Zero-based, end-exclusive range of lines to work on.
+    pub lines: Range<usize>,
+    /// Empty means "reuse the last search pattern".
+    pub pattern: String,
+    /// Replacement in `regex` expansion syntax (`${1}` rather than `\1`).
+    pub replacement: String,
+    pub global: bool,
+    ignore_case: Option<bool>,
+}
+
+impl Substitution {
+    /// Parses `cmd` if it is a substitute command, e.g. `s/a/b/`, `%s#a#b#g`
+    /// or `3,7s/(x)/[\1]/`. `current` and `line_count` resolve the range.
+    /// Returns `None` for anything that isn't a substitution.
+    pub fn parse(cmd: &str, current: usize, line_count: usize) -> Option<Result<Self, String>> {
+        let split = cmd.find(|c: char| c.is_ascii_alphabetic())?;
+        let (range, rest) = cmd.split_at(split);
+        let rest = rest
+            .strip_prefix("substitute")
+            .or_else(|| rest.strip_prefix('s'))?;
+        let delimiter = rest.chars().next()?;
+        if delimiter.is_alphanumeric() || delimiter.is_whitespace() || delimiter == '\\' {
+            return None;
+        }
+        let lines = match parse_range(range, current, line_count) {
+            Some(lines) => lines,
+            None => return Some(Err(format!("Invalid range: {}", range))),
+        };
+
+        let mut parts = split_delimited(&rest[delimiter.len_utf8()..], delimiter);
+        let pattern = parts.next().unwrap_or_default();
+        let replacement = translate_replacement(&parts.next().unwrap_or_default());
+        let flags = parts.next().unwrap_or_default();
+
+        let mut global = false;
+        let mut ignore_case = None;
+        for flag in flags.trim().chars() {
+            match flag {
+                'g' => global = true,
+                'i' => ignore_case = Some(true),
+                'I' => ignore_case = Some(false),
+                _ => return Some(Err(format!("Trailing characters: {}", flags))),
+            }
+        }
+
+        Some(Ok(Self {
+            lines,
+            pattern,
+            replacement,
+            global,
+            ignore_case,
+        }))
+    }
+
+    /// Compiles `pattern` honouring the `i`/`I` flags, falling back to smart-case.
+    pub fn regex(&self, pattern: &str) -> Result<Regex, regex::Error> {
+        compile_with_case(pattern, self.ignore_case)
+    }
+}
+
+/// Resolves `""`, `%`, `N` and `N,M` (where each address may also be `.` or `$`).
+fn parse_range(range: &str, current: usize, line_count: usize) -> Option<Range<usize>> {
+    let address = |s: &str| -> Option<usize> {
+        match s.trim() {
+            "." => Some(current),
+            "$" => Some(line_count.saturating_sub(1)),
+            n => n.parse::<usize>().ok().map(|n| n.saturating_sub(1)),
+        }
+    };
+    let (start, end) = match range.trim() {
+        "" => (current, current),
+        "%" => (0, line_count.saturating_sub(1)),
+        range => match range.split_once(',') {
+            Some((start, end)) => (address(start)?, address(end)?),
+            None => (address(range)?, address(range)?),
+        },
+    };
+    let (start, end) = (start.min(end), start.max(end));
+    if end >= line_count {
+        return None;
+    }
+    Some(start..end + 1)
+}
+
+/// Splits on unescaped `delimiter`s. An escaped delimiter loses its backslash;
+/// every other escape is kept for the regex or replacement to interpret.
+fn split_delimited(text: &str, delimiter: char) -> impl Iterator<Item = String> {
+    let mut parts = vec![String::new()];
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        let part = parts.last_mut().unwrap();
+        if c == '\\' {
+            match chars.next() {
+                Some(next) if next == delimiter => part.push(next),
+                Some(next) => {
+                    part.push('\\');
+                    part.push(next);
+                }
+                None => part.push('\\'),
+            }
+        } else if c == delimiter {
+            parts.push(String::new());
+        } else {
+            part.push(c);
+        }
+    }
+    parts.into_iter()
+}
+
+/// Converts a Vim replacement string (`\1`, `&`, `\n`) into `regex` syntax.
+fn translate_replacement(replacement: &str) -> String {
+    let mut out = String::with_capacity(replacement.len());
+    let mut chars = replacement.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '&' => out.push_str("${0}"),
+            '$' => out.push_str("$$"),
+            '\\' => match chars.next() {
+                Some(d @ '0'..='9') => {
+                    out.push_str("${");
+                    out.push(d);
+                    out.push('}');
+                }
+                Some('n') | Some('r') => out.push('\n'),
+                Some('t') => out.push('\t'),
+                Some('$') => out.push_str("$$"),
+                Some(other) => out.push(other),
+                None => out.push('\\'),
+            },
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(cmd: &str) -> Substitution {
+        Substitution::parse(cmd, 2, 10).unwrap().unwrap()
+    }
+
+    #[test]
+    fn other_delimiters() {
+        let sub = parse("s#a/b#c#");
+        assert_eq!(sub.pattern, "a/b");
+        assert_eq!(sub.replacement, "c");
+        let sub = parse("s|x|y");
+        assert_eq!((sub.pattern.as_str(), sub.replacement.as_str()), ("x", "y"));
+    }
+
+    #[test]
+    fn escaped_delimiter_loses_its_backslash() {
+        let sub = parse(r"s/a\/b/c\/d/");
+        assert_eq!(sub.pattern, "a/b");
+        assert_eq!(sub.replacement, "c/d");
+        // Other escapes are left for the regex
+        assert_eq!(parse(r"s/\d\+/x/").pattern, r"\d\+");
+    }
+
+    #[test]
+    fn flags() {
+        let sub = parse("s/a/b/");
+        assert!(!sub.global);
+        assert_eq!(sub.ignore_case, None);
+        let sub = parse("s/a/b/gi");
+        assert!(sub.global);
+        assert_eq!(sub.ignore_case, Some(true));
+        assert_eq!(parse("s/a/b/I").ignore_case, Some(false));
+        assert!(Substitution::parse("s/a/b/gx", 0, 1).unwrap().is_err());
+    }
+
+    #[test]
+    fn case_flags_override_smart_case() {
+        let sub = parse("s/x/y/i");
+        assert!(sub.regex("ABC").unwrap().is_match("abc"));
+        let sub = parse("s/x/y/I");
+        assert!(!sub.regex("abc").unwrap().is_match("ABC"));
+        // Smart-case without a flag
+        let sub = parse("s/x/y/");
+        assert!(sub.regex("abc").unwrap().is_match("ABC"));
+        assert!(!sub.regex("Abc").unwrap().is_match("ABC"));
+    }
+
+    #[test]
+    fn replacement_groups_and_ampersand() {
+        assert_eq!(parse(r"s/(a)(b)/\2\1/").replacement, "${2}${1}");
+        assert_eq!(parse("s/a/[&]/").replacement, "[${0}]");
+        assert_eq!(parse(r"s/a/\&/").replacement, "&");
+        assert_eq!(parse(r"s/a/x\ny\t/").replacement, "x\ny\t");
+    }
+
+    #[test]
+    fn dollar_in_replacement_is_literal() {
+        let sub = parse("s/(x)/$1 costs $5/");
+        assert_eq!(sub.replacement, "$$1 costs $$5");
+        let regex = sub.regex("(x)").unwrap();
+        assert_eq!(regex.replace("x", sub.replacement.as_str()), "$1 costs $5");
+        assert_eq!(parse(r"s/a/\$/").replacement, "$$");
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse("s/a/b/").lines, 2..3);
+        assert_eq!(parse("%s/a/b/").lines, 0..10);
+        assert_eq!(parse("3,5s/a/b/").lines, 2..5);
+        assert_eq!(parse(".,$s/a/b/").lines, 2..10);
+        assert_eq!(parse("5,3s/a/b/").lines, 2..5);
+        assert!(Substitution::parse("20s/a/b/", 0, 10).unwrap().is_err());
+    }
+
+    #[test]
+    fn not_a_substitution() {
+        assert!(Substitution::parse("set", 0, 1).is_none());
+        assert!(Substitution::parse("sa", 0, 1).is_none());
+        assert!(Substitution::parse("s a b", 0, 1).is_none());
+    }
+}
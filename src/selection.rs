@@ -0,0 +1,209 @@
+use crate::document::Document;
+use crate::editor::Position;
+use crate::row::{self, Row};
+use std::ops::{Range, RangeInclusive};
+
+/// The text covered by a Visual-mode selection, from its anchor to the cursor.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Selection {
+    /// `v`: every char from `start` to `end`, both included.
+    Chars { start: Position, end: Position },
+    /// `V`: whole lines `first..=last`.
+    Lines { first: usize, last: usize },
+    /// `Ctrl-v`: display columns `left..=right` of lines `top..=bottom`.
+    Block {
+        top: usize,
+        bottom: usize,
+        left: usize,
+        right: usize,
+    },
+}
+
+impl Selection {
+    pub fn chars(anchor: Position, cursor: Position) -> Self {
+        let (start, end) = if (anchor.y, anchor.x) <= (cursor.y, cursor.x) {
+            (anchor, cursor)
+        } else {
+            (cursor, anchor)
+        };
+        Selection::Chars { start, end }
+    }
+
+    pub fn lines(anchor: Position, cursor: Position) -> Self {
+        Selection::Lines {
+            first: anchor.y.min(cursor.y),
+            last: anchor.y.max(cursor.y),
+        }
+    }
+
+    /// A block spans the display columns of both corners, so a tab or wide
+    /// char under either corner is covered entirely.
+    pub fn block(document: &Document, anchor: Position, cursor: Position, tab_size: usize) -> Self {
+        let span = |pos: Position| {
+            document.row(pos.y).map_or((0, 0), |row| {
+                let start = row.column_of(pos.x, tab_size);
+                let width = row
+                    .graphemes()
+                    .find(|&(index, _)| index == pos.x)
+                    .map_or(1, |(_, g)| row::grapheme_width(g, start, tab_size).max(1));
+                (start, start + width - 1)
+            })
+        };
+        let (a_left, a_right) = span(anchor);
+        let (c_left, c_right) = span(cursor);
+        Selection::Block {
+            top: anchor.y.min(cursor.y),
+            bottom: anchor.y.max(cursor.y),
+            left: a_left.min(c_left),
+            right: a_right.max(c_right),
+        }
+    }
+
+    /// The lines the selection touches.
+    pub fn line_range(&self) -> RangeInclusive<usize> {
+        match *self {
+            Selection::Chars { start, end } => start.y..=end.y,
+            Selection::Lines { first, last } => first..=last,
+            Selection::Block { top, bottom, .. } => top..=bottom,
+        }
+    }
+
+    /// Whether the grapheme starting at char `x` of line `y`, drawn over
+    /// display columns `columns`, is selected.
+    pub fn contains(&self, y: usize, x: usize, columns: Range<usize>) -> bool {
+        if !self.line_range().contains(&y) {
+            return false;
+        }
+        match *self {
+            Selection::Chars { start, end } => {
+                (y > start.y || x >= start.x) && (y < end.y || x <= end.x)
+            }
+            Selection::Lines { .. } => true,
+            Selection::Block { left, right, .. } => columns.start <= right && columns.end > left,
+        }
+    }
+}
+
+/// Char range of `row` drawn inside display columns `left..=right`. A grapheme
+/// straddling either edge is included.
+pub fn block_span(row: &Row, left: usize, right: usize, tab_size: usize) -> Range<usize> {
+    let mut start = None;
+    let mut end = row.len();
+    let mut column = 0;
+    for (index, grapheme) in row.graphemes() {
+        let next = column + row::grapheme_width(grapheme, column, tab_size);
+        if column > right {
+            end = index;
+            break;
+        }
+        if start.is_none() && next > left {
+            start = Some(index);
+        }
+        column = next;
+    }
+    let start = start.unwrap_or(row.len());
+    start..end.max(start)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ropey::Rope;
+
+    fn doc(text: &str) -> Document {
+        let mut document = Document::default();
+        document.content = Rope::from_str(text);
+        document
+    }
+
+    fn pos(x: usize, y: usize) -> Position {
+        Position { x, y }
+    }
+
+    fn block(left: usize, right: usize) -> Selection {
+        Selection::Block {
+            top: 0,
+            bottom: 2,
+            left,
+            right,
+        }
+    }
+
+    #[test]
+    fn block_from_reversed_anchors() {
+        let d = doc("abcdef\nab\n日本語x\n");
+        // The anchor is below and to the right of the cursor, and the
+        // cursor's "本" is two columns wide
+        let selection = Selection::block(&d, pos(1, 2), pos(4, 0), 4);
+        assert_eq!(selection, block(2, 4));
+        assert_eq!(Selection::block(&d, pos(4, 0), pos(1, 2), 4), selection);
+    }
+
+    #[test]
+    fn block_corner_on_a_tab_covers_the_whole_tab() {
+        let d = doc("a\tb\nabcdef\n");
+        let selection = Selection::block(&d, pos(1, 0), pos(0, 1), 4);
+        assert_eq!(
+            selection,
+            Selection::Block {
+                top: 0,
+                bottom: 1,
+                left: 0,
+                right: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn block_span_over_ragged_and_wide_lines() {
+        assert_eq!(block_span(&Row::from("abcdef"), 2, 4, 4), 2..5);
+        // A line that ends before the block has nothing in it
+        assert_eq!(block_span(&Row::from("ab"), 2, 4, 4), 2..2);
+        assert_eq!(block_span(&Row::from("abc"), 2, 4, 4), 2..3);
+        assert_eq!(block_span(&Row::from(""), 0, 4, 4), 0..0);
+        // "本" at columns 2-3 and "語" at 4-5 are both in
+        assert_eq!(block_span(&Row::from("日本語x"), 2, 4, 4), 1..3);
+        // A wide char straddling an edge is included
+        assert_eq!(block_span(&Row::from("日本語x"), 1, 1, 4), 0..1);
+        assert_eq!(block_span(&Row::from("日本語x"), 3, 6, 4), 1..4);
+        // So is a tab (columns 1-3)
+        assert_eq!(block_span(&Row::from("a\tb"), 2, 2, 4), 1..2);
+        assert_eq!(block_span(&Row::from("a\tb"), 4, 4, 4), 2..3);
+    }
+
+    #[test]
+    fn contains_block() {
+        let selection = block(2, 4);
+        assert!(selection.contains(1, 0, 2..3));
+        assert!(selection.contains(1, 0, 4..5));
+        // Wide chars straddling either edge
+        assert!(selection.contains(1, 0, 1..3));
+        assert!(selection.contains(1, 0, 4..6));
+        assert!(!selection.contains(1, 0, 0..2));
+        assert!(!selection.contains(1, 0, 5..7));
+        assert!(!selection.contains(3, 0, 2..3));
+    }
+
+    #[test]
+    fn contains_chars_and_lines() {
+        let selection = Selection::chars(pos(1, 1), pos(2, 0));
+        assert_eq!(
+            selection,
+            Selection::Chars {
+                start: pos(2, 0),
+                end: pos(1, 1),
+            }
+        );
+        assert!(!selection.contains(0, 1, 1..2));
+        assert!(selection.contains(0, 2, 2..3));
+        assert!(selection.contains(0, 50, 50..51));
+        assert!(selection.contains(1, 1, 1..2));
+        assert!(!selection.contains(1, 2, 2..3));
+        assert!(!selection.contains(2, 0, 0..1));
+
+        let selection = Selection::lines(pos(5, 3), pos(0, 1));
+        assert_eq!(selection.line_range(), 1..=3);
+        assert!(selection.contains(2, 100, 100..101));
+        assert!(!selection.contains(0, 0, 0..1));
+    }
+}
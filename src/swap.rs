@@ -0,0 +1,116 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::hash::{BuildHasher, RandomState};
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Bumped whenever the layout of swap files changes.
+const SWAP_FILE_VERSION: u32 = 1;
+
+/// The unsaved text of a buffer, written periodically while it has changes
+/// so they can be recovered if the editor dies before they are saved.
+#[derive(Serialize, Deserialize)]
+pub struct SwapFile {
+    version: u32,
+    /// The file the text belongs to, as it was opened.
+    pub file_name: String,
+    /// The process that wrote the swap file.
+    pub pid: u32,
+    /// Random number identifying the session that wrote the swap file. The
+    /// pid alone can't: a new process may well get the pid of one that died.
+    session: u64,
+    /// When the swap file was written, in seconds since the Unix epoch.
+    pub modified: u64,
+    pub text: String,
+}
+
+impl SwapFile {
+    pub fn new(file_name: &str, text: String) -> Self {
+        let modified = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        Self {
+            version: SWAP_FILE_VERSION,
+            file_name: file_name.to_string(),
+            pid: std::process::id(),
+            session: session_id(),
+            modified,
+            text,
+        }
+    }
+
+    /// Whether this process wrote the swap file, e.g. before `:e!`.
+    pub fn is_own(&self) -> bool {
+        self.session == session_id()
+    }
+
+    /// Writes the swap file for `file_name`, replacing any earlier one.
+    pub fn write(&self) -> Result<(), Error> {
+        let path = path_for(&self.file_name)?;
+        let json = serde_json::to_vec(self).map_err(Error::other)?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, json)?;
+        fs::rename(&tmp_path, path)
+    }
+
+    /// Reads the swap file left for `file_name`. Fails with `NotFound` if
+    /// there is none.
+    pub fn read(file_name: &str) -> Result<Self, Error> {
+        let json = fs::read(path_for(file_name)?)?;
+        let swap: SwapFile =
+            serde_json::from_slice(&json).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        if swap.version != SWAP_FILE_VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported version {}", swap.version),
+            ));
+        }
+        Ok(swap)
+    }
+}
+
+/// This session's `SwapFile::session`, picked at random on first use.
+fn session_id() -> u64 {
+    static SESSION: OnceLock<u64> = OnceLock::new();
+    *SESSION.get_or_init(|| {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos());
+        // `RandomState` is seeded from the OS's random number generator
+        RandomState::new().hash_one((nanos, std::process::id()))
+    })
+}
+
+/// Deletes the swap file for `file_name`, if there is one.
+pub fn remove(file_name: &str) {
+    if let Ok(path) = path_for(file_name) {
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// Swap files live in `~/.local/state/meow/swap/`, see `config::state_file`.
+fn path_for(file_name: &str) -> Result<PathBuf, Error> {
+    crate::config::state_file("swap", Path::new(file_name))
+        .ok_or_else(|| Error::other("No place to keep swap files"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_pid_from_another_session_is_not_own() {
+        let swap = SwapFile::new("file.txt", "text".to_string());
+        assert!(swap.is_own());
+        let other = SwapFile {
+            session: swap.session.wrapping_add(1),
+            ..swap
+        };
+        assert!(!other.is_own());
+    }
+}
@@ -1,258 +1,695 @@
+use crate::row::HighlightType;
+use regex::Regex;
+use serde::Deserialize;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, OnceLock};
+
+/// How a language is recognised and highlighted. Built-in languages are the
+/// TOML files in `src/languages/`; files of the same format in
+/// `~/.config/meow/languages/` or `.config/languages/` add more, or replace a
+/// built-in one with the same `file_type`.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
 pub struct Syntax {
-    pub file_type: &'static str,
-    pub file_extensions: &'static [&'static str],
-    pub keywords: &'static [&'static str],
-    pub types: &'static [&'static str],
-    pub control_flow: &'static [&'static str],
-    pub single_line_comment: &'static str,
+    pub file_type: String,
+    pub file_extensions: Vec<String>,
+    /// Whole file names, e.g. `Makefile`.
+    pub file_names: Vec<String>,
+    /// Glob patterns for file names, e.g. `Dockerfile.*`. A pattern with a
+    /// `/` in it is matched against the end of the path, e.g. `.git/config`.
+    pub file_globs: Vec<String>,
+    /// Regexes for the program a `#!` line runs, e.g. `python[0-9.]*`.
+    pub shebangs: Vec<String>,
+    pub keywords: Vec<String>,
+    pub types: Vec<String>,
+    pub control_flow: Vec<String>,
+    pub single_line_comment: String,
+    /// Start and end of comments that can span lines, e.g. `["/*", "*/"]`.
+    pub block_comments: Vec<(String, String)>,
+    /// How comments that document something start, e.g. `/**` or `///`.
+    pub doc_comments: Vec<String>,
+    /// Quotes that start a string, which lasts until the same quote or the
+    /// end of the line. Shorthand for `strings` entries with only a `start`.
+    pub string_delimiters: Vec<String>,
+    /// Kinds of string, longest `start` first once parsed. The
+    /// `string_delimiters` are added to them.
+    pub strings: Vec<Quote>,
+    /// The quote around a character literal: `'` for `'a'` and `'\n'`, but not
+    /// for a Rust lifetime like `'a`.
+    pub char_delimiter: String,
+    /// Characters highlighted as operators.
+    pub operators: String,
+    /// Characters highlighted as punctuation.
+    pub punctuation: String,
+    /// Regexes for what the rest can't describe, like Markdown headings.
+    pub patterns: Vec<Pattern>,
+    // `shebangs` as one regex
+    #[serde(skip)]
+    shebang: Option<Regex>,
+    // `file_globs` as one regex
+    #[serde(skip)]
+    glob: Option<Regex>,
+    // `patterns`, compiled
+    #[serde(skip)]
+    pattern_regexes: Vec<(Regex, HighlightType)>,
 }
 
 impl Default for Syntax {
     fn default() -> Self {
         Syntax {
-            file_type: "Text",
-            file_extensions: &[],
-            keywords: &[],
-            types: &[],
-            control_flow: &[],
-            single_line_comment: "",
+            file_type: String::new(),
+            file_extensions: Vec::new(),
+            file_names: Vec::new(),
+            file_globs: Vec::new(),
+            shebangs: Vec::new(),
+            keywords: Vec::new(),
+            types: Vec::new(),
+            control_flow: Vec::new(),
+            single_line_comment: String::new(),
+            block_comments: Vec::new(),
+            doc_comments: Vec::new(),
+            string_delimiters: vec!["\"".to_string()],
+            strings: Vec::new(),
+            char_delimiter: String::new(),
+            operators: "+-*/%=<>!&|^~?:".to_string(),
+            punctuation: "()[]{},;.".to_string(),
+            patterns: Vec::new(),
+            shebang: None,
+            glob: None,
+            pattern_regexes: Vec::new(),
         }
     }
 }
 
+/// A kind of string literal, e.g. Python's `"""..."""` or Rust's `r#"..."#`.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Quote {
+    pub start: String,
+    /// Defaults to `start`.
+    #[serde(default)]
+    pub end: String,
+    /// Backslashes don't escape anything.
+    #[serde(default)]
+    pub raw: bool,
+    /// The string can go on past the end of the line. Others only do after a
+    /// backslash at the end of the line.
+    #[serde(default)]
+    pub multiline: bool,
+}
+
+/// A regex and how to highlight what it matches, or its first group if it
+/// has one. `kind` is a theme key like `keyword` or `doc_comment`.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Pattern {
+    pub regex: String,
+    pub kind: String,
+}
+
 impl Syntax {
-    pub fn select(filename: &str) -> &'static Syntax {
-        let parts: Vec<&str> = filename.split('.').collect();
-        if let Some(ext) = parts.last() {
-            for syntax in SYNTAX_LIST.iter() {
-                if syntax.file_extensions.contains(ext) {
-                    return syntax;
+    /// The language of a file named `file_name`. `lines` are the first and
+    /// last few lines of its text, first line first.
+    ///
+    /// A modeline (`vim: ft=python`, `-*- mode: python -*-`) decides if
+    /// there is one. Otherwise the file name is tried, then glob patterns,
+    /// then the extension, each again without a backup suffix like `.orig` or
+    /// `~`, and last the `#!` line. The user's languages are tried before the
+    /// built-in ones.
+    pub fn select(file_name: &str, lines: &[String]) -> Arc<Syntax> {
+        let languages = languages();
+        let by_modeline = || lines.iter().find_map(|line| Syntax::find(modeline(line)?));
+        let by_file_name = || {
+            let mut path = file_name;
+            loop {
+                if let Some(syntax) = by_path(languages, path) {
+                    return Some(Arc::clone(syntax));
+                }
+                path = strip_backup_suffix(path)?;
+            }
+        };
+        let by_shebang = || {
+            let program = interpreter(lines.first()?)?;
+            languages
+                .iter()
+                .find(|syntax| {
+                    syntax
+                        .shebang
+                        .as_ref()
+                        .is_some_and(|regex| regex.is_match(program))
+                })
+                .cloned()
+        };
+        by_modeline()
+            .or_else(by_file_name)
+            .or_else(by_shebang)
+            .unwrap_or_else(Syntax::plain)
+    }
+
+    /// The language called `name`, as in `:set filetype=name`: its
+    /// `file_type` in any case, or one of its extensions, so `cpp` and `sh`
+    /// work as well as `C++` and `Shell`.
+    pub fn find(name: &str) -> Option<Arc<Syntax>> {
+        if PLAIN.file_type.eq_ignore_ascii_case(name) {
+            return Some(Syntax::plain());
+        }
+        let languages = languages();
+        languages
+            .iter()
+            .find(|syntax| syntax.file_type.eq_ignore_ascii_case(name))
+            .or_else(|| {
+                languages
+                    .iter()
+                    .find(|syntax| syntax.file_extensions.iter().any(|e| e == name))
+            })
+            .cloned()
+    }
+
+    /// `patterns`, compiled, in the order they were given.
+    pub fn pattern_regexes(&self) -> &[(Regex, HighlightType)] {
+        &self.pattern_regexes
+    }
+
+    /// Plain text, without keywords or comments.
+    pub fn plain() -> Arc<Syntax> {
+        Arc::clone(&PLAIN)
+    }
+
+    /// Turns `string_delimiters` into `strings`, unless one with the same
+    /// start is there already, and sorts them so that `"""` is tried before
+    /// `"`.
+    fn add_string_delimiters(&mut self) {
+        for quote in &self.string_delimiters {
+            if !quote.is_empty() && !self.strings.iter().any(|q| q.start == *quote) {
+                self.strings.push(Quote {
+                    start: quote.clone(),
+                    end: String::new(),
+                    raw: false,
+                    multiline: false,
+                });
+            }
+        }
+        self.strings.retain(|quote| !quote.start.is_empty());
+        for quote in &mut self.strings {
+            if quote.end.is_empty() {
+                quote.end = quote.start.clone();
+            }
+        }
+        self.strings
+            .sort_by_key(|quote| std::cmp::Reverse(quote.start.chars().count()));
+    }
+}
+
+static PLAIN: LazyLock<Arc<Syntax>> = LazyLock::new(|| {
+    let mut syntax = Syntax {
+        file_type: "Text".to_string(),
+        operators: String::new(),
+        punctuation: String::new(),
+        ..Syntax::default()
+    };
+    syntax.add_string_delimiters();
+    Arc::new(syntax)
+});
+
+/// The languages compiled into the editor.
+const BUILTIN_LANGUAGES: &[(&str, &str)] = &[
+    ("rust.toml", include_str!("languages/rust.toml")),
+    ("c.toml", include_str!("languages/c.toml")),
+    ("cpp.toml", include_str!("languages/cpp.toml")),
+    ("java.toml", include_str!("languages/java.toml")),
+    ("javascript.toml", include_str!("languages/javascript.toml")),
+    ("python.toml", include_str!("languages/python.toml")),
+    ("typescript.toml", include_str!("languages/typescript.toml")),
+    ("go.toml", include_str!("languages/go.toml")),
+    ("shell.toml", include_str!("languages/shell.toml")),
+    ("toml.toml", include_str!("languages/toml.toml")),
+    ("json.toml", include_str!("languages/json.toml")),
+    ("yaml.toml", include_str!("languages/yaml.toml")),
+    ("markdown.toml", include_str!("languages/markdown.toml")),
+    ("makefile.toml", include_str!("languages/makefile.toml")),
+    ("dockerfile.toml", include_str!("languages/dockerfile.toml")),
+];
+
+static LANGUAGES: OnceLock<Vec<Arc<Syntax>>> = OnceLock::new();
+
+fn languages() -> &'static [Arc<Syntax>] {
+    LANGUAGES.get_or_init(|| load(&[]).0)
+}
+
+/// Loads the built-in languages and the user's. Called once at startup,
+/// before any file is opened. Returns a message for each language file that
+/// couldn't be used.
+pub fn load_languages() -> Vec<String> {
+    let mut dirs = Vec::new();
+    if let Some(home) = env::var_os("HOME") {
+        dirs.push(PathBuf::from(home).join(".config/meow/languages"));
+    }
+    dirs.push(PathBuf::from(".config/languages"));
+    let (languages, errors) = load(&dirs);
+    let _ = LANGUAGES.set(languages);
+    errors
+}
+
+/// The built-in languages plus those in `dirs`, where later files win.
+fn load(dirs: &[PathBuf]) -> (Vec<Arc<Syntax>>, Vec<String>) {
+    let mut languages: Vec<Syntax> = BUILTIN_LANGUAGES
+        .iter()
+        .map(|(name, text)| parse(text).unwrap_or_else(|e| panic!("{}: {}", name, e)))
+        .collect();
+    let mut errors = Vec::new();
+    for dir in dirs {
+        let Ok(entries) = fs::read_dir(dir) else {
+            continue;
+        };
+        let mut paths: Vec<PathBuf> = entries
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+            .collect();
+        paths.sort();
+        for path in paths {
+            let syntax = fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|text| parse(&text));
+            match syntax {
+                Ok(syntax) => {
+                    languages.retain(|other| other.file_type != syntax.file_type);
+                    languages.insert(0, syntax);
                 }
+                Err(e) => errors.push(format!("{}: {}", path.display(), e)),
             }
         }
-        &DEFAULT_SYNTAX
     }
+    (languages.into_iter().map(Arc::new).collect(), errors)
+}
 
-    pub fn default_ref() -> &'static Syntax {
-        &DEFAULT_SYNTAX
+fn parse(text: &str) -> Result<Syntax, String> {
+    let mut syntax: Syntax = toml::from_str(text).map_err(|e| match e.span() {
+        Some(span) => format!(
+            "line {}: {}",
+            text[..span.start].matches('\n').count() + 1,
+            e.message()
+        ),
+        None => e.message().to_string(),
+    })?;
+    if syntax.file_type.is_empty() {
+        return Err("file_type is missing".to_string());
+    }
+    if syntax
+        .block_comments
+        .iter()
+        .any(|(start, end)| start.is_empty() || end.is_empty())
+    {
+        return Err("block_comments need a start and an end".to_string());
+    }
+    syntax.add_string_delimiters();
+    for pattern in &syntax.patterns {
+        let regex = Regex::new(&pattern.regex).map_err(|e| e.to_string())?;
+        let kind = HighlightType::from_name(&pattern.kind)
+            .ok_or_else(|| format!("unknown pattern kind \"{}\"", pattern.kind))?;
+        syntax.pattern_regexes.push((regex, kind));
+    }
+    if !syntax.shebangs.is_empty() {
+        let pattern = format!("^(?:{})$", syntax.shebangs.join("|"));
+        syntax.shebang = Some(Regex::new(&pattern).map_err(|e| e.to_string())?);
     }
+    if !syntax.file_globs.is_empty() {
+        let globs: Vec<String> = syntax.file_globs.iter().map(|g| glob_to_regex(g)).collect();
+        let pattern = format!("(?:^|/)(?:{})$", globs.join("|"));
+        syntax.glob = Some(Regex::new(&pattern).map_err(|e| e.to_string())?);
+    }
+    Ok(syntax)
+}
+
+/// The language for `path` by its file name, glob patterns or extension.
+fn by_path<'a>(languages: &'a [Arc<Syntax>], path: &str) -> Option<&'a Arc<Syntax>> {
+    let name = Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(path);
+    let by_name = || {
+        languages
+            .iter()
+            .find(|syntax| syntax.file_names.iter().any(|n| n == name))
+    };
+    let by_glob = || {
+        let path = path.replace('\\', "/");
+        languages.iter().find(|syntax| {
+            syntax
+                .glob
+                .as_ref()
+                .is_some_and(|regex| regex.is_match(&path))
+        })
+    };
+    let by_extension = || {
+        let (_, extension) = name.rsplit_once('.')?;
+        languages
+            .iter()
+            .find(|syntax| syntax.file_extensions.iter().any(|e| e == extension))
+    };
+    by_name().or_else(by_glob).or_else(by_extension)
 }
 
-static DEFAULT_SYNTAX: Syntax = Syntax {
-    file_type: "Text",
-    file_extensions: &[],
-    keywords: &[],
-    types: &[],
-    control_flow: &[],
-    single_line_comment: "",
-};
-
-static SYNTAX_LIST: &[Syntax] = &[
-    Syntax {
-        file_type: "Rust",
-        file_extensions: &["rs"],
-        keywords: &[
-            "fn", "let", "mut", "pub", "use", "mod", "struct", "enum", "impl", "trait", "match",
-            "if", "else", "for", "while", "loop", "return", "break", "continue", "const", "static",
-            "type", "as", "ref", "in", "where", "crate", "super", "self", "Self", "true", "false",
-            "None", "Some", "Ok", "Err",
-        ],
-        single_line_comment: "//",
-        types: &[
-            "bool", "u8", "u16", "u32", "u64", "i8", "i16", "i32", "i64", "f32", "f64", "char",
-            "str", "String", "Vec", "Option", "Result", "Self",
-        ],
-        control_flow: &[
-            "match", "if", "else", "for", "while", "loop", "return", "break", "continue",
-        ],
-    },
-    Syntax {
-        file_type: "C",
-        file_extensions: &["c", "h"],
-        keywords: &[
-            "switch", "if", "while", "for", "break", "continue", "return", "else", "struct",
-            "union", "typedef", "static", "enum", "class", "case", "int", "long", "double",
-            "float", "char", "unsigned", "signed", "void", "NULL",
-        ],
-        single_line_comment: "//",
-        types: &[
-            "char",
-            "double",
-            "float",
-            "int",
-            "long",
-            "short",
-            "signed",
-            "unsigned",
-            "void",
-            "_Bool",
-            "_Complex",
-            "_Imaginary",
-        ],
-        control_flow: &[
-            "break", "case", "continue", "default", "do", "else", "for", "goto", "if", "return",
-            "switch", "while",
-        ],
-    },
-    Syntax {
-        file_type: "C++",
-        file_extensions: &["cpp", "hpp", "cc", "cxx", "hh"],
-        keywords: &[
-            "switch",
-            "if",
-            "while",
-            "for",
-            "break",
-            "continue",
-            "return",
-            "else",
-            "struct",
-            "union",
-            "typedef",
-            "static",
-            "enum",
-            "class",
-            "case",
-            "public",
-            "private",
-            "protected",
-            "friend",
-            "inline",
-            "virtual",
-            "template",
-            "using",
-            "namespace",
-            "true",
-            "false",
-            "int",
-            "long",
-            "double",
-            "float",
-            "char",
-            "unsigned",
-            "signed",
-            "void",
-            "NULL",
-        ],
-        single_line_comment: "//",
-        types: &[
-            "bool", "char", "double", "float", "int", "long", "short", "signed", "unsigned",
-            "void", "wchar_t",
-        ],
-        control_flow: &[
-            "break", "case", "catch", "continue", "default", "do", "else", "for", "goto", "if",
-            "return", "switch", "throw", "try", "while",
-        ],
-    },
-    Syntax {
-        file_type: "Java",
-        file_extensions: &["java"],
-        keywords: &[
-            "class",
-            "public",
-            "private",
-            "protected",
-            "static",
-            "final",
-            "void",
-            "return",
-            "if",
-            "else",
-            "for",
-            "while",
-            "do",
-            "break",
-            "continue",
-            "switch",
-            "case",
-            "default",
-            "try",
-            "catch",
-            "finally",
-            "import",
-            "package",
-            "new",
-            "this",
-            "super",
-            "int",
-            "double",
-            "float",
-            "boolean",
-            "char",
-            "true",
-            "false",
-            "null",
-        ],
-        single_line_comment: "//",
-        types: &[
-            "boolean", "byte", "char", "double", "float", "int", "long", "short", "void", "String",
-            "Integer", "Double",
-        ],
-        control_flow: &[
-            "break", "case", "catch", "continue", "default", "do", "else", "for", "goto", "if",
-            "return", "switch", "throw", "try", "while",
-        ],
-    },
-    Syntax {
-        file_type: "JavaScript",
-        file_extensions: &["js", "jsx", "ts", "tsx"],
-        keywords: &[
-            "function",
-            "let",
-            "var",
-            "const",
-            "if",
-            "else",
-            "for",
-            "while",
-            "do",
-            "return",
-            "break",
-            "continue",
-            "switch",
-            "case",
-            "default",
-            "try",
-            "catch",
-            "finally",
-            "class",
-            "extends",
-            "new",
-            "this",
-            "import",
-            "export",
-            "from",
-            "async",
-            "await",
-            "true",
-            "false",
-            "null",
-            "undefined",
-        ],
-        single_line_comment: "//",
-        types: &[
-            "boolean", "byte", "char", "double", "float", "int", "long", "short", "void", "any",
-            "string", "number", "Array", "Object",
-        ],
-        control_flow: &[
-            "await", "break", "case", "catch", "continue", "default", "do", "else", "finally",
-            "for", "if", "return", "switch", "throw", "try", "while",
-        ],
-    },
-    Syntax {
-        file_type: "Python",
-        file_extensions: &["py"],
-        keywords: &[
-            "def", "class", "if", "elif", "else", "for", "while", "break", "continue", "return",
-            "import", "from", "as", "pass", "try", "except", "finally", "raise", "with", "lambda",
-            "global", "nonlocal", "True", "False", "None", "and", "or", "not", "is", "in",
-        ],
-        single_line_comment: "#",
-        types: &[
-            "bool", "int", "float", "str", "list", "dict", "set", "tuple", "None", "True", "False",
-        ],
-        control_flow: &[
-            "break", "continue", "else", "for", "if", "raise", "return", "try", "while",
-        ],
-    },
+/// Suffixes editors, patch tools and package managers add to a file's name
+/// when keeping another copy of it.
+const BACKUP_SUFFIXES: &[&str] = &[
+    "~",
+    ".bak",
+    ".old",
+    ".orig",
+    ".rej",
+    ".new",
+    ".save",
+    ".tmp",
+    ".dpkg-dist",
+    ".dpkg-old",
+    ".dpkg-new",
+    ".rpmnew",
+    ".rpmsave",
+    ".rpmorig",
 ];
+
+/// `path` without a backup suffix, e.g. `foo.rs` for `foo.rs.orig`.
+fn strip_backup_suffix(path: &str) -> Option<&str> {
+    BACKUP_SUFFIXES
+        .iter()
+        .find_map(|suffix| path.strip_suffix(suffix))
+        .filter(|rest| !rest.is_empty() && !rest.ends_with('/'))
+}
+
+/// A glob pattern as a regex: `*` matches within a path component, `**`
+/// across them, `?` one character and `[...]` or `[!...]` a set.
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::new();
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str(".*");
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                regex.push('[');
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    regex.push('^');
+                }
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        break;
+                    }
+                    if c == '\\' || c == '[' {
+                        regex.push('\\');
+                    }
+                    regex.push(c);
+                }
+                regex.push(']');
+            }
+            c => regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    regex
+}
+
+static VIM_MODELINE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?:^|\s)(?:vi|vim|Vim|ex):(?:.*?[\s:])?(?:ft|filetype|syn|syntax)=([\w+#.-]+)")
+        .unwrap()
+});
+static EMACS_MODELINE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"-\*-(.*?)-\*-").unwrap());
+static EMACS_MODE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?:^|;)\s*mode:\s*([\w+#.-]+)").unwrap());
+
+/// The language a Vim modeline (`vim: set ft=python:`) or Emacs one
+/// (`-*- mode: python -*-` or `-*- python -*-`) in `line` asks for.
+fn modeline(line: &str) -> Option<&str> {
+    if let Some(captures) = VIM_MODELINE.captures(line) {
+        return Some(captures.get(1)?.as_str());
+    }
+    let variables = EMACS_MODELINE.captures(line)?.get(1)?.as_str();
+    if let Some(captures) = EMACS_MODE.captures(variables) {
+        return Some(captures.get(1)?.as_str());
+    }
+    let mode = variables.trim();
+    (!mode.is_empty() && !mode.contains([':', ';', ' '])).then_some(mode)
+}
+
+/// The program a `#!` line runs, without its directory: `python3` for both
+/// `#!/usr/bin/python3` and `#!/usr/bin/env python3`.
+fn interpreter(line: &str) -> Option<&str> {
+    let mut words = line.strip_prefix("#!")?.split_whitespace();
+    let program = words.next()?.rsplit('/').next()?;
+    if program == "env" {
+        // Skip options like `-S` and variables like `LANG=C`
+        return words.find(|word| !word.starts_with('-') && !word.contains('='));
+    }
+    Some(program)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A new directory under the temp dir holding `files`.
+    fn language_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = env::temp_dir().join(format!("meow-languages-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for (file, text) in files {
+            fs::write(dir.join(file), text).unwrap();
+        }
+        dir
+    }
+
+    fn find<'a>(languages: &'a [Arc<Syntax>], file_type: &str) -> Vec<&'a Arc<Syntax>> {
+        languages
+            .iter()
+            .filter(|syntax| syntax.file_type == file_type)
+            .collect()
+    }
+
+    #[test]
+    fn builtin_languages_parse() {
+        let mut file_types = Vec::new();
+        for (name, text) in BUILTIN_LANGUAGES {
+            let syntax = parse(text).unwrap_or_else(|e| panic!("{}: {}", name, e));
+            assert!(
+                !file_types.contains(&syntax.file_type),
+                "{}: {} is defined twice",
+                name,
+                syntax.file_type
+            );
+            file_types.push(syntax.file_type);
+        }
+        let (languages, errors) = load(&[]);
+        assert!(errors.is_empty());
+        assert_eq!(languages.len(), BUILTIN_LANGUAGES.len());
+    }
+
+    #[test]
+    fn user_languages_add_to_and_replace_builtin_ones() {
+        let dir = language_dir(
+            "user",
+            &[
+                (
+                    "ruby.toml",
+                    "file_type = \"Ruby\"\nfile_extensions = [\"rb\"]\n",
+                ),
+                (
+                    "rust.toml",
+                    "file_type = \"Rust\"\nfile_extensions = [\"rs\"]\nkeywords = [\"mine\"]\n",
+                ),
+                ("notes.txt", "not a language"),
+            ],
+        );
+        let (languages, errors) = load(std::slice::from_ref(&dir));
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(languages.len(), BUILTIN_LANGUAGES.len() + 1);
+        let rust = find(&languages, "Rust");
+        assert_eq!(rust.len(), 1);
+        assert_eq!(rust[0].keywords, ["mine"]);
+        // The user's languages are tried first
+        assert_eq!(by_path(&languages, "a.rb").unwrap().file_type, "Ruby");
+        assert_eq!(by_path(&languages, "a.rs").unwrap().keywords, ["mine"]);
+    }
+
+    #[test]
+    fn later_directories_win() {
+        let first = language_dir(
+            "first",
+            &[("a.toml", "file_type = \"X\"\nkeywords = [\"one\"]\n")],
+        );
+        let second = language_dir(
+            "second",
+            &[("b.toml", "file_type = \"X\"\nkeywords = [\"two\"]\n")],
+        );
+        let (languages, _) = load(&[first.clone(), second.clone()]);
+        fs::remove_dir_all(&first).unwrap();
+        fs::remove_dir_all(&second).unwrap();
+        let x = find(&languages, "X");
+        assert_eq!(x.len(), 1);
+        assert_eq!(x[0].keywords, ["two"]);
+    }
+
+    #[test]
+    fn broken_user_languages_are_reported_and_skipped() {
+        let dir = language_dir(
+            "broken",
+            &[
+                ("bad.toml", "file_type = \"Bad\"\nkeywords = 5\n"),
+                (
+                    "kind.toml",
+                    "file_type = \"Kind\"\npatterns = [{ regex = 'x', kind = \"nope\" }]\n",
+                ),
+                ("good.toml", "file_type = \"Good\"\n"),
+            ],
+        );
+        let (languages, errors) = load(std::slice::from_ref(&dir));
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].contains("bad.toml: line 2"), "{}", errors[0]);
+        assert!(
+            errors[1].contains("unknown pattern kind \"nope\""),
+            "{}",
+            errors[1]
+        );
+        assert_eq!(find(&languages, "Good").len(), 1);
+        assert!(find(&languages, "Bad").is_empty());
+    }
+
+    #[test]
+    fn parse_checks_definitions() {
+        assert_eq!(parse("keywords = []").unwrap_err(), "file_type is missing");
+        assert!(parse("file_type = \"A\"\nblock_comments = [[\"/*\", \"\"]]").is_err());
+        assert!(parse("file_type = \"A\"\nno_such_field = 1").is_err());
+        assert!(parse("file_type = \"A\"\nshebangs = [\"(\"]").is_err());
+
+        let syntax = parse("file_type = \"A\"\nstring_delimiters = ['\"', \"'\"]\nstrings = [{ start = '\"\"\"', multiline = true }]").unwrap();
+        let starts: Vec<&str> = syntax.strings.iter().map(|q| q.start.as_str()).collect();
+        assert_eq!(starts, ["\"\"\"", "\"", "'"]);
+        assert!(syntax.strings.iter().all(|q| q.end == q.start));
+    }
+
+    fn file_type(file_name: &str, lines: &[&str]) -> String {
+        let lines: Vec<String> = lines.iter().map(|line| line.to_string()).collect();
+        Syntax::select(file_name, &lines).file_type.clone()
+    }
+
+    #[test]
+    fn detects_file_names() {
+        assert_eq!(file_type("Makefile", &[]), "Makefile");
+        assert_eq!(file_type("src/GNUmakefile", &[]), "Makefile");
+        assert_eq!(file_type("rules.mk", &[]), "Makefile");
+        assert_eq!(file_type("/app/Dockerfile", &[]), "Dockerfile");
+        assert_eq!(file_type("/home/me/.bashrc", &[]), "Shell");
+        assert_eq!(file_type("Cargo.lock", &[]), "TOML");
+        assert_eq!(file_type("notes", &[]), "Text");
+    }
+
+    #[test]
+    fn detects_globs() {
+        assert_eq!(file_type("Dockerfile.dev", &[]), "Dockerfile");
+        assert_eq!(file_type("build/Makefile.am", &[]), "Makefile");
+
+        let syntax =
+            parse("file_type = \"Git\"\nfile_globs = [\".git/config\", \"*.conf\"]").unwrap();
+        let languages = [Arc::new(syntax)];
+        assert!(by_path(&languages, "/src/repo/.git/config").is_some());
+        assert!(by_path(&languages, ".git/config").is_some());
+        assert!(by_path(&languages, "/src/repo/config").is_none());
+        assert!(by_path(&languages, "/etc/a.conf").is_some());
+        assert!(by_path(&languages, "/etc/a.conf/b").is_none());
+    }
+
+    #[test]
+    fn glob_syntax() {
+        let matches = |glob: &str, path: &str| {
+            Regex::new(&format!("^(?:{})$", glob_to_regex(glob)))
+                .unwrap()
+                .is_match(path)
+        };
+        assert!(matches("*.rs", "main.rs"));
+        assert!(!matches("*.rs", "src/main.rs"));
+        assert!(matches("**/*.rs", "src/bin/main.rs"));
+        assert!(matches("file?.txt", "file1.txt"));
+        assert!(!matches("file?.txt", "file12.txt"));
+        assert!(!matches("file?.txt", "file/.txt"));
+        assert!(matches("[abc].md", "b.md"));
+        assert!(!matches("[abc].md", "d.md"));
+        assert!(matches("[!abc].md", "d.md"));
+        assert!(!matches("[!abc].md", "a.md"));
+        // Regex characters are taken literally
+        assert!(!matches("a.b", "axb"));
+        assert!(matches("a+(b)", "a+(b)"));
+    }
+
+    #[test]
+    fn backup_suffixes() {
+        assert_eq!(strip_backup_suffix("foo.rs.orig"), Some("foo.rs"));
+        assert_eq!(strip_backup_suffix("foo.py~"), Some("foo.py"));
+        assert_eq!(strip_backup_suffix("conf.dpkg-dist"), Some("conf"));
+        assert_eq!(strip_backup_suffix("foo.rs"), None);
+        assert_eq!(strip_backup_suffix(".bak"), None);
+        assert_eq!(strip_backup_suffix("dir/~"), None);
+
+        assert_eq!(file_type("foo.rs.orig", &[]), "Rust");
+        assert_eq!(file_type("script.py~", &[]), "Python");
+        // One suffix after another
+        assert_eq!(file_type("main.c.orig~", &[]), "C");
+        assert_eq!(file_type(".bashrc.bak", &[]), "Shell");
+        assert_eq!(file_type("notes.orig", &[]), "Text");
+    }
+
+    #[test]
+    fn modelines() {
+        assert_eq!(modeline("# vim: set ft=python :"), Some("python"));
+        assert_eq!(modeline("// vim:ft=cpp"), Some("cpp"));
+        assert_eq!(modeline("/* vi: set ts=4 syntax=c: */"), Some("c"));
+        assert_eq!(modeline("ex: filetype=sh"), Some("sh"));
+        assert_eq!(modeline("// -*- mode: java -*-"), Some("java"));
+        assert_eq!(modeline("# -*- mode: ruby; tab-width: 2 -*-"), Some("ruby"));
+        assert_eq!(modeline("# -*- python -*-"), Some("python"));
+        assert_eq!(modeline("# -*- coding: utf-8 -*-"), None);
+        assert_eq!(modeline("# nvim:ft=c"), None);
+        assert_eq!(modeline("plain text"), None);
+
+        // A modeline wins over the file name, in the first or last lines
+        assert_eq!(file_type("a.txt", &["# vim: ft=python"]), "Python");
+        assert_eq!(file_type("a.rs", &["fn main() {}", "// vim: ft=c"]), "C");
+        assert_eq!(file_type("a.h", &["// -*- C++ -*-"]), "C++");
+        // An unknown language is ignored
+        assert_eq!(file_type("a.rs", &["// vim: ft=cobol"]), "Rust");
+    }
+
+    #[test]
+    fn shebangs() {
+        assert_eq!(interpreter("#!/usr/bin/env python3"), Some("python3"));
+        assert_eq!(interpreter("#!/bin/sh -e"), Some("sh"));
+        assert_eq!(
+            interpreter("#!/usr/bin/env -S LANG=C bash -x"),
+            Some("bash")
+        );
+        assert_eq!(interpreter("#!node"), Some("node"));
+        assert_eq!(interpreter("# comment"), None);
+
+        assert_eq!(file_type("script", &["#!/usr/bin/env python3"]), "Python");
+        assert_eq!(file_type("run", &["#!/bin/bash", "echo hi"]), "Shell");
+        assert_eq!(file_type("run", &["#!/usr/bin/perl"]), "Text");
+        // Only the first line counts, and a file name wins
+        assert_eq!(file_type("run", &["", "#!/bin/sh"]), "Text");
+        assert_eq!(file_type("a.py", &["#!/bin/sh"]), "Python");
+    }
+
+    #[test]
+    fn find_by_name() {
+        assert_eq!(Syntax::find("cpp").unwrap().file_type, "C++");
+        assert_eq!(Syntax::find("c++").unwrap().file_type, "C++");
+        assert_eq!(Syntax::find("sh").unwrap().file_type, "Shell");
+        assert_eq!(Syntax::find("MAKEFILE").unwrap().file_type, "Makefile");
+        assert_eq!(Syntax::find("text").unwrap().file_type, "Text");
+        assert!(Syntax::find("cobol").is_none());
+    }
+}
@@ -1,10 +1,17 @@
 use crossterm::{
-    event::{DisableMouseCapture, EnableMouseCapture},
+    cursor::Show,
+    event::{DisableFocusChange, DisableMouseCapture, EnableFocusChange, EnableMouseCapture},
     execute,
-    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+    terminal::{EnterAlternateScreen, LeaveAlternateScreen, disable_raw_mode, enable_raw_mode},
 };
-use ratatui::{backend::CrosstermBackend, Terminal as RatatuiTerminal};
+use ratatui::{Terminal as RatatuiTerminal, backend::CrosstermBackend};
 use std::io::{self, Stdout};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Whether raw mode, the alternate screen, mouse capture and focus reporting
+// are on, so that whichever of `stop`, `Drop` and the panic hook runs first
+// undoes them once
+static ACTIVE: AtomicBool = AtomicBool::new(false);
 
 pub struct Terminal {
     pub backend: RatatuiTerminal<CrosstermBackend<Stdout>>,
@@ -13,21 +20,63 @@ pub struct Terminal {
 impl Terminal {
     pub fn new() -> io::Result<Self> {
         enable_raw_mode()?;
+        ACTIVE.store(true, Ordering::SeqCst);
         let mut stdout = io::stdout();
-        execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
+        execute!(
+            stdout,
+            EnterAlternateScreen,
+            EnableMouseCapture,
+            EnableFocusChange
+        )
+        .inspect_err(|_| restore())?;
         let backend = CrosstermBackend::new(stdout);
-        let terminal = RatatuiTerminal::new(backend)?;
+        let terminal = RatatuiTerminal::new(backend).inspect_err(|_| restore())?;
         Ok(Self { backend: terminal })
     }
 
+    /// A terminal that is never switched to raw mode or the alternate
+    /// screen, for tests that drive the editor with keys.
+    #[cfg(test)]
+    pub fn headless() -> io::Result<Self> {
+        let options = ratatui::TerminalOptions {
+            viewport: ratatui::Viewport::Fixed(ratatui::layout::Rect::new(0, 0, 80, 24)),
+        };
+        let backend = RatatuiTerminal::with_options(CrosstermBackend::new(io::stdout()), options)?;
+        Ok(Self { backend })
+    }
+
     pub fn stop(&mut self) -> io::Result<()> {
         disable_raw_mode()?;
         execute!(
             self.backend.backend_mut(),
             LeaveAlternateScreen,
-            DisableMouseCapture
+            DisableMouseCapture,
+            DisableFocusChange
         )?;
         self.backend.show_cursor()?;
+        ACTIVE.store(false, Ordering::SeqCst);
         Ok(())
     }
 }
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        restore();
+    }
+}
+
+/// Puts the terminal back the way the shell expects it, if `Terminal::new`
+/// changed it. Errors are ignored: this runs on the way out, when there is
+/// nothing better to do.
+pub fn restore() {
+    if ACTIVE.swap(false, Ordering::SeqCst) {
+        let _ = disable_raw_mode();
+        let _ = execute!(
+            io::stdout(),
+            LeaveAlternateScreen,
+            DisableMouseCapture,
+            DisableFocusChange,
+            Show
+        );
+    }
+}
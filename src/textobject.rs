@@ -0,0 +1,578 @@
+use crate::document::Document;
+use crate::editor::Position;
+use ropey::Rope;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ObjectKind {
+    /// `w` / `W`
+    Word { big: bool },
+    /// `s`
+    Sentence,
+    /// `p`
+    Paragraph,
+    /// `"`, `'` or `` ` ``
+    Quote(char),
+    /// `(`, `{`, `[` or `<`, with the matching closer.
+    Bracket(char, char),
+    /// `t`: an XML/HTML element.
+    Tag,
+}
+
+/// A text object such as `iw` or `a(`. "Inner" objects leave out the
+/// surrounding whitespace, quotes, brackets or tags.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct TextObject {
+    pub kind: ObjectKind,
+    pub inner: bool,
+}
+
+/// Chars `start..end` of the document. `linewise` ranges cover whole lines.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Range {
+    pub start: usize,
+    pub end: usize,
+    pub linewise: bool,
+}
+
+impl TextObject {
+    /// `inner` is true after `i`, false after `a`; `c` is the object key.
+    pub fn from_key(inner: bool, c: char) -> Option<Self> {
+        let kind = match c {
+            'w' => ObjectKind::Word { big: false },
+            'W' => ObjectKind::Word { big: true },
+            's' => ObjectKind::Sentence,
+            'p' => ObjectKind::Paragraph,
+            '"' | '\'' | '`' => ObjectKind::Quote(c),
+            '(' | ')' | 'b' => ObjectKind::Bracket('(', ')'),
+            '{' | '}' | 'B' => ObjectKind::Bracket('{', '}'),
+            '[' | ']' => ObjectKind::Bracket('[', ']'),
+            '<' | '>' => ObjectKind::Bracket('<', '>'),
+            't' => ObjectKind::Tag,
+            _ => return None,
+        };
+        Some(Self { kind, inner })
+    }
+
+    /// The range of the object around `at`. A count selects that many words,
+    /// sentences or paragraphs, or the count-th enclosing bracket pair or tag.
+    pub fn select(self, document: &Document, at: Position, count: usize) -> Option<Range> {
+        if at.y >= document.len() {
+            return None;
+        }
+        let idx = document.position_to_char_idx(&at);
+        let count = count.max(1);
+        let charwise = |(start, end)| Range {
+            start,
+            end,
+            linewise: false,
+        };
+        match self.kind {
+            ObjectKind::Word { big } => word(document, at, count, big, self.inner).map(charwise),
+            ObjectKind::Sentence => sentence(&document.content, idx, self.inner).map(charwise),
+            ObjectKind::Paragraph => paragraph(document, at.y, count, self.inner),
+            ObjectKind::Quote(quote) => quoted(document, at, quote, self.inner).map(charwise),
+            ObjectKind::Bracket(open, close) => {
+                bracket(&document.content, idx, open, close, count, self.inner).map(charwise)
+            }
+            ObjectKind::Tag => tag(&document.content, idx, count, self.inner).map(charwise),
+        }
+    }
+}
+
+#[derive(PartialEq)]
+enum CharClass {
+    Blank,
+    Word,
+    Punctuation,
+}
+
+fn char_class(c: char, big: bool) -> CharClass {
+    if c.is_whitespace() {
+        CharClass::Blank
+    } else if big || c.is_alphanumeric() || c == '_' {
+        CharClass::Word
+    } else {
+        CharClass::Punctuation
+    }
+}
+
+/// `iw` / `aw`: works within the cursor's line. For `iw` a run of blanks
+/// counts as a word too.
+fn word(
+    document: &Document,
+    at: Position,
+    count: usize,
+    big: bool,
+    inner: bool,
+) -> Option<(usize, usize)> {
+    let chars: Vec<char> = document.content.line(at.y).chars().collect();
+    let len = document.line_len(at.y);
+    if len == 0 {
+        return None;
+    }
+    let x = at.x.min(len - 1);
+    let class = |i: usize| char_class(chars[i], big);
+    let run_end = |mut i: usize| {
+        let c = class(i);
+        while i < len && class(i) == c {
+            i += 1;
+        }
+        i
+    };
+
+    let mut start = x;
+    while start > 0 && class(start - 1) == class(x) {
+        start -= 1;
+    }
+    let mut end = run_end(x);
+    let on_blank = class(x) == CharClass::Blank;
+
+    if inner {
+        for _ in 1..count {
+            if end >= len {
+                break;
+            }
+            end = run_end(end);
+        }
+        return Some(offset(document, at.y, start, end));
+    }
+
+    // A word takes the blanks after it; a run of blanks takes the word after it
+    for i in 0..count {
+        if i > 0 && end < len {
+            end = run_end(end);
+        }
+        if end < len && (on_blank || class(end) == CharClass::Blank) {
+            end = run_end(end);
+        }
+    }
+    // With no trailing blanks to take, `aw` takes the leading ones instead
+    if !on_blank && class(end - 1) != CharClass::Blank {
+        while start > 0 && class(start - 1) == CharClass::Blank {
+            start -= 1;
+        }
+    }
+    Some(offset(document, at.y, start, end))
+}
+
+fn offset(document: &Document, y: usize, start: usize, end: usize) -> (usize, usize) {
+    let line_start = document.content.line_to_char(y);
+    (line_start + start, line_start + end)
+}
+
+/// A sentence ends at `.`, `!` or `?` (optionally followed by closing
+/// quotes or brackets) followed by whitespace. Blank lines end one too.
+fn ends_sentence(text: &Rope, mut i: usize) -> bool {
+    while i > 0 && matches!(text.char(i), ')' | ']' | '"' | '\'') {
+        i -= 1;
+    }
+    matches!(text.char(i), '.' | '!' | '?')
+}
+
+/// Whether the char at `i` is the line break of a line that is followed by
+/// a blank line or the end of the text.
+fn ends_paragraph(text: &Rope, i: usize) -> bool {
+    text.char(i) == '\n' && (i + 1 >= text.len_chars() || text.char(i + 1) == '\n')
+}
+
+fn sentence(text: &Rope, idx: usize, inner: bool) -> Option<(usize, usize)> {
+    let len = text.len_chars();
+    let is_blank = |i: usize| text.char(i).is_whitespace();
+    // From the blanks between sentences, take the next sentence
+    let mut idx = idx;
+    while idx < len && is_blank(idx) && !ends_paragraph(text, idx) {
+        idx += 1;
+    }
+    if idx >= len || is_blank(idx) {
+        return None;
+    }
+
+    let mut start = idx;
+    while start > 0 && !ends_paragraph(text, start - 1) {
+        if is_blank(start - 1) && !is_blank(start) {
+            let mut j = start - 1;
+            while j > 0 && is_blank(j) {
+                j -= 1;
+            }
+            if ends_sentence(text, j) {
+                break;
+            }
+        }
+        start -= 1;
+    }
+
+    let mut end = idx;
+    while end < len && !ends_paragraph(text, end) {
+        end += 1;
+        if ends_sentence(text, end - 1) && (end >= len || is_blank(end)) {
+            break;
+        }
+    }
+
+    if !inner {
+        let trailing = end;
+        while end < len && is_blank(end) && !ends_paragraph(text, end) {
+            end += 1;
+        }
+        if end == trailing {
+            while start > 0 && is_blank(start - 1) && text.char(start - 1) != '\n' {
+                start -= 1;
+            }
+        }
+    }
+    Some((start, end))
+}
+
+/// `ip` selects the run of non-blank (or blank) lines around `y`; `ap` adds
+/// the blank lines after it, or before it when there are none after.
+fn paragraph(document: &Document, y: usize, count: usize, inner: bool) -> Option<Range> {
+    let lines = document.len();
+    if y >= lines {
+        return None;
+    }
+    let blank = |y: usize| document.line_len(y) == 0;
+    let run_end = |y: usize| {
+        let b = blank(y);
+        let mut end = y;
+        while end + 1 < lines && blank(end + 1) == b {
+            end += 1;
+        }
+        end
+    };
+
+    let mut first = y;
+    while first > 0 && blank(first - 1) == blank(y) {
+        first -= 1;
+    }
+    let mut last = run_end(y);
+    let runs = if inner { count } else { count * 2 };
+    for _ in 1..runs {
+        if last + 1 >= lines {
+            break;
+        }
+        last = run_end(last + 1);
+    }
+    if !inner && !blank(y) && blank(last) == blank(y) {
+        // No blank lines followed; take the ones before instead
+        while first > 0 && blank(first - 1) {
+            first -= 1;
+        }
+    }
+
+    let text = &document.content;
+    let end = if last + 1 < lines {
+        text.line_to_char(last + 1)
+    } else {
+        text.len_chars()
+    };
+    Some(Range {
+        start: text.line_to_char(first),
+        end,
+        linewise: true,
+    })
+}
+
+/// Quotes only pair up within a line. A quote preceded by a backslash is skipped.
+fn quoted(document: &Document, at: Position, quote: char, inner: bool) -> Option<(usize, usize)> {
+    let chars: Vec<char> = document.content.line(at.y).chars().collect();
+    let len = document.line_len(at.y);
+    let quotes: Vec<usize> = (0..len)
+        .filter(|&i| {
+            chars[i] == quote && (0..i).rev().take_while(|&j| chars[j] == '\\').count() % 2 == 0
+        })
+        .collect();
+
+    let before = quotes.iter().filter(|&&i| i < at.x).count();
+    let pair = if quotes.get(before) == Some(&at.x) {
+        // On a quote: even ones open a string, odd ones close it
+        if before % 2 == 0 {
+            (before, before + 1)
+        } else {
+            (before - 1, before)
+        }
+    } else if before % 2 == 1 {
+        (before - 1, before)
+    } else {
+        // Not inside a string: use the next one on the line
+        (before, before + 1)
+    };
+    let (open, close) = (*quotes.get(pair.0)?, *quotes.get(pair.1)?);
+
+    let (mut start, mut end) = if inner {
+        (open + 1, close)
+    } else {
+        (open, close + 1)
+    };
+    if !inner {
+        let trailing = end;
+        while end < len && chars[end].is_whitespace() {
+            end += 1;
+        }
+        if end == trailing {
+            while start > 0 && chars[start - 1].is_whitespace() {
+                start -= 1;
+            }
+        }
+    }
+    Some(offset(document, at.y, start, end))
+}
+
+/// Finds the `count`-th bracket pair enclosing `idx`, across lines.
+fn bracket(
+    text: &Rope,
+    idx: usize,
+    open: char,
+    close: char,
+    count: usize,
+    inner: bool,
+) -> Option<(usize, usize)> {
+    let len = text.len_chars();
+    if len == 0 {
+        return None;
+    }
+    let idx = idx.min(len - 1);
+    let mut start = match text.char(idx) {
+        c if c == open => idx,
+        // On a closer, the unmatched opener before it is its partner
+        c if c == close => enclosing_open(text, idx, open, close)?,
+        _ => enclosing_open(text, idx, open, close)?,
+    };
+    for _ in 1..count {
+        start = enclosing_open(text, start, open, close)?;
+    }
+
+    let mut depth = 0;
+    let mut end = None;
+    for (i, c) in text.chars_at(start + 1).enumerate() {
+        if c == open {
+            depth += 1;
+        } else if c == close {
+            if depth == 0 {
+                end = Some(start + 1 + i);
+                break;
+            }
+            depth -= 1;
+        }
+    }
+    let end = end?;
+
+    if !inner {
+        return Some((start, end + 1));
+    }
+    // For a block whose brackets sit on their own lines, `i{` is the lines
+    // between them
+    let mut inner_start = start + 1;
+    let mut inner_end = end;
+    if inner_start < end && text.char(inner_start) == '\n' {
+        inner_start += 1;
+        let close_line = text.char_to_line(end);
+        let line_start = text.line_to_char(close_line);
+        if line_start > inner_start
+            && text
+                .slice(line_start..end)
+                .chars()
+                .all(|c| c.is_whitespace())
+        {
+            inner_end = line_start;
+        }
+    }
+    Some((inner_start, inner_end.max(inner_start)))
+}
+
+/// The unmatched `open` before `idx`.
+fn enclosing_open(text: &Rope, idx: usize, open: char, close: char) -> Option<usize> {
+    let mut depth = 0;
+    let mut chars = text.chars_at(idx);
+    let mut i = idx;
+    while let Some(c) = chars.prev() {
+        i -= 1;
+        if c == close {
+            depth += 1;
+        } else if c == open {
+            if depth == 0 {
+                return Some(i);
+            }
+            depth -= 1;
+        }
+    }
+    None
+}
+
+struct Tag {
+    start: usize,
+    end: usize,
+    name: String,
+    closing: bool,
+}
+
+/// Scans the document for tags, skipping comments, `<!DOCTYPE>`, `<?xml?>`
+/// and self-closing tags.
+fn scan_tags(text: &Rope) -> Vec<Tag> {
+    let mut tags = Vec::new();
+    let mut chars = text.chars().enumerate().peekable();
+    while let Some((i, c)) = chars.next() {
+        if c != '<' {
+            continue;
+        }
+        let closing = chars.next_if(|(_, c)| *c == '/').is_some();
+        let declaration = !closing && chars.next_if(|(_, c)| matches!(c, '!' | '?')).is_some();
+        // Not a tag at all, as in `a < b`: look on from here, not after the next `>`
+        if !declaration
+            && !chars
+                .peek()
+                .is_some_and(|(_, c)| c.is_alphabetic() || matches!(c, '_' | ':'))
+        {
+            continue;
+        }
+        let mut name = String::new();
+        while let Some((_, c)) = chars.next_if(|(_, c)| c.is_alphanumeric() || "-_:.".contains(*c))
+        {
+            name.push(c);
+        }
+        let mut last = c;
+        let mut end = None;
+        for (j, c) in chars.by_ref() {
+            if c == '>' {
+                end = Some(j + 1);
+                break;
+            }
+            last = c;
+        }
+        let Some(end) = end else {
+            break;
+        };
+        if declaration || last == '/' {
+            continue;
+        }
+        tags.push(Tag {
+            start: i,
+            end,
+            name,
+            closing,
+        });
+    }
+    tags
+}
+
+/// `it` / `at`: the `count`-th element enclosing `idx`.
+fn tag(text: &Rope, idx: usize, count: usize, inner: bool) -> Option<(usize, usize)> {
+    let mut stack: Vec<&Tag> = Vec::new();
+    let mut enclosing = Vec::new();
+    let tags = scan_tags(text);
+    for tag in &tags {
+        if !tag.closing {
+            stack.push(tag);
+            continue;
+        }
+        // Unclosed tags like `<br>` are dropped when their parent closes
+        let Some(pos) = stack.iter().rposition(|open| open.name == tag.name) else {
+            continue;
+        };
+        let open = stack[pos];
+        stack.truncate(pos);
+        if open.start <= idx && idx < tag.end {
+            enclosing.push((open, tag));
+        }
+    }
+    // Pairs close innermost first
+    let (open, close) = enclosing.get(count - 1)?;
+    if inner {
+        Some((open.end, close.start))
+    } else {
+        Some((open.start, close.end))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The text of object `keys` (like `"i("`) with the cursor at `x, y`.
+    fn select(text: &str, x: usize, y: usize, keys: &str, count: usize) -> Option<String> {
+        let mut document = Document::default();
+        document.content = Rope::from_str(text);
+        let mut keys = keys.chars();
+        let inner = keys.next() == Some('i');
+        let object = TextObject::from_key(inner, keys.next()?)?;
+        let range = object.select(&document, Position { x, y }, count)?;
+        Some(document.content.slice(range.start..range.end).to_string())
+    }
+
+    fn sel(text: &str, x: usize, keys: &str) -> Option<String> {
+        select(text, x, 0, keys, 1)
+    }
+
+    #[test]
+    fn words() {
+        let text = "foo bar  baz";
+        assert_eq!(sel(text, 5, "iw").unwrap(), "bar");
+        assert_eq!(sel(text, 5, "aw").unwrap(), "bar  ");
+        // No blanks after the last word: `aw` takes the ones before it
+        assert_eq!(sel(text, 10, "aw").unwrap(), "  baz");
+        assert_eq!(sel(text, 3, "iw").unwrap(), " ");
+        assert_eq!(select(text, 0, 0, "iw", 3).unwrap(), "foo bar");
+        assert_eq!(sel("a.b c", 0, "iw").unwrap(), "a");
+        assert_eq!(sel("a.b c", 0, "iW").unwrap(), "a.b");
+    }
+
+    #[test]
+    fn sentences() {
+        let text = "One. Two three. Four.";
+        assert_eq!(sel(text, 6, "is").unwrap(), "Two three.");
+        assert_eq!(sel(text, 6, "as").unwrap(), "Two three. ");
+        assert_eq!(sel(text, 18, "as").unwrap(), " Four.");
+    }
+
+    #[test]
+    fn paragraphs() {
+        let text = "a\nb\n\nc\n";
+        assert_eq!(select(text, 0, 1, "ip", 1).unwrap(), "a\nb\n");
+        assert_eq!(select(text, 0, 0, "ap", 1).unwrap(), "a\nb\n\n");
+        // No blank line after the last paragraph: `ap` takes the one before
+        assert_eq!(select("a\n\nc", 0, 2, "ap", 1).unwrap(), "\nc");
+    }
+
+    #[test]
+    fn quotes() {
+        let text = r#"say "hi \"x\"" now"#;
+        assert_eq!(sel(text, 6, "i\"").unwrap(), r#"hi \"x\""#);
+        assert_eq!(sel(text, 6, "a\"").unwrap(), r#""hi \"x\"" "#);
+        // Before the first quote, the next string on the line
+        assert_eq!(sel(text, 0, "i\"").unwrap(), r#"hi \"x\""#);
+        assert_eq!(sel(text, 0, "i'"), None);
+    }
+
+    #[test]
+    fn brackets() {
+        let text = "f(a, (b), c)";
+        assert_eq!(sel(text, 6, "i(").unwrap(), "b");
+        assert_eq!(sel(text, 6, "a)").unwrap(), "(b)");
+        assert_eq!(select(text, 6, 0, "ib", 2).unwrap(), "a, (b), c");
+        assert_eq!(sel(text, 11, "i(").unwrap(), "a, (b), c");
+        assert_eq!(sel(text, 0, "i("), None);
+        // A block on its own lines is the lines between the brackets
+        let block = "fn x() {\n    body;\n}\n";
+        assert_eq!(select(block, 4, 1, "i{", 1).unwrap(), "    body;\n");
+        assert_eq!(select(block, 4, 1, "a{", 1).unwrap(), "{\n    body;\n}");
+    }
+
+    #[test]
+    fn tags() {
+        let text = "<div> a < b <p class=\"x\">x</p> </div>";
+        assert_eq!(sel(text, 6, "it").unwrap(), " a < b <p class=\"x\">x</p> ");
+        assert_eq!(sel(text, 6, "at").unwrap(), text);
+        assert_eq!(sel(text, 26, "it").unwrap(), "x");
+        assert_eq!(sel(text, 26, "at").unwrap(), "<p class=\"x\">x</p>");
+        assert_eq!(
+            select(text, 26, 0, "it", 2).unwrap(),
+            " a < b <p class=\"x\">x</p> "
+        );
+    }
+
+    #[test]
+    fn tags_skip_unclosed_and_self_closing_ones() {
+        let text = "<!DOCTYPE html><p>a<br>b<img/></p>";
+        assert_eq!(sel(text, 20, "it").unwrap(), "a<br>b<img/>");
+        assert_eq!(sel("a < b > c", 4, "it"), None);
+    }
+}
@@ -1,4 +1,6 @@
-use crate::config::ThemeConfig;
+use crate::config::{HighlightStyle, ThemeConfig};
+use crate::row::HighlightType;
+use ratatui::style::{Color, Modifier, Style};
 use std::env;
 use std::fs;
 use std::path::{Path, PathBuf};
@@ -19,13 +21,171 @@ pub fn load_theme(name: &str) -> ThemeConfig {
 
     let path = Path::new(&theme_path);
 
-    if path.exists() {
-        if let Ok(content) = fs::read_to_string(path) {
-            if let Ok(theme) = toml::from_str::<ThemeConfig>(&content) {
-                return theme;
-            }
-        }
+    if path.exists()
+        && let Ok(content) = fs::read_to_string(path)
+        && let Ok(theme) = toml::from_str::<ThemeConfig>(&content)
+    {
+        return theme;
     }
 
     ThemeConfig::default()
 }
+
+/// A theme's colors and styles, parsed once so that drawing a character is a
+/// table lookup.
+pub struct Palette {
+    pub background: Color,
+    pub foreground: Color,
+    pub selection_bg: Color,
+    pub status_bg: Color,
+    pub status_fg: Color,
+    highlights: [Style; HighlightType::ALL.len()],
+}
+
+impl Palette {
+    pub fn new(theme: &ThemeConfig) -> Self {
+        let foreground = parse_hex_color(&theme.foreground);
+        let fallback = |style: &Option<HighlightStyle>, or: &HighlightStyle| {
+            style.clone().unwrap_or_else(|| or.clone())
+        };
+        let plain = HighlightStyle::default();
+        let highlights = HighlightType::ALL.map(|kind| {
+            let style = match kind {
+                HighlightType::None => plain.clone(),
+                HighlightType::Number => theme.number.clone(),
+                HighlightType::Keyword => theme.keyword.clone(),
+                HighlightType::Type => theme.type_color.clone(),
+                HighlightType::ControlFlow => theme.control_flow.clone(),
+                HighlightType::String => theme.string.clone(),
+                HighlightType::Comment => theme.comment.clone(),
+                HighlightType::Function => fallback(&theme.function, &theme.keyword),
+                HighlightType::Lifetime => fallback(&theme.lifetime, &theme.type_color),
+                HighlightType::Macro => fallback(
+                    &theme.macro_color,
+                    &fallback(&theme.function, &theme.keyword),
+                ),
+                HighlightType::Attribute => fallback(&theme.attribute, &theme.type_color),
+                HighlightType::Operator => fallback(&theme.operator, &plain),
+                HighlightType::Punctuation => fallback(&theme.punctuation, &plain),
+                HighlightType::Constant => fallback(&theme.constant, &theme.number),
+                HighlightType::Escape => fallback(&theme.escape, &theme.keyword),
+                HighlightType::DocComment => fallback(&theme.doc_comment, &theme.comment),
+            };
+            to_style(&style, foreground)
+        });
+        Self {
+            background: parse_hex_color(&theme.background),
+            foreground,
+            selection_bg: parse_hex_color(&theme.selection_bg),
+            status_bg: parse_hex_color(&theme.status_bg),
+            status_fg: parse_hex_color(&theme.status_fg),
+            highlights,
+        }
+    }
+
+    /// How text highlighted as `kind` is drawn.
+    pub fn highlight(&self, kind: HighlightType) -> Style {
+        self.highlights[kind as usize]
+    }
+}
+
+/// `style` as drawn, with `foreground` when it has no color of its own.
+fn to_style(style: &HighlightStyle, foreground: Color) -> Style {
+    let mut result = Style::default().fg(style.fg.as_deref().map_or(foreground, parse_hex_color));
+    if let Some(bg) = &style.bg {
+        result = result.bg(parse_hex_color(bg));
+    }
+    for (on, modifier) in [
+        (style.bold, Modifier::BOLD),
+        (style.italic, Modifier::ITALIC),
+        (style.underline, Modifier::UNDERLINED),
+    ] {
+        if on {
+            result = result.add_modifier(modifier);
+        }
+    }
+    result
+}
+
+pub fn parse_hex_color(hex: &str) -> Color {
+    if hex.len() != 7 || !hex.starts_with('#') {
+        return Color::Reset;
+    }
+
+    let r = u8::from_str_radix(&hex[1..3], 16).unwrap_or(0);
+    let g = u8::from_str_radix(&hex[3..5], 16).unwrap_or(0);
+    let b = u8::from_str_radix(&hex[5..7], 16).unwrap_or(0);
+
+    Color::Rgb(r, g, b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn palette(theme: &str) -> Palette {
+        Palette::new(&toml::from_str(theme).unwrap())
+    }
+
+    fn fg(palette: &Palette, kind: HighlightType) -> Option<Color> {
+        palette.highlight(kind).fg
+    }
+
+    fn rgb(b: u8) -> Option<Color> {
+        Some(Color::Rgb(0, 0, b))
+    }
+
+    #[test]
+    fn missing_classes_fall_back_to_coarser_ones() {
+        let palette = palette(
+            r##"
+            foreground = "#ffffff"
+            keyword = "#000001"
+            type_color = "#000002"
+            number = "#000003"
+            comment = { fg = "#000004", italic = true }
+            "##,
+        );
+        assert_eq!(fg(&palette, HighlightType::Function), rgb(1));
+        assert_eq!(fg(&palette, HighlightType::Macro), rgb(1));
+        assert_eq!(fg(&palette, HighlightType::Escape), rgb(1));
+        assert_eq!(fg(&palette, HighlightType::Lifetime), rgb(2));
+        assert_eq!(fg(&palette, HighlightType::Attribute), rgb(2));
+        assert_eq!(fg(&palette, HighlightType::Constant), rgb(3));
+        assert_eq!(
+            palette.highlight(HighlightType::DocComment),
+            palette.highlight(HighlightType::Comment)
+        );
+        assert_eq!(
+            fg(&palette, HighlightType::Operator),
+            Some(Color::Rgb(255, 255, 255))
+        );
+        assert_eq!(
+            fg(&palette, HighlightType::Punctuation),
+            Some(Color::Rgb(255, 255, 255))
+        );
+    }
+
+    #[test]
+    fn set_classes_are_used_by_those_falling_back_to_them() {
+        let only_function = palette(
+            r##"
+            keyword = "#000001"
+            function = { fg = "#000005", bold = true }
+            "##,
+        );
+        assert_eq!(fg(&only_function, HighlightType::Function), rgb(5));
+        assert_eq!(
+            only_function.highlight(HighlightType::Macro),
+            only_function.highlight(HighlightType::Function)
+        );
+        let both = palette(
+            r##"
+            function = "#000005"
+            macro_color = "#000006"
+            "##,
+        );
+        assert_eq!(fg(&both, HighlightType::Function), rgb(5));
+        assert_eq!(fg(&both, HighlightType::Macro), rgb(6));
+    }
+}
@@ -0,0 +1,118 @@
+use crate::editor::Position;
+use ratatui::layout::{Constraint, Direction, Layout, Rect};
+
+/// A view onto one of the editor's buffers.
+///
+/// The focused window's view state lives in the `Editor` itself while it has
+/// focus; it is stored back here before drawing or when focus moves away.
+#[derive(Clone, Copy, Default)]
+pub struct Window {
+    pub buffer: usize,
+    pub cursor_position: Position,
+    pub offset: Position,
+    pub selection_start: Option<Position>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SplitDirection {
+    /// Windows stacked on top of each other (`:split`).
+    Horizontal,
+    /// Windows side by side (`:vsplit`).
+    Vertical,
+}
+
+/// Tree of splits; leaves are indices into the editor's window list.
+pub enum WindowLayout {
+    Leaf(usize),
+    Split(SplitDirection, Vec<WindowLayout>),
+}
+
+impl WindowLayout {
+    /// Splits the leaf for `target`, placing window `new` before it.
+    pub fn split(&mut self, target: usize, new: usize, direction: SplitDirection) -> bool {
+        match self {
+            WindowLayout::Leaf(window) if *window == target => {
+                *self = WindowLayout::Split(
+                    direction,
+                    vec![WindowLayout::Leaf(new), WindowLayout::Leaf(target)],
+                );
+                true
+            }
+            WindowLayout::Leaf(_) => false,
+            WindowLayout::Split(split_direction, children) => {
+                // Splitting along the same axis just adds a sibling
+                if *split_direction == direction
+                    && let Some(pos) = children
+                        .iter()
+                        .position(|c| matches!(c, WindowLayout::Leaf(w) if *w == target))
+                {
+                    children.insert(pos, WindowLayout::Leaf(new));
+                    return true;
+                }
+                children
+                    .iter_mut()
+                    .any(|child| child.split(target, new, direction))
+            }
+        }
+    }
+
+    /// Removes the leaf for `target` and shifts higher window indices down by one.
+    pub fn remove(&mut self, target: usize) {
+        self.remove_leaf(target);
+        self.renumber(target);
+    }
+
+    fn remove_leaf(&mut self, target: usize) -> bool {
+        let WindowLayout::Split(_, children) = self else {
+            return false;
+        };
+        if let Some(pos) = children
+            .iter()
+            .position(|c| matches!(c, WindowLayout::Leaf(w) if *w == target))
+        {
+            children.remove(pos);
+        } else if !children.iter_mut().any(|child| child.remove_leaf(target)) {
+            return false;
+        }
+        if children.len() == 1 {
+            *self = children.remove(0);
+        }
+        true
+    }
+
+    fn renumber(&mut self, removed: usize) {
+        match self {
+            WindowLayout::Leaf(window) => {
+                if *window > removed {
+                    *window -= 1;
+                }
+            }
+            WindowLayout::Split(_, children) => {
+                for child in children {
+                    child.renumber(removed);
+                }
+            }
+        }
+    }
+
+    /// Divides `area` evenly between the windows, returning `(window, area)` pairs.
+    pub fn areas(&self, area: Rect, out: &mut Vec<(usize, Rect)>) {
+        match self {
+            WindowLayout::Leaf(window) => out.push((*window, area)),
+            WindowLayout::Split(direction, children) => {
+                let direction = match direction {
+                    SplitDirection::Horizontal => Direction::Vertical,
+                    SplitDirection::Vertical => Direction::Horizontal,
+                };
+                let count = children.len() as u32;
+                let chunks = Layout::default()
+                    .direction(direction)
+                    .constraints(vec![Constraint::Ratio(1, count); children.len()])
+                    .split(area);
+                for (child, chunk) in children.iter().zip(chunks.iter()) {
+                    child.areas(*chunk, out);
+                }
+            }
+        }
+    }
+}